@@ -1,2 +1,6 @@
 pub mod board_post;
+pub mod composer;
+pub mod keys;
+pub mod roster;
+pub mod search;
 pub mod text_box;
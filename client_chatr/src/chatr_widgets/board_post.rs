@@ -1,64 +1,281 @@
-use chatr::{Content, Username};
+use chatr::{
+    ChatMessage, MessageId, MessageKind, Status, Username, files,
+    markup::{self, MarkupBlock, StyledSpan},
+};
 use ratatui::{
     buffer::Buffer,
     layout::Rect,
-    style::Stylize,
-    text::{Line, Text},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span, Text},
     widgets::Widget,
 };
 
+/// Longest a quoted parent gets before it's cut short
+const QUOTE_LEN: usize = 60;
+
 /// Any post that can get displayed on the MessageBoard
 #[derive(Debug)]
 pub enum BoardPost {
-    Message {
-        username: Username,
-        content: Content,
+    Message(ChatMessage),
+    StatusChanged(Username, Status),
+    /// Something we asked for failed
+    Error(String),
+    /// Something we asked for happened, like a download finishing
+    Info(String),
+    /// Encrypted direct message, only between the two of them
+    Direct {
+        from: Username,
+        to: Username,
+        content: String,
+        /// Whether the other side's key has been checked
+        verified: bool,
     },
-    Connected(Username),
-    Disconnected(Username),
 }
 impl BoardPost {
     pub fn as_text(&self) -> Text<'_> {
+        Text::from(self.as_lines(false, ""))
+    }
+
+    /// Whether this is a message from someone else mentioning `username`
+    pub fn mentions(&self, username: &str) -> bool {
         match self {
-            BoardPost::Message { username, content } => {
-                // Text::from(format!("{username}: {content}"))
-                Text::from(vec![
-                    Line::from(username.clone()).bold(),
-                    Line::from(": ".to_string()),
-                    Line::from(content.clone()),
-                ])
+            BoardPost::Message(message) => {
+                message.kind != MessageKind::System
+                    && message.username != username
+                    && markup::mentions_user(&message.content, username)
             }
-            BoardPost::Connected(user) => Text::from(format!("{user} connected").italic()),
-            BoardPost::Disconnected(user) => Text::from(format!("{user} disconnected").italic()),
+            BoardPost::StatusChanged(..)
+            | BoardPost::Error(_)
+            | BoardPost::Info(_)
+            | BoardPost::Direct { .. } => false,
+        }
+    }
+    pub fn message(&self) -> Option<&ChatMessage> {
+        match self {
+            BoardPost::Message(message) => Some(message),
+            _ => None,
+        }
+    }
+    pub fn message_mut(&mut self) -> Option<&mut ChatMessage> {
+        match self {
+            BoardPost::Message(message) => Some(message),
+            _ => None,
+        }
+    }
+
+    /// Lines to show on the board, `raw` skips formatting so content shows as typed and
+    /// mentions of `me` get highlighted
+    pub(crate) fn as_lines(&self, raw: bool, me: &str) -> Vec<Line<'static>> {
+        let mut lines = self.post_lines(raw, me);
+        let BoardPost::Message(message) = self else {
+            return lines;
+        };
+        if let Some(attachment) = &message.attachment {
+            let line = format!(
+                "  📎 {} · {} · Alt-D to download",
+                markup::sanitize(&attachment.name),
+                files::human_size(attachment.size)
+            );
+            lines.push(Line::from(line).blue());
+        }
+        if !message.reactions.is_empty() {
+            lines.push(reaction_line(message, me));
         }
+        lines
     }
 
-    pub(crate) fn as_line(&self) -> Line<'_> {
+    fn post_lines(&self, raw: bool, me: &str) -> Vec<Line<'static>> {
         match self {
-            BoardPost::Message { username, content } => {
-                Line::from(vec![username.clone().bold(), ": ".into(), content.into()])
+            BoardPost::Message(ChatMessage {
+                username,
+                kind,
+                content,
+                ..
+            }) => match kind {
+                MessageKind::Normal => content_lines(
+                    vec![username.clone().bold(), ": ".into()],
+                    content,
+                    Style::default(),
+                    raw,
+                    me,
+                ),
+                MessageKind::Action => content_lines(
+                    vec![format!("* {username} ").into()],
+                    content,
+                    Style::default().italic().magenta(),
+                    raw,
+                    me,
+                ),
+                MessageKind::Notice => content_lines(
+                    vec![format!("-{username}- ").bold().yellow()],
+                    content,
+                    Style::default().yellow(),
+                    raw,
+                    me,
+                ),
+                MessageKind::System => content_lines(
+                    vec!["*** ".into()],
+                    content,
+                    Style::default().italic().dark_gray(),
+                    true,
+                    me,
+                ),
+            },
+            BoardPost::StatusChanged(user, status) => {
+                vec![Line::from(format!("{user} is {status}").italic())]
             }
-            BoardPost::Connected(user) => Line::from(format!("{user} connected").italic()),
-            BoardPost::Disconnected(user) => Line::from(format!("{user} disconnected").italic()),
+            BoardPost::Error(reason) => vec![Line::from(format!("! {reason}").red())],
+            BoardPost::Direct {
+                from,
+                to,
+                content,
+                verified,
+            } => {
+                let mut prefix = vec![format!("🔒 {from} → {to}").bold().cyan()];
+                if !verified {
+                    prefix.push(" (unverified)".yellow());
+                }
+                prefix.push(": ".into());
+                content_lines(prefix, content, Style::default().cyan(), raw, me)
+            }
+            BoardPost::Info(info) => info
+                .lines()
+                .map(|line| Line::from(line.to_string().italic().dark_gray()))
+                .collect(),
         }
     }
 }
 
+/// Summary of reactions under a message, the ones from `me` stand out
+fn reaction_line(message: &ChatMessage, me: &str) -> Line<'static> {
+    let mut spans = vec![Span::from("  ")];
+    for reaction in &message.reactions {
+        let text = format!("{} {}", reaction.emoji, reaction.users.len());
+        if reaction.users.iter().any(|u| u == me) {
+            spans.push(text.bold().yellow());
+        } else {
+            spans.push(text.dark_gray());
+        }
+        spans.push(Span::from("  "));
+    }
+    Line::from(spans)
+}
+
+/// Line quoting the start of the message `id` a reply answers, `parent` is `None` when it
+/// isn't on the board
+pub fn quote_line(id: MessageId, parent: Option<&ChatMessage>) -> Line<'static> {
+    let quote = match parent {
+        Some(parent) => {
+            let first = markup::sanitize(&parent.content)
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            let snippet = match first.char_indices().nth(QUOTE_LEN) {
+                Some((end, _)) => format!("{}…", &first[..end]),
+                None => first,
+            };
+            format!("  ╭ {}: {snippet}", parent.username)
+        }
+        None => format!("  ╭ message {id}"),
+    };
+    Line::from(quote).italic().dark_gray()
+}
+
+/// `prefix` followed by the formatted content, code blocks start on their own lines
+fn content_lines(
+    prefix: Vec<Span<'static>>,
+    content: &str,
+    base: Style,
+    raw: bool,
+    me: &str,
+) -> Vec<Line<'static>> {
+    let mut prefix = Some(prefix);
+    let mut lines = Vec::new();
+    let blocks = if raw {
+        markup::sanitize(content)
+            .lines()
+            .map(|line| {
+                MarkupBlock::Line(vec![StyledSpan {
+                    text: line.to_string(),
+                    ..Default::default()
+                }])
+            })
+            .collect()
+    } else {
+        markup::parse(content)
+    };
+    for block in blocks {
+        match block {
+            MarkupBlock::Line(spans) => {
+                let mut line = prefix.take().unwrap_or_default();
+                line.extend(spans.into_iter().map(|span| styled_span(span, base, me)));
+                lines.push(Line::from(line).style(base));
+            }
+            MarkupBlock::Code { lang, lines: code } => {
+                if let Some(prefix) = prefix.take() {
+                    lines.push(Line::from(prefix).style(base));
+                }
+                let code_style = Style::default().fg(Color::Cyan);
+                if let Some(lang) = lang {
+                    lines.push(Line::from(format!("  ┌ {lang}")).style(code_style.dim()));
+                }
+                lines.extend(
+                    code.into_iter()
+                        .map(|code| Line::from(format!("  │ {code}")).style(code_style)),
+                );
+            }
+        }
+    }
+    if let Some(prefix) = prefix {
+        lines.push(Line::from(prefix).style(base));
+    }
+    lines
+}
+
+fn styled_span(span: StyledSpan, base: Style, me: &str) -> Span<'static> {
+    let StyledSpan {
+        text,
+        style,
+        link,
+        mention,
+    } = span;
+    if style.code {
+        return Span::styled(text, Style::default().fg(Color::Cyan).bg(Color::DarkGray));
+    }
+    match mention {
+        Some(mention) if mention == me => {
+            return Span::styled(text, Style::default().bold().black().on_yellow());
+        }
+        Some(_) => return Span::styled(text, base.bold().cyan()),
+        None => (),
+    }
+    let mut span_style = base;
+    if style.bold {
+        span_style = span_style.add_modifier(Modifier::BOLD);
+    }
+    if style.italic {
+        span_style = span_style.add_modifier(Modifier::ITALIC);
+    }
+    if style.strikethrough {
+        span_style = span_style.add_modifier(Modifier::CROSSED_OUT);
+    }
+    match link {
+        // Show where named links go since the terminal can't follow them
+        Some(url) if url != text => Span::styled(
+            format!("{text} <{url}>"),
+            span_style.fg(Color::Blue).underlined(),
+        ),
+        Some(_) => Span::styled(text, span_style.fg(Color::Blue).underlined()),
+        None => Span::styled(text, span_style),
+    }
+}
+
 impl Widget for &BoardPost {
     fn render(self, area: Rect, buf: &mut Buffer)
     where
         Self: Sized,
     {
-        match self {
-            BoardPost::Message { username, content } => {
-                Line::from(format!("{username}: {content}")).render(area, buf);
-            }
-            BoardPost::Connected(user) => {
-                Line::from(format!("{user} connected").italic()).render(area, buf);
-            }
-            BoardPost::Disconnected(user) => {
-                Line::from(format!("{user} disconnected").italic()).render(area, buf);
-            }
-        }
+        Text::from(self.as_lines(false, "")).render(area, buf);
     }
 }
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+
+use chatr::history::InputHistory;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::{buffer::Buffer, layout::Rect, style::Stylize, text::Line, widgets::Widget};
+
+use crate::chatr_widgets::text_box::TextBox;
+
+/// Most kills remembered by the KillRing
+const KILL_RING_LEN: usize = 16;
+
+/// Where users write messages, a TextBox with sent history, reverse search and a kill ring
+///
+/// Up/Down walk the history, Ctrl-R starts a reverse incremental search, Ctrl-K/Ctrl-U/Ctrl-W
+/// kill text, Ctrl-Y yanks it back and Alt-Y cycles the yank through older kills.
+#[derive(Debug, Default)]
+pub struct Composer {
+    text_box: TextBox,
+    history: InputHistory,
+    kill_ring: KillRing,
+    search: Option<HistorySearch>,
+}
+
+/// State of an in progress Ctrl-R search
+#[derive(Debug, Default)]
+struct HistorySearch {
+    query: String,
+    /// Index of the matching history entry
+    hit: Option<usize>,
+    /// What was in the TextBox before searching, restored on Esc
+    original: String,
+}
+
+/// Killed text that can be yanked back
+#[derive(Debug, Default)]
+pub struct KillRing {
+    kills: VecDeque<String>,
+    /// Ring index and length of the text the last yank inserted, cleared by any other edit
+    last_yank: Option<(usize, usize)>,
+}
+
+impl KillRing {
+    pub fn kill(&mut self, text: String) {
+        self.last_yank = None;
+        if text.is_empty() {
+            return;
+        }
+        if self.kills.len() == KILL_RING_LEN {
+            self.kills.pop_back();
+        }
+        self.kills.push_front(text);
+    }
+    /// Most recent kill
+    pub fn yank(&mut self) -> Option<&str> {
+        let text = self.kills.front()?;
+        self.last_yank = Some((0, text.len()));
+        Some(text)
+    }
+    /// Next older kill along with the length of the previous yank it replaces, only valid
+    /// straight after a yank
+    pub fn yank_pop(&mut self) -> Option<(usize, &str)> {
+        let (index, yanked_len) = self.last_yank?;
+        let index = (index + 1) % self.kills.len();
+        let text = &self.kills[index];
+        self.last_yank = Some((index, text.len()));
+        Some((yanked_len, text))
+    }
+    pub fn break_yank(&mut self) {
+        self.last_yank = None;
+    }
+}
+
+impl Composer {
+    pub fn set_history(&mut self, history: InputHistory) {
+        self.history = history;
+    }
+    pub fn is_empty(&mut self) -> bool {
+        self.text_box.is_empty()
+    }
+    pub fn is_searching(&self) -> bool {
+        self.search.is_some()
+    }
+    /// Complete the word before the cursor as a username, a unique match is filled in as a
+    /// mention and several are completed as far as they agree
+    pub fn complete_username<'a>(&mut self, usernames: impl Iterator<Item = &'a String>) {
+        let before = &self.text_box.buffer()[..self.text_box.cursor_position()];
+        let word_start = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &before[word_start..];
+        let prefix = word.strip_prefix('@').unwrap_or(word);
+        let matches: Vec<&String> = usernames.filter(|u| u.starts_with(prefix)).collect();
+        let completion = match matches.as_slice() {
+            [] => return,
+            [only] => format!("@{only} "),
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(first.len(), |len, other| {
+                    first[..len]
+                        .char_indices()
+                        .zip(other.chars())
+                        .find(|((_, a), b)| a != b)
+                        .map_or(len.min(other.len()), |((i, _), _)| i)
+                });
+                format!("@{}", &first[..common])
+            }
+        };
+        let word_len = word.len();
+        self.text_box.remove_before_cursor(word_len);
+        self.text_box.insert_str(&completion);
+    }
+    /// Take the composed message, remembering it in the history
+    pub fn take_buffer(&mut self) -> String {
+        let msg = self.text_box.take_buffer();
+        // Failing to persist the history shouldn't stop the message going out
+        self.history.push(&msg).ok();
+        msg
+    }
+    /// Returns whether the message being composed changed
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> bool {
+        if self.search.is_some() {
+            self.handle_search_key(key_event);
+            return false;
+        }
+        let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key_event.modifiers.contains(KeyModifiers::ALT);
+        match key_event.code {
+            KeyCode::Char('r') if ctrl => self.start_search(),
+            KeyCode::Char('k') if ctrl => return self.kill(TextBox::kill_to_end),
+            KeyCode::Char('u') if ctrl => return self.kill(TextBox::kill_to_start),
+            KeyCode::Char('w') if ctrl => return self.kill(TextBox::kill_word_back),
+            KeyCode::Char('y') if ctrl => {
+                if let Some(text) = self.kill_ring.yank() {
+                    self.text_box.insert_str(text);
+                    return true;
+                }
+            }
+            KeyCode::Char('y') if alt => {
+                if let Some((yanked_len, text)) = self.kill_ring.yank_pop() {
+                    self.text_box.remove_before_cursor(yanked_len);
+                    self.text_box.insert_str(text);
+                    return true;
+                }
+            }
+            KeyCode::Char('a') if ctrl => self.text_box.home(),
+            KeyCode::Char('e') if ctrl => self.text_box.end(),
+            KeyCode::Up => {
+                let current = self.text_box.buffer().to_string();
+                if let Some(entry) = self.history.older(&current) {
+                    self.text_box.set_buffer(entry.to_string());
+                }
+            }
+            KeyCode::Down => {
+                if let Some(entry) = self.history.newer() {
+                    self.text_box.set_buffer(entry.to_string());
+                }
+            }
+            _ if ctrl || alt => {}
+            _ => {
+                self.kill_ring.break_yank();
+                return self.text_box.handle_key_event(key_event);
+            }
+        }
+        false
+    }
+
+    fn kill(&mut self, kill: fn(&mut TextBox) -> String) -> bool {
+        let killed = kill(&mut self.text_box);
+        let changed = !killed.is_empty();
+        self.kill_ring.kill(killed);
+        changed
+    }
+    fn start_search(&mut self) {
+        self.search = Some(HistorySearch {
+            original: self.text_box.buffer().to_string(),
+            ..Default::default()
+        });
+    }
+    fn cancel_search(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.text_box.set_buffer(search.original);
+        }
+    }
+    fn handle_search_key(&mut self, key_event: KeyEvent) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        match key_event.code {
+            KeyCode::Char('r') if ctrl => {
+                // Look further back than the current hit
+                if let Some(older) = search.hit.and_then(|hit| hit.checked_sub(1))
+                    && let Some((i, _)) = self.history.search_back(&search.query, Some(older))
+                {
+                    search.hit = Some(i);
+                }
+            }
+            KeyCode::Char(c) if !ctrl => {
+                search.query.push(c);
+                search.hit = self
+                    .history
+                    .search_back(&search.query, None)
+                    .map(|(i, _)| i);
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                search.hit = self
+                    .history
+                    .search_back(&search.query, None)
+                    .map(|(i, _)| i);
+            }
+            KeyCode::Esc => self.cancel_search(),
+            KeyCode::Char('g') if ctrl => self.cancel_search(),
+            _ => {
+                let found = search.hit.and_then(|i| self.history.entry(i));
+                if let Some(entry) = found {
+                    self.text_box.set_buffer(entry.to_string());
+                }
+                self.search = None;
+            }
+        }
+    }
+}
+
+impl Widget for &Composer {
+    fn render(self, area: Rect, buf: &mut Buffer)
+    where
+        Self: Sized,
+    {
+        match &self.search {
+            Some(search) => {
+                let found = search
+                    .hit
+                    .and_then(|i| self.history.entry(i))
+                    .unwrap_or_default();
+                Line::from(vec![
+                    format!("(reverse-i-search)`{}': ", search.query).italic(),
+                    found.into(),
+                ])
+                .render(area, buf);
+            }
+            None => self.text_box.render(area, buf),
+        }
+    }
+}
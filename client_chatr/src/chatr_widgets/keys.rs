@@ -0,0 +1,146 @@
+use chatr::{
+    Username,
+    e2e::{self, Keyring},
+};
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Block, BorderType, Borders, Clear, Paragraph, Widget},
+};
+
+/// What the app should do after a key went to the keys overlay
+#[derive(Debug)]
+pub enum KeysAction {
+    Nothing,
+    /// Mark this user's key as checked, or not
+    SetVerified(Username, bool),
+    Close,
+}
+
+/// Box over the message board listing key fingerprints to compare, opened with Alt-K
+#[derive(Debug, Default)]
+pub struct KeysOverlay {
+    /// Our own fingerprint
+    mine: String,
+    /// Everyone we have a key for, with its fingerprint and whether it's verified
+    peers: Vec<(Username, String, bool)>,
+    /// Fingerprints of keys that changed and wait on being verified, by position in `peers`
+    changed: Vec<Option<String>>,
+    selected: usize,
+}
+
+impl KeysOverlay {
+    pub fn new(keyring: &Keyring) -> Self {
+        let mut overlay = Self::default();
+        overlay.refresh(keyring);
+        overlay
+    }
+    /// Keys changed, like one getting verified or a new one coming in
+    pub fn refresh(&mut self, keyring: &Keyring) {
+        self.mine = e2e::fingerprint(&keyring.public());
+        (self.peers, self.changed) = keyring
+            .known()
+            .map(|(username, known)| {
+                (
+                    (
+                        username.clone(),
+                        e2e::fingerprint(&known.key),
+                        known.verified,
+                    ),
+                    known.changed.as_ref().map(e2e::fingerprint),
+                )
+            })
+            .unzip();
+        self.selected = self.selected.min(self.peers.len().saturating_sub(1));
+    }
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> KeysAction {
+        match key_event.code {
+            KeyCode::Esc => KeysAction::Close,
+            KeyCode::Up => {
+                self.selected = self.selected.saturating_sub(1);
+                KeysAction::Nothing
+            }
+            KeyCode::Down => {
+                self.selected = (self.selected + 1).min(self.peers.len().saturating_sub(1));
+                KeysAction::Nothing
+            }
+            KeyCode::Enter => match self.peers.get(self.selected) {
+                // A changed key only goes one way, to taking the new one
+                Some((username, _, verified)) => {
+                    let changed = self.changed[self.selected].is_some();
+                    KeysAction::SetVerified(username.clone(), changed || !verified)
+                }
+                None => KeysAction::Nothing,
+            },
+            _ => KeysAction::Nothing,
+        }
+    }
+}
+
+impl Widget for &KeysOverlay {
+    fn render(self, area: Rect, buf: &mut Buffer)
+    where
+        Self: Sized,
+    {
+        let block = Block::default()
+            .title_top(" Keys ")
+            .title_bottom(" ↑↓ pick · Enter verify/unverify · Esc close ")
+            .borders(Borders::ALL)
+            .border_type(BorderType::Plain);
+        let inner = block.inner(area);
+        Clear.render(area, buf);
+        block.render(area, buf);
+        let mut lines = vec![
+            Line::from(vec!["you  ".bold(), self.mine.clone().into()]),
+            Line::from("compare fingerprints some other way before verifying them")
+                .italic()
+                .dark_gray(),
+            Line::from(""),
+        ];
+        if self.peers.is_empty() {
+            lines.push(
+                Line::from("no keys yet, /msg someone to get theirs")
+                    .italic()
+                    .dark_gray(),
+            );
+        }
+        for (i, ((username, fingerprint, verified), changed)) in
+            self.peers.iter().zip(&self.changed).enumerate()
+        {
+            let line = match changed {
+                Some(changed) => Line::from(vec![
+                    "! ".red(),
+                    username.clone().bold(),
+                    "  ".into(),
+                    changed.clone().into(),
+                    "  changed, was ".red(),
+                    fingerprint.clone().dark_gray(),
+                ]),
+                None => Line::from(vec![
+                    if *verified {
+                        "✓ ".green()
+                    } else {
+                        "? ".yellow()
+                    },
+                    username.clone().bold(),
+                    "  ".into(),
+                    fingerprint.clone().into(),
+                ]),
+            };
+            lines.push(if i == self.selected {
+                line.patch_style(Style::default().bg(Color::DarkGray))
+            } else {
+                line
+            });
+        }
+        // Keep the picked key in view, the first three lines are the header
+        let height = inner.height as usize;
+        let scroll = (self.selected + 4).saturating_sub(height);
+        Paragraph::new(lines)
+            .scroll((scroll as u16, 0))
+            .render(inner, buf);
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use chatr::{Presence, Status, Username};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::Stylize,
+    text::{Line, Span},
+    widgets::{Block, BorderType, Borders, Paragraph, Widget},
+};
+
+/// Sidebar listing who is currently in the chatroom and their status
+#[derive(Debug, Default)]
+pub struct Roster {
+    members: BTreeMap<Username, Status>,
+}
+
+impl Roster {
+    /// Replace everyone with a snapshot from the server
+    pub fn set(&mut self, members: Vec<(Username, Status)>) {
+        self.members = members.into_iter().collect();
+    }
+    pub fn user_connected(&mut self, username: Username) {
+        self.members.insert(username, Status::default());
+    }
+    pub fn user_disconnected(&mut self, username: &Username) {
+        self.members.remove(username);
+    }
+    pub fn status_changed(&mut self, username: Username, status: Status) {
+        self.members.insert(username, status);
+    }
+    pub fn usernames(&self) -> impl Iterator<Item = &Username> {
+        self.members.keys()
+    }
+}
+
+/// Username coloured by presence, followed by any status text
+fn member_line<'a>(username: &'a str, status: &'a Status) -> Line<'a> {
+    let name = match status.presence {
+        Presence::Online => Span::from(username),
+        Presence::Away => Span::from(username).yellow(),
+        Presence::Busy => Span::from(username).red(),
+    };
+    match &status.text {
+        Some(text) => Line::from(vec![name, " ".into(), text.as_str().italic().dark_gray()]),
+        None => Line::from(name),
+    }
+}
+
+impl Widget for &Roster {
+    fn render(self, area: Rect, buf: &mut Buffer)
+    where
+        Self: Sized,
+    {
+        let block = Block::default()
+            .title_top(format!(" Online ({}) ", self.members.len()))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Plain);
+        let names = self
+            .members
+            .iter()
+            .map(|(username, status)| member_line(username, status))
+            .collect::<Vec<Line>>();
+        Paragraph::new(names).block(block).render(area, buf);
+    }
+}
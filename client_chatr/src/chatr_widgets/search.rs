@@ -0,0 +1,175 @@
+use chatr::{ChatMessage, ChatrMessage, markup, search::SearchQuery, time};
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Block, BorderType, Borders, Clear, Paragraph, Widget},
+};
+
+use crate::chatr_widgets::text_box::TextBox;
+
+/// What the app should do after a key went to the search overlay
+#[derive(Debug)]
+pub enum SearchAction {
+    Nothing,
+    /// Ask the server
+    Send(ChatrMessage),
+    /// Show this hit on the message board
+    Jump(ChatMessage),
+    Close,
+}
+
+/// Box over the message board for searching the server's history, opened with Ctrl-F
+#[derive(Debug)]
+pub struct SearchOverlay {
+    input: TextBox,
+    /// Input the current hits are for
+    submitted: String,
+    query: Option<SearchQuery>,
+    hits: Vec<ChatMessage>,
+    more: bool,
+    selected: usize,
+    /// Shown instead of the hits, like when nothing matched
+    note: Option<String>,
+}
+
+impl Default for SearchOverlay {
+    fn default() -> Self {
+        let mut input = TextBox::default();
+        input.select();
+        Self {
+            input,
+            submitted: String::new(),
+            query: None,
+            hits: Vec::new(),
+            more: false,
+            selected: 0,
+            note: None,
+        }
+    }
+}
+
+impl SearchOverlay {
+    /// Results came back, later pages add to what's already listed
+    pub fn results(&mut self, query: SearchQuery, hits: Vec<ChatMessage>, more: bool) {
+        if self
+            .query
+            .as_ref()
+            .is_some_and(|q| q.page + 1 == query.page)
+        {
+            self.hits.extend(hits);
+        } else {
+            self.hits = hits;
+            self.selected = 0;
+        }
+        self.note = self.hits.is_empty().then(|| "nothing found".to_string());
+        self.query = Some(query);
+        self.more = more;
+    }
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> SearchAction {
+        match key_event.code {
+            KeyCode::Esc => SearchAction::Close,
+            KeyCode::Up => {
+                self.selected = self.selected.saturating_sub(1);
+                SearchAction::Nothing
+            }
+            KeyCode::Down => {
+                self.selected = (self.selected + 1).min(self.hits.len().saturating_sub(1));
+                SearchAction::Nothing
+            }
+            KeyCode::PageDown => match &self.query {
+                Some(query) if self.more => {
+                    self.more = false;
+                    SearchAction::Send(ChatrMessage::Search {
+                        query: query.next_page(),
+                    })
+                }
+                _ => SearchAction::Nothing,
+            },
+            // Enter searches for new input, or picks the hit when the input hasn't changed
+            KeyCode::Enter if self.input.buffer() != self.submitted => {
+                self.submitted = self.input.buffer().to_string();
+                match SearchQuery::parse(&self.submitted) {
+                    Some(query) => {
+                        self.note = Some("searching…".to_string());
+                        SearchAction::Send(ChatrMessage::Search { query })
+                    }
+                    None => {
+                        self.note = Some("nothing to search for".to_string());
+                        SearchAction::Nothing
+                    }
+                }
+            }
+            KeyCode::Enter => match self.hits.get(self.selected) {
+                Some(hit) => SearchAction::Jump(hit.clone()),
+                None => SearchAction::Nothing,
+            },
+            _ => {
+                self.input.handle_key_event(key_event);
+                SearchAction::Nothing
+            }
+        }
+    }
+}
+
+/// Hit as a single line, when it was sent, who sent it and the start of what they said
+fn hit_line(hit: &ChatMessage) -> Line<'static> {
+    let content = markup::sanitize(&hit.content).replace('\n', " ");
+    Line::from(vec![
+        time::format_time(hit.sent_at).dark_gray(),
+        " ".into(),
+        hit.username.clone().bold(),
+        ": ".into(),
+        content.into(),
+    ])
+}
+
+impl Widget for &SearchOverlay {
+    fn render(self, area: Rect, buf: &mut Buffer)
+    where
+        Self: Sized,
+    {
+        let block = Block::default()
+            .title_top(" Search ")
+            .title_bottom(" Enter search/jump · ↑↓ pick · PgDn more · Esc close ")
+            .borders(Borders::ALL)
+            .border_type(BorderType::Plain);
+        let inner = block.inner(area);
+        Clear.render(area, buf);
+        block.render(area, buf);
+        let [input_area, hits_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(inner);
+        self.input.render(input_area, buf);
+        if let Some(note) = &self.note {
+            Line::from(note.clone())
+                .italic()
+                .dark_gray()
+                .render(hits_area, buf);
+            return;
+        }
+        let mut lines: Vec<Line> = self
+            .hits
+            .iter()
+            .enumerate()
+            .map(|(i, hit)| {
+                let line = hit_line(hit);
+                if i == self.selected {
+                    line.patch_style(Style::default().bg(Color::DarkGray))
+                } else {
+                    line
+                }
+            })
+            .collect();
+        if self.more {
+            lines.push(Line::from("PgDn for more").italic().dark_gray());
+        }
+        // Keep the picked hit in view
+        let height = hits_area.height as usize;
+        let scroll = (self.selected + 1).saturating_sub(height);
+        Paragraph::new(lines)
+            .scroll((scroll as u16, 0))
+            .render(hits_area, buf);
+    }
+}
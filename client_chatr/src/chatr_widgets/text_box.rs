@@ -17,7 +17,8 @@ pub struct TextBox {
 /// Little square to show where text will get placed/deleted from a TextBox
 #[derive(Debug, Default)]
 pub struct Cursor {
-    position: u16,
+    /// Counted in chars, not bytes
+    position: usize,
     inverted: bool,
 }
 
@@ -37,11 +38,14 @@ impl Cursor {
         }
     }
     pub fn position(&self) -> usize {
-        self.position as usize
+        self.position
     }
     pub fn reset(&mut self) {
         self.position = 0;
     }
+    pub fn set(&mut self, position: usize) {
+        self.position = position;
+    }
 }
 
 impl TextBox {
@@ -60,45 +64,90 @@ impl TextBox {
         self.cursor.reset();
         std::mem::take(&mut self.buffer)
     }
+    pub fn buffer(&self) -> &str {
+        &self.buffer
+    }
+    /// Where the cursor is as a byte offset into [`TextBox::buffer`]
+    pub fn cursor_position(&self) -> usize {
+        self.byte_offset(self.cursor.position())
+    }
+    /// Byte offset of the char at `chars`, or the end
+    fn byte_offset(&self, chars: usize) -> usize {
+        self.buffer
+            .char_indices()
+            .nth(chars)
+            .map_or(self.buffer.len(), |(i, _)| i)
+    }
+    fn char_len(&self) -> usize {
+        self.buffer.chars().count()
+    }
+    /// Replace the contents, leaving the cursor at the end
+    pub fn set_buffer(&mut self, buffer: String) {
+        self.cursor.set(buffer.chars().count());
+        self.buffer = buffer;
+    }
+    pub fn insert_str(&mut self, s: &str) {
+        self.buffer.insert_str(self.cursor_position(), s);
+        self.cursor.set(self.cursor.position() + s.chars().count());
+    }
+    /// Remove the `len` bytes before the cursor, which have to end on a char boundary
+    pub fn remove_before_cursor(&mut self, len: usize) -> String {
+        let end = self.cursor_position();
+        let start = end.saturating_sub(len);
+        let removed: String = self.buffer.drain(start..end).collect();
+        self.cursor
+            .set(self.cursor.position() - removed.chars().count());
+        removed
+    }
+    pub fn kill_to_end(&mut self) -> String {
+        self.buffer.split_off(self.cursor_position())
+    }
+    pub fn kill_to_start(&mut self) -> String {
+        let len = self.cursor_position();
+        self.remove_before_cursor(len)
+    }
+    /// Remove the word before the cursor along with any whitespace trailing it
+    pub fn kill_word_back(&mut self) -> String {
+        let before = &self.buffer[..self.cursor_position()];
+        let word_start = before
+            .trim_end()
+            .char_indices()
+            .rfind(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        self.remove_before_cursor(before.len() - word_start)
+    }
+    pub fn home(&mut self) {
+        self.cursor.reset();
+    }
+    pub fn end(&mut self) {
+        self.cursor.set(self.char_len());
+    }
     fn handle_key_code(&mut self, key_code: KeyCode) {
         match key_code {
             KeyCode::Char(c) => {
-                if self.cursor.position() == self.buffer.len() {
-                    self.buffer.push(c);
-                    self.cursor.forward();
-                } else {
-                    self.buffer.insert(self.cursor.position(), c);
-                    self.cursor.forward();
-                }
+                self.buffer.insert(self.cursor_position(), c);
+                self.cursor.forward();
             }
-            KeyCode::Backspace => {
-                if !self.buffer.is_empty() {
-                    if self.cursor.position() == self.buffer.len() {
-                        if self.buffer.pop().is_some() {
-                            self.cursor.backward()
-                        }
-                    } else if self.cursor.position() != 0 {
-                        self.buffer.remove(self.cursor.position() - 1);
-                        self.cursor.backward();
-                    }
-                }
+            KeyCode::Backspace if self.cursor.position() != 0 => {
+                self.cursor.backward();
+                self.buffer.remove(self.cursor_position());
             }
-            KeyCode::Delete => {
-                if !self.buffer.is_empty() && self.cursor.position() != self.buffer.len() {
-                    self.buffer.remove(self.cursor.position());
-                }
+            KeyCode::Delete if self.cursor.position() < self.char_len() => {
+                self.buffer.remove(self.cursor_position());
             }
+            KeyCode::Home => self.home(),
+            KeyCode::End => self.end(),
             KeyCode::Left => self.cursor.backward(),
-            KeyCode::Right => {
-                if self.cursor.position() < self.buffer.len() {
-                    self.cursor.forward()
-                }
-            }
+            KeyCode::Right if self.cursor.position() < self.char_len() => self.cursor.forward(),
             _ => {}
         }
     }
-    pub fn handle_key_event(&mut self, key_event: KeyEvent) {
+    /// Returns whether the buffer changed
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> bool {
+        // Every edit a key can make adds or removes characters
+        let len = self.buffer.len();
         self.handle_key_code(key_event.code);
+        len != self.buffer.len()
     }
 }
 
@@ -177,14 +226,18 @@ impl Widget for &Cursor {
     where
         Self: Sized,
     {
+        // Past the edge of the box there's nowhere to draw it
+        let Some(x) = u16::try_from(self.position)
+            .ok()
+            .and_then(|position| area.x.checked_add(position))
+            .filter(|x| *x < area.right())
+        else {
+            return;
+        };
         if self.inverted {
-            buf[(area.x + self.position, area.y)]
-                .set_fg(Color::White)
-                .set_bg(Color::Black);
+            buf[(x, area.y)].set_fg(Color::White).set_bg(Color::Black);
         } else {
-            buf[(area.x + self.position, area.y)]
-                .set_bg(Color::White)
-                .set_fg(Color::Black);
+            buf[(x, area.y)].set_bg(Color::White).set_fg(Color::Black);
         }
     }
 }
@@ -1,33 +1,74 @@
-use std::{io, vec};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+    vec,
+};
 
-use chatr::{ChatrMessage, client::ClientConnection};
+use chatr::{
+    ChatMessage, ChatrMessage, MessageId, MessageKind, Presence, Status, Username,
+    client::{ChatrClient, ClientEvent, Credentials},
+    command::{FileCommand, parse_file_command, parse_input},
+    e2e::Keyring,
+    export::PendingExport,
+    files::{TransferEvent, Transfers},
+    history::InputHistory,
+    markup,
+    reaction::QUICK_REACTIONS,
+    room::{Access, RoomInfo, Topic},
+    typing::{TypingDebounce, TypingUsers},
+};
 use crossterm::event::{self, Event, EventStream, KeyCode, KeyEventKind, KeyModifiers};
 use futures::StreamExt;
 use ratatui::{
     DefaultTerminal, Frame,
     buffer::Buffer,
     layout::{Constraint, Layout, Rect, Spacing},
-    style::{Color, Stylize},
+    style::{Color, Style, Stylize},
     text::{Line, Text},
     widgets::{
         Block, BorderType, Borders, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState,
         StatefulWidget, Widget, Wrap,
     },
 };
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio_util::sync::CancellationToken;
+use tokio::time::Instant;
 
 use crate::chatr_widgets::{
-    board_post::BoardPost,
+    board_post::{self, BoardPost},
+    composer::Composer,
+    keys::{KeysAction, KeysOverlay},
+    roster::Roster,
+    search::{SearchAction, SearchOverlay},
     text_box::{TextBox, TitledTextBox},
 };
 pub mod chatr_widgets;
 
+/// Env var overriding how many minutes without input before going away, 0 turns it off
+const IDLE_MINUTES_ENV: &str = "CHATR_IDLE_MINUTES";
+const DEFAULT_IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+/// Env var picking how mentions get your attention: `bell` (the default), `osc` for a desktop
+/// notification or `off`
+const NOTIFY_ENV: &str = "CHATR_NOTIFY";
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     color_eyre::install().unwrap();
+    let mut app = App::default();
+    app.composer.set_history(InputHistory::from_env()?);
+    app.transfers = Transfers::from_env();
+    if let Some(minutes) = std::env::var(IDLE_MINUTES_ENV)
+        .ok()
+        .and_then(|m| m.parse::<u64>().ok())
+    {
+        app.idle.idle_after = (minutes != 0).then(|| Duration::from_secs(minutes * 60));
+    }
+    app.notify = match std::env::var(NOTIFY_ENV).as_deref() {
+        Ok("osc") => Notify::Osc,
+        Ok("off") => Notify::Off,
+        _ => Notify::Bell,
+    };
     let mut terminal = ratatui::init();
-    let result = App::default().run(&mut terminal).await;
+    let result = app.run(&mut terminal).await;
     ratatui::restore();
     result
 }
@@ -38,25 +79,265 @@ struct App {
     /// Where received messages get displayed
     message_board: MessageBoard,
     /// Where use writes messages
-    buffer: TextBox,
+    composer: Composer,
+    /// Who is online, shown beside the message board
+    roster: Roster,
+    /// Toggled with Ctrl-O
+    hide_roster: bool,
+    idle: IdleTracker,
+    /// Limits how often our own typing gets signalled
+    typing_debounce: TypingDebounce,
+    /// Others currently typing, shown above the composer
+    typing_users: TypingUsers,
+    notify: Notify,
+    /// Files shared in the chatroom and transfers in progress
+    transfers: Transfers,
+    /// Open with Ctrl-F
+    search: Option<SearchOverlay>,
+    /// History being saved with `/export`
+    export: Option<PendingExport>,
+    /// Our identity and everyone's keys, shared with the connection that seals and opens
+    /// direct messages with them
+    keyring: Option<Arc<Mutex<Keyring>>>,
+    /// Open with Alt-K
+    keys: Option<KeysOverlay>,
     exit: bool,
 }
 
+/// How to get the user's attention when they're mentioned
+#[derive(Debug, Default)]
+enum Notify {
+    /// Ring the terminal bell
+    #[default]
+    Bell,
+    /// OSC 9 desktop notification, for terminals that support it
+    Osc,
+    Off,
+}
+
+impl Notify {
+    fn mentioned(&self, username: &str, content: &str) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        match self {
+            Notify::Bell => stdout.write_all(b"\x07")?,
+            Notify::Osc => {
+                let text = markup::sanitize(&format!("{username}: {content}")).replace('\n', " ");
+                write!(stdout, "\x1b]9;{text}\x07")?
+            }
+            Notify::Off => return Ok(()),
+        }
+        stdout.flush()
+    }
+}
+
+/// Flips the user to away after a stretch without input and back on their next keypress
+#[derive(Debug)]
+struct IdleTracker {
+    idle_after: Option<Duration>,
+    last_input: Instant,
+    /// Status the user picked themselves, restored when they come back
+    status: Status,
+    /// Set while away only because of idling
+    auto_away: bool,
+}
+
+impl Default for IdleTracker {
+    fn default() -> Self {
+        Self {
+            idle_after: Some(DEFAULT_IDLE_AFTER),
+            last_input: Instant::now(),
+            status: Status::default(),
+            auto_away: false,
+        }
+    }
+}
+
+impl IdleTracker {
+    /// When to go away, only while online by choice
+    fn deadline(&self) -> Option<Instant> {
+        if self.auto_away || self.status.presence != Presence::Online {
+            return None;
+        }
+        self.idle_after
+            .map(|idle_after| self.last_input + idle_after)
+    }
+    fn went_idle(&mut self) -> ChatrMessage {
+        self.auto_away = true;
+        ChatrMessage::SetStatus {
+            status: Status {
+                presence: Presence::Away,
+                text: Some("idle".to_string()),
+            },
+        }
+    }
+    /// Note some input, giving back the status to restore if the user was idle
+    fn input(&mut self) -> Option<ChatrMessage> {
+        self.last_input = Instant::now();
+        if !self.auto_away {
+            return None;
+        }
+        self.auto_away = false;
+        Some(ChatrMessage::SetStatus {
+            status: self.status.clone(),
+        })
+    }
+    fn set_status(&mut self, status: Status) {
+        self.status = status;
+        self.auto_away = false;
+    }
+}
+
 /// Displays events and messages received from chatroom
 #[derive(Debug, Default)]
 struct MessageBoard {
     messages: Vec<BoardPost>,
+    /// Show content as typed instead of formatted, toggled with Ctrl-T
+    raw: bool,
+    /// Only show posts mentioning us, toggled with Ctrl-N
+    mentions_only: bool,
+    /// Our own username, for picking out mentions
+    username: Username,
+    /// Message picked with Alt-Up/Alt-Down, what gets replied to
+    selected: Option<MessageId>,
+    /// Root of the thread being viewed, toggled with Alt-T
+    thread: Option<MessageId>,
+    /// Room we're in, shown in the title with its topic
+    room: Option<RoomInfo>,
 }
 
 impl MessageBoard {
-    pub fn user_disconnected(&mut self, username: String) {
-        self.messages.push(BoardPost::Disconnected(username));
+    /// Moved to another room, what was shown for the last one goes
+    pub fn joined(&mut self, room: RoomInfo) {
+        if self.room.is_some() {
+            self.messages.clear();
+            self.selected = None;
+            self.thread = None;
+        }
+        self.room = Some(room);
     }
-    pub fn user_connected(&mut self, username: String) {
-        self.messages.push(BoardPost::Connected(username));
+    pub fn topic_changed(&mut self, room: &str, topic: Option<Topic>) {
+        if let Some(info) = &mut self.room
+            && info.name == room
+        {
+            info.topic = topic;
+        }
+    }
+    pub fn post_message(&mut self, message: ChatMessage) {
+        self.messages.push(BoardPost::Message(message));
+    }
+    pub fn status_changed(&mut self, username: String, status: Status) {
+        self.messages
+            .push(BoardPost::StatusChanged(username, status));
+    }
+    pub fn error(&mut self, reason: String) {
+        self.messages.push(BoardPost::Error(reason));
+    }
+    pub fn info(&mut self, info: String) {
+        self.messages.push(BoardPost::Info(info));
+    }
+    pub fn reaction_added(&mut self, id: MessageId, emoji: &str, username: &Username) {
+        if let Some(message) = self.get_mut(id) {
+            message.add_reaction(emoji, username);
+        }
+    }
+    pub fn reaction_removed(&mut self, id: MessageId, emoji: &str, username: &Username) {
+        if let Some(message) = self.get_mut(id) {
+            message.remove_reaction(emoji, username);
+        }
+    }
+    /// React to the selected message with one of the quick reactions, or take it back if we
+    /// already did
+    pub fn toggle_reaction(&self, quick: usize) -> Option<ChatrMessage> {
+        let message = self.get(self.selected?)?;
+        let emoji = QUICK_REACTIONS.get(quick)?.to_string();
+        let id = message.id;
+        Some(if message.has_reaction(&emoji, &self.username) {
+            ChatrMessage::Unreact { id, emoji }
+        } else {
+            ChatrMessage::React { id, emoji }
+        })
+    }
+    fn get_mut(&mut self, id: MessageId) -> Option<&mut ChatMessage> {
+        self.messages
+            .iter_mut()
+            .filter_map(BoardPost::message_mut)
+            .find(|message| message.id == id)
+    }
+    /// Select a message, putting it on the board first if it's from before what we have
+    pub fn show_message(&mut self, message: ChatMessage) {
+        let id = message.id;
+        if self.get(id).is_none() {
+            let at = self
+                .messages
+                .iter()
+                .position(|post| post.message().is_some_and(|m| m.id > id))
+                .unwrap_or(self.messages.len());
+            self.messages.insert(at, BoardPost::Message(message));
+        }
+        self.thread = None;
+        self.mentions_only = false;
+        self.selected = Some(id);
+    }
+    pub fn get(&self, id: MessageId) -> Option<&ChatMessage> {
+        self.messages
+            .iter()
+            .filter_map(BoardPost::message)
+            .find(|message| message.id == id)
     }
-    pub fn post_message(&mut self, username: String, content: String) {
-        self.messages.push(BoardPost::Message { username, content });
+    /// First message of the thread `id` is in, as far back as the board goes
+    pub fn thread_root(&self, id: MessageId) -> MessageId {
+        let mut root = id;
+        while let Some(parent) = self.get(root).and_then(|message| message.parent) {
+            root = parent;
+        }
+        root
+    }
+    /// Messages that can be replied to, system notices can't
+    fn selectable(&self) -> Vec<MessageId> {
+        self.messages
+            .iter()
+            .filter(|post| self.shown(post))
+            .filter_map(BoardPost::message)
+            .filter(|message| message.kind != MessageKind::System)
+            .map(|message| message.id)
+            .collect()
+    }
+    /// Move the selection up a message, starting from the newest
+    pub fn select_older(&mut self) {
+        let ids = self.selectable();
+        self.selected = match self
+            .selected
+            .and_then(|s| ids.iter().position(|id| *id == s))
+        {
+            Some(0) => ids.first().copied(),
+            Some(i) => Some(ids[i - 1]),
+            None => ids.last().copied(),
+        };
+    }
+    /// Move the selection down a message, past the newest clears it
+    pub fn select_newer(&mut self) {
+        let ids = self.selectable();
+        self.selected = self
+            .selected
+            .and_then(|s| ids.iter().position(|id| *id == s))
+            .and_then(|i| ids.get(i + 1).copied());
+    }
+    /// View the thread of the selected message, or go back to everything
+    pub fn toggle_thread(&mut self) {
+        self.thread = match (self.thread, self.selected) {
+            (None, Some(selected)) => Some(self.thread_root(selected)),
+            _ => None,
+        };
+    }
+    fn shown(&self, post: &BoardPost) -> bool {
+        if self.mentions_only && !post.mentions(&self.username) {
+            return false;
+        }
+        match (self.thread, post.message()) {
+            (Some(root), Some(message)) => self.thread_root(message.id) == root,
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
     }
 }
 
@@ -65,23 +346,55 @@ impl Widget for &MessageBoard {
     where
         Self: Sized,
     {
+        let mut title = match &self.room {
+            Some(RoomInfo {
+                name,
+                topic: Some(topic),
+                ..
+            }) => format!(" #{name}: {} ", markup::sanitize(&topic.text)),
+            Some(RoomInfo { name, .. }) => format!(" #{name} "),
+            None => " Chatr ".to_string(),
+        };
+        match (self.thread, self.mentions_only) {
+            (Some(_), _) => title.push_str("- thread "),
+            (None, true) => title.push_str("- mentions "),
+            (None, false) => (),
+        }
         let block = Block::default()
-            .title_top(" Chatr ")
+            .title_top(title)
             .borders(Borders::ALL)
             .border_type(BorderType::Plain);
         let inner = block.inner(area);
-        let msgs = self
-            .messages
-            .iter()
-            .map(|m| m.as_line())
-            .collect::<Vec<Line>>();
+        let mut msgs = Vec::new();
+        // Where the selected message starts, so it can be scrolled to
+        let mut selected_start = None;
+        for post in self.messages.iter().filter(|post| self.shown(post)) {
+            let message = post.message();
+            if let Some(parent) = message.and_then(|message| message.parent) {
+                msgs.push(board_post::quote_line(parent, self.get(parent)));
+            }
+            let lines = post.as_lines(self.raw, &self.username);
+            if message.is_some_and(|message| Some(message.id) == self.selected) {
+                selected_start = Some(msgs.len());
+                let selected = Style::default().bg(Color::DarkGray);
+                msgs.extend(lines.into_iter().map(|line| line.patch_style(selected)));
+            } else {
+                msgs.extend(lines);
+            }
+        }
+        let selected_offset = selected_start.map(|start| {
+            Paragraph::new(msgs[..start].to_vec())
+                .wrap(Wrap { trim: false })
+                .line_count(inner.width)
+        });
         let para = Paragraph::new(msgs).block(block).wrap(Wrap { trim: false });
         let content_height = para.line_count(inner.width);
         let view_height = inner.height as usize;
         let max_scroll = content_height.saturating_sub(view_height);
-        let para = para.scroll((max_scroll as u16, 0));
+        let scroll = selected_offset.map_or(max_scroll, |offset| offset.min(max_scroll));
+        let para = para.scroll((scroll as u16, 0));
         para.render(area, buf);
-        let mut sb_state = ScrollbarState::new(content_height).position(max_scroll);
+        let mut sb_state = ScrollbarState::new(content_height).position(scroll);
         let scrollbar = Scrollbar::new(ScrollbarOrientation::VerticalRight);
         scrollbar.render(area, buf, &mut sb_state);
     }
@@ -210,8 +523,10 @@ impl LoginFlow {
                     return Err(io::Error::new(io::ErrorKind::Interrupted, "user quit"));
                 }
             }
-            None => todo!(),
-            x => todo!("{x:?}"),
+            Some(Err(e)) => return Err(e),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input closed")),
+            // Resizes and the like, the next draw takes care of them
+            Some(Ok(_)) => (),
         }
         Ok(())
     }
@@ -266,18 +581,31 @@ impl App {
     pub async fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
         let mut event_stream = event::EventStream::new();
         let mut lf = LoginFlow::default();
-        lf.run(terminal, &mut event_stream).await.unwrap();
-        let (username, host) = lf.verify().unwrap();
-        let mut client_conn = ClientConnection::new(&host).await.unwrap();
-        client_conn.login(username).await.unwrap();
-        let ct = CancellationToken::new();
-        let (s1, mut r1) = tokio::sync::mpsc::channel(1024);
-        let (mut s2, r2) = tokio::sync::mpsc::channel(1024);
-        client_conn.run(s1, r2, ct);
+        match lf.run(terminal, &mut event_stream).await {
+            // Quit before logging in
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            result => result?,
+        }
+        let (username, host) = lf.verify()?;
+        let keyring = Keyring::from_env(username.clone())?;
+        self.message_board.username = username.clone();
+        let credentials = Credentials::new(username).with_keyring(keyring);
+        let mut client = ChatrClient::connect(&host, credentials).await?;
+        self.keyring = client.keyring();
+        let result = self.chat(terminal, &mut event_stream, &mut client).await;
+        // Log out however the session ended
+        client.disconnect().await;
+        result
+    }
+    async fn chat(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        event_stream: &mut EventStream,
+        client: &mut ChatrClient,
+    ) -> io::Result<()> {
         while !self.exit {
             terminal.draw(|frame| self.draw(frame))?;
-            self.handle_events(&mut event_stream, &mut r1, &mut s2)
-                .await?;
+            self.handle_events(event_stream, client).await?;
         }
         Ok(())
     }
@@ -288,53 +616,285 @@ impl App {
     fn exit(&mut self) {
         self.exit = true;
     }
-    async fn send_message(&mut self, send_message: &mut Sender<ChatrMessage>) -> io::Result<()> {
-        let msg = self.buffer.take_buffer();
+    async fn send_message(&mut self, client: &ChatrClient) -> io::Result<()> {
+        let msg = self.composer.take_buffer();
         if msg.is_empty() {
             Ok(())
         } else {
-            send_message
-                .send(ChatrMessage::SentMessage { content: msg })
-                .await
-                .unwrap();
-            Ok(())
+            self.typing_debounce.reset();
+            let file_msg = match parse_file_command(&msg) {
+                Some(FileCommand::Upload(path)) => self.transfers.upload(&path).await,
+                Some(FileCommand::Download(id)) => self.transfers.download(id).await,
+                Some(FileCommand::Export(..)) if self.export.is_some() => {
+                    self.message_board.error("already exporting".to_string());
+                    return Ok(());
+                }
+                Some(FileCommand::Export(format, path, query)) => {
+                    self.export = Some(PendingExport::new(format, path, &query));
+                    Ok(ChatrMessage::Export { query })
+                }
+                None => Ok(parse_input(&msg)),
+            };
+            let mut msg = match file_msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.message_board.error(e.to_string());
+                    return Ok(());
+                }
+            };
+            if let ChatrMessage::SentMessage { parent, .. } = &mut msg
+                && parent.is_none()
+            {
+                *parent = self.message_board.selected.take();
+            }
+            if let ChatrMessage::SetStatus { status } = &msg {
+                self.idle.set_status(status.clone());
+            }
+            client.send(msg).await
         }
     }
 
     async fn handle_events(
         &mut self,
         event_stream: &mut EventStream,
-        new_messages: &mut Receiver<ChatrMessage>,
-        send_message: &mut Sender<ChatrMessage>,
+        client: &mut ChatrClient,
     ) -> io::Result<()> {
+        let idle_deadline = self.idle.deadline();
+        let typing_expiry = self.typing_users.next_expiry().map(Instant::from_std);
         tokio::select! {
             event = event_stream.next() => match event {
             // it's important to check that the event is a key press event as
             // crossterm also emits key release and repeat events on Windows.
             Some(Ok(Event::Key(key_event))) if key_event.kind == KeyEventKind::Press => {
-                if key_event.modifiers != KeyModifiers::CONTROL {
-                        if key_event.code != KeyCode::Enter {
-                            self.buffer.handle_key_event(key_event);
-                        } else if !self.buffer.is_empty() {
-                        self.send_message(send_message).await?;
+                if let Some(back) = self.idle.input() {
+                    client.send(back).await?;
+                }
+                if key_event.modifiers == KeyModifiers::CONTROL && key_event.code == KeyCode::Char('q') {
+                    self.exit()
+                } else if let Some(search) = &mut self.search {
+                    match search.handle_key_event(key_event) {
+                        SearchAction::Nothing => (),
+                        SearchAction::Send(msg) => client.send(msg).await?,
+                        SearchAction::Jump(hit) => {
+                            self.message_board.show_message(hit);
+                            self.search = None;
                         }
-                    } else if key_event.code == KeyCode::Char('q') {
-                        self.exit()
+                        SearchAction::Close => self.search = None,
                     }
-
+                } else if let Some(keys) = &mut self.keys {
+                    match keys.handle_key_event(key_event) {
+                        KeysAction::Nothing => (),
+                        KeysAction::SetVerified(username, verified) => {
+                            if let Some(keyring) = &self.keyring {
+                                // Verifying sends what was held waiting on their key
+                                let saved = if verified {
+                                    client.verify(&username).await.map(|_| ())
+                                } else {
+                                    keyring.lock().unwrap().set_verified(&username, false).map(|_| ())
+                                };
+                                if let Err(e) = saved {
+                                    self.message_board.error(format!("couldn't save keys: {e}"));
+                                }
+                                keys.refresh(&keyring.lock().unwrap());
+                            }
+                        }
+                        KeysAction::Close => self.keys = None,
+                    }
+                } else if key_event.modifiers == KeyModifiers::CONTROL && key_event.code == KeyCode::Char('f') {
+                    self.search = Some(SearchOverlay::default());
+                } else if key_event.modifiers == KeyModifiers::ALT && key_event.code == KeyCode::Char('k') {
+                    if let Some(keyring) = &self.keyring {
+                        self.keys = Some(KeysOverlay::new(&keyring.lock().unwrap()));
+                    }
+                } else if key_event.modifiers == KeyModifiers::CONTROL && key_event.code == KeyCode::Char('o') {
+                    self.hide_roster = !self.hide_roster;
+                } else if key_event.modifiers == KeyModifiers::CONTROL && key_event.code == KeyCode::Char('t') {
+                    self.message_board.raw = !self.message_board.raw;
+                } else if key_event.modifiers == KeyModifiers::CONTROL && key_event.code == KeyCode::Char('n') {
+                    self.message_board.mentions_only = !self.message_board.mentions_only;
+                } else if key_event.modifiers == KeyModifiers::ALT && key_event.code == KeyCode::Up {
+                    self.message_board.select_older();
+                } else if key_event.modifiers == KeyModifiers::ALT && key_event.code == KeyCode::Down {
+                    self.message_board.select_newer();
+                } else if key_event.modifiers == KeyModifiers::ALT && key_event.code == KeyCode::Char('t') {
+                    self.message_board.toggle_thread();
+                } else if key_event.modifiers == KeyModifiers::ALT
+                    && let KeyCode::Char(c @ '1'..='9') = key_event.code
+                {
+                    let quick = c as usize - '1' as usize;
+                    if let Some(msg) = self.message_board.toggle_reaction(quick) {
+                        client.send(msg).await?;
+                    }
+                } else if key_event.modifiers == KeyModifiers::ALT && key_event.code == KeyCode::Char('d') {
+                    if let Some(id) = self.message_board.selected {
+                        match self.transfers.download(id).await {
+                            Ok(msg) => client.send(msg).await?,
+                            Err(e) => self.message_board.error(e.to_string()),
+                        }
+                    }
+                } else if key_event.code == KeyCode::Esc && !self.composer.is_searching() {
+                    self.message_board.selected = None;
+                } else if key_event.code == KeyCode::Tab && !self.composer.is_searching() {
+                    self.composer.complete_username(self.roster.usernames());
+                } else if key_event.code != KeyCode::Enter || self.composer.is_searching() {
+                    if self.composer.handle_key_event(key_event)
+                        && self.typing_debounce.should_send(std::time::Instant::now())
+                    {
+                        client.send(ChatrMessage::Typing).await?;
+                    }
+                } else if !self.composer.is_empty() {
+                    self.send_message(client).await?;
+                }
             }
+            Some(Err(e)) => return Err(e),
+            // No more input, nothing left to do
+            None => self.exit(),
             _ => {}
             },
-            new_msg = new_messages.recv() => {
-                match new_msg {
-                    Some(ChatrMessage::ReceivedMessage { username, content }) => self.message_board.post_message(username, content),
-                    Some(ChatrMessage::UserConnected{username}) => self.message_board.user_connected(username),
-                    Some(ChatrMessage::UserDisconnected{username}) => self.message_board.user_disconnected(username),
-                    None => todo!(),
-                    x => todo!("{x:?}"),
-                }
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                client.send(self.idle.went_idle()).await?;
+            }
+            _ = tokio::time::sleep_until(typing_expiry.unwrap_or_else(Instant::now)), if typing_expiry.is_some() => {
+                self.typing_users.expire(std::time::Instant::now());
             }
+            event = client.next_event() => match event {
+                Some(ClientEvent::Message(message)) => {
+                    self.typing_users.stopped(&message.username);
+                    self.message_board.post_message(message);
+                    if let Some(post) = self.message_board.messages.last()
+                        && post.mentions(&self.message_board.username)
+                        && let BoardPost::Message(message) = post
+                    {
+                        self.notify.mentioned(&message.username, &message.content)?;
+                    }
+                }
+                // Backlog from before we joined, nothing in it is new so no notifying
+                Some(ClientEvent::History(messages)) => {
+                    messages.into_iter().for_each(|message| self.message_board.post_message(message));
+                }
+                Some(ClientEvent::Error(reason)) => self.message_board.error(reason),
+                Some(ClientEvent::UserConnected(username)) => self.roster.user_connected(username),
+                Some(ClientEvent::UserDisconnected(username)) => {
+                    self.typing_users.stopped(&username);
+                    self.roster.user_disconnected(&username);
+                }
+                Some(ClientEvent::Roster(members)) => self.roster.set(members),
+                Some(ClientEvent::StatusChanged(username, status)) => {
+                    self.roster.status_changed(username.clone(), status.clone());
+                    self.message_board.status_changed(username, status);
+                }
+                Some(ClientEvent::Motd(text)) => self.message_board.info(text),
+                Some(ClientEvent::Joined(room)) => self.message_board.joined(room),
+                Some(ClientEvent::Direct { from, to, content, verified, .. }) => {
+                    if from != self.message_board.username {
+                        self.notify.mentioned(&from, &content)?;
+                    }
+                    self.message_board.messages.push(BoardPost::Direct { from, to, content, verified });
+                }
+                Some(ClientEvent::Other(msg)) => self.handle_message(msg, client).await?,
+                // The server is gone, nothing more to show
+                Some(ClientEvent::Disconnected) | None => self.exit(),
+            },
+        }
+        Ok(())
+    }
 
+    /// Messages without an event of their own
+    async fn handle_message(&mut self, msg: ChatrMessage, client: &ChatrClient) -> io::Result<()> {
+        match self.transfers.handle(&msg).await {
+            Ok(Some(TransferEvent::Upload(mut upload))) => loop {
+                match upload.next_chunk().await {
+                    Ok(Some(msg)) => client.send(msg).await?,
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.message_board.error(e.to_string());
+                        break;
+                    }
+                }
+            },
+            Ok(Some(TransferEvent::Downloaded(path))) => {
+                self.message_board.info(format!("saved {}", path.display()));
+            }
+            Ok(None) => (),
+            Err(e) => self.message_board.error(e.to_string()),
+        }
+        match msg {
+            ChatrMessage::SearchResults { query, hits, more } => {
+                if let Some(search) = &mut self.search {
+                    search.results(query, hits, more);
+                }
+            }
+            ChatrMessage::ReactionAdded {
+                id,
+                emoji,
+                username,
+            } => {
+                self.message_board.reaction_added(id, &emoji, &username);
+            }
+            ChatrMessage::ReactionRemoved {
+                id,
+                emoji,
+                username,
+            } => {
+                self.message_board.reaction_removed(id, &emoji, &username);
+            }
+            ChatrMessage::UserTyping { username } => {
+                self.typing_users
+                    .typing(username, std::time::Instant::now());
+            }
+            ChatrMessage::ExportMessages { messages, done } => {
+                if let Some(export) = &mut self.export {
+                    match export.receive(&messages, done).await {
+                        Ok(true) => {
+                            let info = format!(
+                                "saved {} messages to {}",
+                                export.len(),
+                                export.path.display()
+                            );
+                            self.message_board.info(info);
+                            self.export = None;
+                        }
+                        Ok(false) => (),
+                        Err(e) => {
+                            let reason = format!("couldn't write {}: {e}", export.path.display());
+                            self.message_board.error(reason);
+                            self.export = None;
+                        }
+                    }
+                }
+            }
+            ChatrMessage::JoinRejected { room, reason } => {
+                self.message_board
+                    .error(format!("can't join #{room}: {reason}"));
+            }
+            ChatrMessage::Invited { room, by } => {
+                self.message_board
+                    .info(format!("{by} invited you to #{room}, /join {room}"));
+            }
+            ChatrMessage::TopicChanged { room, topic } => {
+                self.message_board.topic_changed(&room, topic);
+            }
+            ChatrMessage::RoomList { rooms } => {
+                let rooms = rooms
+                    .iter()
+                    .map(|room| match room.access {
+                        Access::Public => format!("#{} ({})", room.name, room.members.len()),
+                        access => format!("#{} ({}, {access})", room.name, room.members.len()),
+                    })
+                    .collect::<Vec<String>>();
+                self.message_board
+                    .info(format!("rooms: {}", rooms.join(", ")));
+            }
+            // The connection already took it, the keys overlay may be showing it
+            ChatrMessage::PeerKey { .. } => {
+                if let (Some(keys), Some(keyring)) = (&mut self.keys, &self.keyring) {
+                    keys.refresh(&keyring.lock().unwrap());
+                }
+            }
+            // Dealt with by transfers above
+            ChatrMessage::UploadReady { .. } | ChatrMessage::DownloadChunk { .. } => (),
+            // Nothing to show for the rest, like replies meant for other clients
+            _ => (),
         }
         Ok(())
     }
@@ -342,10 +902,63 @@ impl App {
 
 impl Widget for &App {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let row_constraints = vec![Constraint::Fill(1), Constraint::Length(2)];
+        let row_constraints = vec![
+            Constraint::Fill(1),
+            Constraint::Length(1),
+            Constraint::Length(2),
+        ];
         let horizontal = Layout::vertical(row_constraints).spacing(Spacing::Space(0));
         let rows = horizontal.split(area);
-        self.message_board.render(rows[0], buf);
-        self.buffer.render(rows[1], buf);
+        if self.hide_roster {
+            self.message_board.render(rows[0], buf);
+        } else {
+            let columns =
+                Layout::horizontal([Constraint::Fill(1), Constraint::Length(20)]).split(rows[0]);
+            self.message_board.render(columns[0], buf);
+            self.roster.render(columns[1], buf);
+        }
+        if let Some(parent) = self.message_board.selected {
+            let to = match self.message_board.get(parent) {
+                Some(message) => message.username.as_str(),
+                None => "message",
+            };
+            Line::from(format!("replying to {to} (Esc to cancel)"))
+                .italic()
+                .cyan()
+                .render(rows[1], buf);
+        } else if let Some(typing) = self.typing_users.describe() {
+            Line::from(typing).italic().dark_gray().render(rows[1], buf);
+        }
+        self.composer.render(rows[2], buf);
+        if let Some(search) = &self.search {
+            let [_, middle, _] = Layout::vertical([
+                Constraint::Percentage(15),
+                Constraint::Percentage(70),
+                Constraint::Percentage(15),
+            ])
+            .areas(rows[0]);
+            let [_, overlay, _] = Layout::horizontal([
+                Constraint::Percentage(10),
+                Constraint::Percentage(80),
+                Constraint::Percentage(10),
+            ])
+            .areas(middle);
+            search.render(overlay, buf);
+        }
+        if let Some(keys) = &self.keys {
+            let [_, middle, _] = Layout::vertical([
+                Constraint::Percentage(20),
+                Constraint::Percentage(60),
+                Constraint::Percentage(20),
+            ])
+            .areas(rows[0]);
+            let [_, overlay, _] = Layout::horizontal([
+                Constraint::Percentage(10),
+                Constraint::Percentage(80),
+                Constraint::Percentage(10),
+            ])
+            .areas(middle);
+            keys.render(overlay, buf);
+        }
     }
 }
@@ -0,0 +1,378 @@
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    Status, Username,
+    audit::Audit,
+    chatroom::{AdminMsg, BannedUsernames},
+    metrics::{Metrics, MetricsSummary},
+    room::RoomName,
+};
+
+/// Where the server listens for admin requests unless told otherwise
+pub const DEFAULT_ADMIN_SOCKET: &str = "chatr_admin.sock";
+
+/// Request to a running server, one JSON object per line on the admin socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// Who is connected
+    ListUsers,
+    /// Disconnect a user, they can log straight back in
+    Kick {
+        username: Username,
+        reason: Option<String>,
+    },
+    /// Disconnect a user and keep them from logging in again
+    Ban {
+        username: Username,
+        reason: Option<String>,
+    },
+    /// Let a banned user back in
+    Unban { username: Username },
+    /// Notice to every room with someone in it
+    Broadcast { text: String },
+    /// How the server is doing
+    Stats,
+    /// Load on the server, the same numbers the metrics endpoint serves
+    Metrics,
+    /// Disconnect everyone and stop the server
+    Shutdown,
+}
+
+/// Answer to an [`AdminRequest`], one JSON object per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Users {
+        users: Vec<UserInfo>,
+    },
+    Stats {
+        stats: ServerStats,
+    },
+    Metrics {
+        metrics: MetricsSummary,
+    },
+    /// The request was carried out
+    Done {
+        message: String,
+    },
+    Error {
+        reason: String,
+    },
+}
+
+/// Connected user as the admin socket lists them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub username: Username,
+    pub room: RoomName,
+    pub status: Status,
+}
+
+/// Snapshot of the chatroom for the admin socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub uptime_secs: u64,
+    pub clients: usize,
+    pub rooms: usize,
+    /// Messages in the history
+    pub messages: usize,
+}
+
+/// Send one request to the server listening on `path` and wait for its answer
+pub async fn request(path: &Path, request: &AdminRequest) -> io::Result<AdminResponse> {
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    let mut lines = BufReader::new(reader).lines();
+    match lines.next_line().await? {
+        Some(line) => Ok(serde_json::from_str(&line)?),
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "server closed without answering",
+        )),
+    }
+}
+
+/// Listen on `path` without anyone else ever being able to connect: the socket is made in a
+/// directory only we can get into, locked down and only then moved into place
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let dir = path.with_extension("bind");
+    // Left behind by an earlier run that stopped half way
+    match std::fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        tracing::warn!("couldn't remove {dir:?} {e}");
+    }
+    bound
+}
+
+/// Local socket for controlling a running server, only its owner can connect
+pub struct AdminSocket {
+    path: PathBuf,
+    admin_send: mpsc::Sender<AdminMsg>,
+    banned_usernames: BannedUsernames,
+    /// Bans get written back here when the server was started with a ban file
+    ban_file: Option<PathBuf>,
+    /// Cancelled on [`AdminRequest::Shutdown`] once everyone has been disconnected
+    shutdown: CancellationToken,
+    metrics: Arc<Metrics>,
+    audit_log: Audit,
+}
+
+impl AdminSocket {
+    pub fn new(
+        path: PathBuf,
+        admin_send: mpsc::Sender<AdminMsg>,
+        banned_usernames: BannedUsernames,
+        shutdown: CancellationToken,
+    ) -> Self {
+        Self {
+            path,
+            admin_send,
+            banned_usernames,
+            ban_file: None,
+            shutdown,
+            metrics: Arc::default(),
+            audit_log: Audit::default(),
+        }
+    }
+    /// Record kicks, bans and the like in `audit`
+    pub fn with_audit(mut self, audit: Audit) -> Self {
+        self.audit_log = audit;
+        self
+    }
+    /// Answer [`AdminRequest::Metrics`] from `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    /// Keep bans made through the socket in `path`, comma separated like `banned_usernames`
+    pub fn with_ban_file(mut self, path: Option<PathBuf>) -> Self {
+        self.ban_file = path;
+        self
+    }
+    /// Start listening, replacing a socket file left behind by an earlier run, until `ct` is
+    /// cancelled
+    pub fn run(self, ct: CancellationToken) -> io::Result<JoinHandle<()>> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        let listener = bind_private(&self.path)?;
+        let socket = Arc::new(self);
+        Ok(tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = ct.cancelled() => break,
+                };
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::error!("admin socket accept failed {e}");
+                        break;
+                    }
+                };
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = socket.serve(stream).await {
+                        tracing::warn!("admin connection failed {e}");
+                    }
+                });
+            }
+        }))
+    }
+    async fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str::<AdminRequest>(&line) {
+                Ok(request) => {
+                    let response = self.handle(request.clone()).await;
+                    self.audit(request, &response);
+                    response
+                }
+                Err(e) => AdminResponse::Error {
+                    reason: format!("bad request: {e}"),
+                },
+            };
+            let mut line = serde_json::to_string(&response)?;
+            line.push('\n');
+            writer.write_all(line.as_bytes()).await?;
+        }
+        Ok(())
+    }
+    async fn handle(&self, request: AdminRequest) -> AdminResponse {
+        tracing::info!(?request, "admin request");
+        match request {
+            AdminRequest::ListUsers => {
+                let (reply, users) = oneshot::channel();
+                match self.ask(AdminMsg::ListUsers(reply), users).await {
+                    Ok(users) => AdminResponse::Users { users },
+                    Err(response) => response,
+                }
+            }
+            AdminRequest::Kick { username, reason } => {
+                let reason = reason.unwrap_or("kicked by an admin".to_string());
+                self.kick(username, reason).await
+            }
+            AdminRequest::Ban { username, reason } => {
+                self.banned_usernames
+                    .write()
+                    .unwrap()
+                    .insert(username.clone());
+                if let Err(e) = self.save_bans().await {
+                    return AdminResponse::Error {
+                        reason: format!("banned {username} but couldn't save bans: {e}"),
+                    };
+                }
+                let reason = reason.unwrap_or("banned by an admin".to_string());
+                match self.kick(username.clone(), reason).await {
+                    AdminResponse::Done { .. } => AdminResponse::Done {
+                        message: format!("banned and kicked {username}"),
+                    },
+                    AdminResponse::Error { .. } => AdminResponse::Done {
+                        message: format!("banned {username}"),
+                    },
+                    response => response,
+                }
+            }
+            AdminRequest::Unban { username } => {
+                if !self.banned_usernames.write().unwrap().remove(&username) {
+                    return AdminResponse::Error {
+                        reason: format!("{username} isn't banned"),
+                    };
+                }
+                match self.save_bans().await {
+                    Ok(()) => AdminResponse::Done {
+                        message: format!("unbanned {username}"),
+                    },
+                    Err(e) => AdminResponse::Error {
+                        reason: format!("unbanned {username} but couldn't save bans: {e}"),
+                    },
+                }
+            }
+            AdminRequest::Broadcast { text } => {
+                if self
+                    .admin_send
+                    .send(AdminMsg::Broadcast(text))
+                    .await
+                    .is_err()
+                {
+                    return stopped();
+                }
+                AdminResponse::Done {
+                    message: "sent".to_string(),
+                }
+            }
+            AdminRequest::Stats => {
+                let (reply, stats) = oneshot::channel();
+                match self.ask(AdminMsg::Stats(reply), stats).await {
+                    Ok(stats) => AdminResponse::Stats { stats },
+                    Err(response) => response,
+                }
+            }
+            AdminRequest::Metrics => AdminResponse::Metrics {
+                metrics: self.metrics.summary(),
+            },
+            AdminRequest::Shutdown => {
+                let (reply, done) = oneshot::channel();
+                let response = match self.ask(AdminMsg::Shutdown(reply), done).await {
+                    Ok(()) => AdminResponse::Done {
+                        message: "shutting down".to_string(),
+                    },
+                    Err(response) => response,
+                };
+                self.shutdown.cancel();
+                response
+            }
+        }
+    }
+    /// Record requests that act on users or the server in the audit log
+    fn audit(&self, request: AdminRequest, response: &AdminResponse) {
+        let (action, target, detail) = match request {
+            AdminRequest::Kick { username, reason } => ("kick", Some(username), reason),
+            AdminRequest::Ban { username, reason } => ("ban", Some(username), reason),
+            AdminRequest::Unban { username } => ("unban", Some(username), None),
+            AdminRequest::Broadcast { text } => ("broadcast", None, Some(text)),
+            AdminRequest::Shutdown => ("shutdown", None, None),
+            AdminRequest::ListUsers | AdminRequest::Stats | AdminRequest::Metrics => return,
+        };
+        let error = match response {
+            AdminResponse::Error { reason } => Some(reason.clone()),
+            _ => None,
+        };
+        self.audit_log
+            .admin_action(action, target.as_deref(), detail, error);
+    }
+    async fn kick(&self, username: Username, reason: String) -> AdminResponse {
+        let (reply, kicked) = oneshot::channel();
+        match self
+            .ask(AdminMsg::Kick(username.clone(), reason, reply), kicked)
+            .await
+        {
+            Ok(true) => AdminResponse::Done {
+                message: format!("kicked {username}"),
+            },
+            Ok(false) => AdminResponse::Error {
+                reason: format!("{username} isn't connected"),
+            },
+            Err(response) => response,
+        }
+    }
+    /// Send `msg` to the chatroom and wait for what it hands back on `answer`
+    async fn ask<T>(
+        &self,
+        msg: AdminMsg,
+        answer: oneshot::Receiver<T>,
+    ) -> Result<T, AdminResponse> {
+        if self.admin_send.send(msg).await.is_err() {
+            return Err(stopped());
+        }
+        answer.await.map_err(|_| stopped())
+    }
+    async fn save_bans(&self) -> io::Result<()> {
+        let Some(path) = &self.ban_file else {
+            return Ok(());
+        };
+        let mut banned: Vec<Username> = self
+            .banned_usernames
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        banned.sort();
+        tokio::fs::write(path, banned.join(",") + "\n").await
+    }
+}
+
+fn stopped() -> AdminResponse {
+    AdminResponse::Error {
+        reason: "the chatroom has stopped".to_string(),
+    }
+}
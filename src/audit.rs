@@ -0,0 +1,237 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{Timestamp, Username, time};
+
+/// Where the server keeps its audit log unless told otherwise
+pub const DEFAULT_AUDIT_LOG: &str = "chatr_audit.jsonl";
+/// Size the audit log gets to before it's rotated, unless told otherwise
+pub const DEFAULT_AUDIT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+/// How many rotated audit logs are kept, unless told otherwise
+pub const DEFAULT_AUDIT_KEEP: usize = 5;
+/// Records waiting to be written before new ones get dropped
+pub const AUDIT_QUEUE_LEN: usize = 4096;
+
+/// One line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub at: Timestamp,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// Something worth keeping a record of, apart from the debug output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// Someone tried to log in
+    Connect {
+        peer: Option<SocketAddr>,
+        /// Name they asked for, if they got as far as asking
+        username: Option<Username>,
+        accepted: bool,
+        /// Why they were turned away
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A logged in connection ended
+    Disconnect {
+        peer: Option<SocketAddr>,
+        username: Username,
+        connected_secs: u64,
+        /// How it ended, like `logged out`, `closed` or `dropped by the server` when kicked
+        reason: String,
+    },
+    /// Someone used their power over others, from the admin socket or in a room
+    Moderation {
+        /// `admin` for the admin socket, otherwise the user
+        actor: String,
+        action: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<Username>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+        /// Why it wasn't carried out, if it wasn't
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// What the writer task gets handed
+#[derive(Debug)]
+enum Queued {
+    Record(AuditRecord),
+    /// Answered once everything queued before it is written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle for recording audit events, cheap to clone and a no-op when the log is off
+#[derive(Debug, Clone, Default)]
+pub struct Audit {
+    sender: Option<mpsc::Sender<Queued>>,
+}
+
+impl Audit {
+    /// Record to `path`, rotating to `path.1`, `path.2` … once it reaches `max_bytes` and
+    /// keeping `keep` of those, `max_bytes` of 0 never rotates
+    pub async fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let mut writer = AuditWriter::open(path, max_bytes, keep).await?;
+        let (sender, mut queued) = mpsc::channel::<Queued>(AUDIT_QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some(next) = queued.recv().await {
+                match next {
+                    Queued::Record(record) => {
+                        if let Err(e) = writer.write(&record).await {
+                            tracing::error!("couldn't write audit log {e}");
+                        }
+                    }
+                    Queued::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            sender: Some(sender),
+        })
+    }
+    pub fn record(&self, event: AuditEvent) {
+        if let Some(sender) = &self.sender {
+            let record = AuditRecord {
+                at: time::now(),
+                event,
+            };
+            match sender.try_send(Queued::Record(record)) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    tracing::error!("audit log is falling behind, dropped a record")
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    tracing::error!("audit log writer has stopped")
+                }
+            }
+        }
+    }
+    /// Wait until everything recorded so far is in the log file
+    pub async fn flush(&self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let (done, written) = oneshot::channel();
+        if sender.send(Queued::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+    /// Record something done from the admin socket
+    pub fn admin_action(
+        &self,
+        action: &str,
+        target: Option<&str>,
+        detail: Option<String>,
+        error: Option<String>,
+    ) {
+        self.record(AuditEvent::Moderation {
+            actor: "admin".to_string(),
+            action: action.to_string(),
+            target: target.map(str::to_string),
+            room: None,
+            detail,
+            error,
+        });
+    }
+    /// Record `actor` changing who can do what in `room`
+    pub fn room_action<T>(
+        &self,
+        actor: &str,
+        action: &str,
+        room: &str,
+        target: Option<&str>,
+        detail: Option<String>,
+        result: &Result<T, String>,
+    ) {
+        self.record(AuditEvent::Moderation {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.map(str::to_string),
+            room: Some(room.to_string()),
+            detail,
+            error: result.as_ref().err().cloned(),
+        });
+    }
+}
+
+/// Appends records to the log file, rotating it as it grows
+struct AuditWriter {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl AuditWriter {
+    async fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = append(&path).await?;
+        let len = file.metadata().await?.len();
+        Ok(Self {
+            path,
+            file,
+            len,
+            max_bytes,
+            keep,
+        })
+    }
+    async fn write(&mut self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        if self.max_bytes > 0 && self.len > 0 && self.len + line.len() as u64 > self.max_bytes {
+            self.rotate().await?;
+        }
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.flush().await?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+    /// Shift `path.n` to `path.n+1`, dropping the oldest, and start a new file
+    async fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rotated(&self.path, n);
+                if tokio::fs::try_exists(&from).await? {
+                    tokio::fs::rename(&from, rotated(&self.path, n + 1)).await?;
+                }
+            }
+            tokio::fs::rename(&self.path, rotated(&self.path, 1)).await?;
+        }
+        self.file = append(&self.path).await?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+async fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// `chatr_audit.jsonl.2` for `n` of 2
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
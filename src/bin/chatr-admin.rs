@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use chatr::admin::{self, AdminRequest, AdminResponse, DEFAULT_ADMIN_SOCKET};
+use clap::Parser;
+
+#[derive(clap::Parser, Debug, Clone)]
+struct AdminArgs {
+    /// Admin socket of the server to control
+    #[arg(short, long, default_value = DEFAULT_ADMIN_SOCKET)]
+    socket: PathBuf,
+    /// Print the server's answer as JSON
+    #[arg(long)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// List connected users
+    Users,
+    /// Disconnect a user
+    Kick {
+        username: String,
+        /// Told to the user as they're disconnected
+        reason: Option<String>,
+    },
+    /// Disconnect a user and keep them out
+    Ban {
+        username: String,
+        /// Told to the user as they're disconnected
+        reason: Option<String>,
+    },
+    /// Let a banned user back in
+    Unban { username: String },
+    /// Send a notice to every room
+    Broadcast {
+        #[arg(required = true, num_args = 1..)]
+        text: Vec<String>,
+    },
+    /// Show how the server is doing
+    Stats,
+    /// Show load on the server
+    Metrics,
+    /// Disconnect everyone and stop the server
+    Shutdown,
+}
+
+/// Admin binary, sends one request to a running server
+#[tokio::main]
+async fn main() -> ExitCode {
+    let AdminArgs {
+        socket,
+        json,
+        command,
+    } = AdminArgs::parse();
+    let request = match command {
+        Command::Users => AdminRequest::ListUsers,
+        Command::Kick { username, reason } => AdminRequest::Kick { username, reason },
+        Command::Ban { username, reason } => AdminRequest::Ban { username, reason },
+        Command::Unban { username } => AdminRequest::Unban { username },
+        Command::Broadcast { text } => AdminRequest::Broadcast {
+            text: text.join(" "),
+        },
+        Command::Stats => AdminRequest::Stats,
+        Command::Metrics => AdminRequest::Metrics,
+        Command::Shutdown => AdminRequest::Shutdown,
+    };
+    let response = match admin::request(&socket, &request).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("couldn't reach the server at {}: {e}", socket.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&response).unwrap());
+    }
+    match response {
+        AdminResponse::Error { reason } => {
+            eprintln!("{reason}");
+            return ExitCode::FAILURE;
+        }
+        _ if json => (),
+        AdminResponse::Users { users } if users.is_empty() => println!("no one is connected"),
+        AdminResponse::Users { users } => {
+            for user in users {
+                println!("{}\t#{}\t{}", user.username, user.room, user.status);
+            }
+        }
+        AdminResponse::Stats { stats } => {
+            println!("uptime    {}s", stats.uptime_secs);
+            println!("clients   {}", stats.clients);
+            println!("rooms     {}", stats.rooms);
+            println!("messages  {}", stats.messages);
+        }
+        AdminResponse::Metrics { metrics } => {
+            println!("uptime       {}s", metrics.uptime_secs);
+            println!("clients      {}", metrics.connected_clients);
+            println!(
+                "messages in  {} ({:.1}/s, {} bytes)",
+                metrics.messages_in, metrics.messages_in_per_sec, metrics.bytes_in
+            );
+            println!(
+                "messages out {} ({:.1}/s, {} bytes)",
+                metrics.messages_out, metrics.messages_out_per_sec, metrics.bytes_out
+            );
+            println!("dropped      {}", metrics.dropped_messages);
+            for (reason, count) in metrics.login_rejections {
+                println!("rejected     {count} {reason}");
+            }
+            for (username, depth) in metrics.queue_depths {
+                println!("queued       {depth} for {username}");
+            }
+        }
+        AdminResponse::Done { message } => println!("{message}"),
+    }
+    ExitCode::SUCCESS
+}
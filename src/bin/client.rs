@@ -1,117 +1,538 @@
-use std::sync::Arc;
-
-use chatr::ChatrMessage;
-use chatr::client::ClientConnection;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio_util::sync::CancellationToken;
+use std::collections::BTreeSet;
+use std::io::IsTerminal;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chatr::client::{ChatrClient, ClientEvent, Credentials};
+use chatr::command::{FileCommand, parse_file_command, parse_input};
+use chatr::e2e::{self, Keyring};
+use chatr::export::PendingExport;
+use chatr::files::{TransferEvent, Transfers};
+use chatr::history::InputHistory;
+use chatr::room::Access;
+use chatr::search::SearchQuery;
+use chatr::time;
+use chatr::{ChatMessage, ChatrMessage, MessageKind};
+use clap::Parser;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Config, Context, Editor, ExternalPrinter, Helper};
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc::{self, Receiver};
+
+#[derive(clap::Parser, Debug, Clone)]
+struct ClientArgs {
+    /// Server to connect to
+    #[arg(default_value = "localhost:1999")]
+    host: String,
+    /// Name to log in with, asked for when not given
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Send each line read from stdin and print received messages as plain lines, the default
+    /// when stdin isn't a terminal
+    #[arg(long)]
+    batch: bool,
+}
+
+/// Usernames seen in the chatroom, shared between the server listener and the completer
+type KnownUsers = Arc<Mutex<BTreeSet<String>>>;
+/// Keys shared with the connection, which seals and opens direct messages with them
+type SharedKeyring = Arc<Mutex<Keyring>>;
+
+/// Line client binary
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     tracing_subscriber::fmt::init();
-    let stdin = tokio::io::stdin();
-    let mut reader = tokio::io::BufReader::new(stdin);
-    let mut stdout = tokio::io::stdout();
-    let ct = CancellationToken::new();
-    let mut line = String::new();
-    stdout.write_all(b"username?").await.unwrap();
-    stdout.flush().await.unwrap();
-    let username = match reader.read_line(&mut line).await {
-        Ok(0) => {
-            eprintln!("can't do no username");
-            return;
-        }
-        Ok(_) => {
-            let username = std::mem::take(&mut line);
-            if username.trim().is_empty() {
-                eprintln!("can't do no username");
-                return;
-            }
-            username.trim().to_string()
+    let ClientArgs {
+        host,
+        username,
+        batch,
+    } = ClientArgs::parse();
+    let batch = batch || !std::io::stdin().is_terminal();
+    let history = match InputHistory::from_env() {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("can't read the input history: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut editor = match Editor::with_history(Config::default(), history) {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("can't set up the prompt: {e}");
+            return ExitCode::FAILURE;
         }
-        Err(_) => todo!(),
     };
 
-    let mut client_conn = ClientConnection::new("localhost:1999").await.unwrap();
-    client_conn.login(username).await.unwrap();
-    let (s1, r1) = mpsc::channel(1024);
-    let (s2, r2) = mpsc::channel(1024);
+    let username = match username {
+        Some(username) => username,
+        None if batch => {
+            eprintln!("--username is needed when not running interactively");
+            return ExitCode::FAILURE;
+        }
+        None => match editor.readline("username? ") {
+            Ok(username) if !username.trim().is_empty() => username.trim().to_string(),
+            _ => {
+                eprintln!("can't do no username");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
 
-    spawn_rest(stdout, reader, line, s1, r2, ct.clone());
-    client_conn.run(s2, r1, ct.clone());
+    let keyring = match Keyring::from_env(username.clone()) {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            eprintln!("can't load the keys for {username}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let credentials = Credentials::new(username).with_keyring(keyring);
+    let client = match ChatrClient::connect(&host, credentials).await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("can't log in to {host}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-    tokio::signal::ctrl_c().await.unwrap();
-    ct.cancel();
+    if batch {
+        run_batch(client).await;
+    } else {
+        run_interactive(editor, client).await;
+    }
+    ExitCode::SUCCESS
 }
 
-fn spawn_rest(
-    stdout: tokio::io::Stdout,
-    mut stdin: tokio::io::BufReader<tokio::io::Stdin>,
-    mut read_buf: String,
-    s1: Sender<ChatrMessage>,
-    mut r2: Receiver<ChatrMessage>,
-    ct: CancellationToken,
+/// Prompt with rustyline, received messages get printed above the prompt without clobbering
+/// the half typed line
+async fn run_interactive(
+    mut editor: Editor<UsernameCompleter, InputHistory>,
+    mut client: ChatrClient,
 ) {
-    let arc_stdout = Arc::new(Mutex::new(stdout));
-    let astd = arc_stdout.clone();
-    tokio::spawn(async move {
+    let known_users = KnownUsers::default();
+    editor.set_helper(Some(UsernameCompleter {
+        known_users: known_users.clone(),
+    }));
+    let mut printer = editor.create_external_printer().unwrap();
+    let (lines_send, lines) = mpsc::channel(1024);
+    let session = tokio::spawn(async move {
+        run_session(lines, false, &mut client, known_users, |line| {
+            printer.print(line).unwrap()
+        })
+        .await;
+        client.disconnect().await;
+        printer
+    });
+    let reading = tokio::task::spawn_blocking(move || {
         loop {
-            {
-                let mut lockout = astd.lock().await;
-                lockout.write_all(b">> ").await.unwrap();
-                lockout.flush().await.unwrap();
+            let line = editor.readline(">> ");
+            // The session's over, whatever was typed has nowhere to go
+            if lines_send.is_closed() {
+                break;
             }
-            tokio::select! {
-                _= ct.cancelled() => {break}
-            res = stdin.read_line(&mut read_buf) => match res {
-                Ok(0) => (),
-                Ok(_) => {
-
-                    let content = std::mem::take(&mut read_buf);
-
-                        if !content.trim().is_empty() {
-                    s1.send(ChatrMessage::SentMessage { content: content.trim().to_string() })
-                        .await
-                        .unwrap();
+            match line {
+                Ok(line) => {
+                    let content = line.trim();
+                    if content.is_empty() {
+                        continue;
+                    }
+                    if let Err(e) = editor.add_history_entry(content) {
+                        tracing::warn!("couldn't save history {e}");
+                    }
+                    if lines_send.blocking_send(content.to_string()).is_err() {
+                        break;
+                    }
+                }
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+                Err(e) => {
+                    tracing::error!("readline failed {e}");
+                    break;
+                }
+            }
+        }
+    });
+    let mut printer = session.await.unwrap();
+    // Typing stopped or the server went away. readline can't be interrupted, so in the second
+    // case it's still waiting on a line
+    if tokio::time::timeout(Duration::from_millis(200), reading)
+        .await
+        .is_err()
+    {
+        let _ = printer.print("! disconnected, press Enter to quit".to_string());
+    }
+}
 
+/// Send every line from stdin, print everything received until the server goes away or ctrl-c,
+/// even once stdin has run out
+async fn run_batch(mut client: ChatrClient) {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let (lines_send, lines_recv) = mpsc::channel(1024);
+    let ct_one = client.cancellation_token();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = ct_one.cancelled() => break,
+                line = lines.next_line() => match line {
+                    Ok(Some(line)) => {
+                        let content = line.trim();
+                        if !content.is_empty() && lines_send.send(content.to_string()).await.is_err() {
+                            tracing::warn!("the session is over, not sending {content}");
+                            break;
                         }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::error!("reading stdin failed {e}");
+                        break;
+                    }
                 }
-                Err(_) => todo!(),
-            }
             }
         }
     });
-    tokio::spawn(async move {
-        while let Some(msg) = r2.recv().await {
-            tracing::trace!("got msg from server {msg:?}");
-            match msg {
-                ChatrMessage::ReceivedMessage { username, content } => {
-                    let mut lockout = arc_stdout.lock().await;
-                    lockout
-                        .write_all(format!("{username}:{content}\n").as_bytes())
-                        .await
-                        .unwrap();
-                    lockout.flush().await.unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = run_session(lines_recv, true, &mut client, KnownUsers::default(), |line| println!("{line}")) => (),
+    }
+    client.disconnect().await;
+}
+
+/// Carry out typed lines and `print` what comes back until the server goes away, or the input
+/// does unless `after_input`
+async fn run_session(
+    mut lines: Receiver<String>,
+    after_input: bool,
+    client: &mut ChatrClient,
+    known_users: KnownUsers,
+    mut print: impl FnMut(String),
+) {
+    let keyring = client.keyring();
+    let mut transfers = Transfers::from_env();
+    // Search with more results to page through with /more
+    let mut last_search: Option<SearchQuery> = None;
+    let mut pending_export: Option<PendingExport> = None;
+    let mut input_done = false;
+    'session: loop {
+        tokio::select! {
+            line = lines.recv(), if !input_done => match line {
+                Some(line) if line == "/more" => match last_search.take() {
+                    Some(query) => {
+                        if let Err(e) = client.send(ChatrMessage::Search { query: query.next_page() }).await {
+                            print(format!("! {e}"));
+                            break;
+                        }
+                    }
+                    None => print("! no more results".to_string()),
+                },
+                Some(line) if line == "/keys" => match &keyring {
+                    Some(keyring) => print(list_keys(keyring)),
+                    None => print("! no keys".to_string()),
+                },
+                Some(line) if line.starts_with("/verify ") => {
+                    let username = line["/verify ".len()..].trim().trim_start_matches('@');
+                    print(match client.verify(username).await {
+                        Ok(true) => format!("verified {username}"),
+                        Ok(false) => format!("! no key for {username} yet, /msg them first"),
+                        Err(e) => format!("! couldn't verify {username}: {e}"),
+                    });
+                }
+                Some(line) => {
+                    let msg = match parse_file_command(&line) {
+                        Some(FileCommand::Upload(path)) => transfers.upload(&path).await,
+                        Some(FileCommand::Download(id)) => transfers.download(id).await,
+                        Some(FileCommand::Export(..)) if pending_export.is_some() => {
+                            print("! already exporting".to_string());
+                            continue;
+                        }
+                        Some(FileCommand::Export(format, path, query)) => {
+                            pending_export = Some(PendingExport::new(format, path, &query));
+                            Ok(ChatrMessage::Export { query })
+                        }
+                        None => Ok(parse_input(&line)),
+                    };
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            print(format!("! {e}"));
+                            continue;
+                        }
+                    };
+                    if let Err(e) = client.send(msg).await {
+                        print(format!("! {e}"));
+                        break;
+                    }
+                }
+                None if after_input => input_done = true,
+                None => break,
+            },
+            event = client.next_event() => {
+                let Some(event) = event.filter(|event| !matches!(event, ClientEvent::Disconnected)) else {
+                    break;
+                };
+                tracing::trace!("got event from server {event:?}");
+                track_users(&known_users, &event);
+                if let ClientEvent::Other(msg) = &event {
+                    if let ChatrMessage::SearchResults { query, more, .. } = msg {
+                        last_search = more.then(|| query.clone());
+                    }
+                    match transfers.handle(msg).await {
+                        Ok(Some(TransferEvent::Upload(mut upload))) => loop {
+                            match upload.next_chunk().await {
+                                Ok(Some(msg)) => {
+                                    if let Err(e) = client.send(msg).await {
+                                        print(format!("! {e}"));
+                                        break 'session;
+                                    }
+                                }
+                                Ok(None) => break,
+                                Err(e) => {
+                                    print(format!("! {e}"));
+                                    break;
+                                }
+                            }
+                        },
+                        Ok(Some(TransferEvent::Downloaded(path))) => {
+                            print(format!("saved {}", path.display()))
+                        }
+                        Ok(None) => (),
+                        Err(e) => print(format!("! {e}")),
+                    }
                 }
-                ChatrMessage::UserDisconnected { username } => {
-                    let mut lockout = arc_stdout.lock().await;
-                    lockout
-                        .write_all(format!("{username} disconnected\n").as_bytes())
-                        .await
-                        .unwrap();
-                    lockout.flush().await.unwrap();
+                if let ClientEvent::Other(ChatrMessage::ExportMessages { messages, done }) = &event
+                    && let Some(export) = pending_export.as_mut()
+                {
+                    match export.receive(messages, *done).await {
+                        Ok(true) => {
+                            print(format!("saved {} messages to {}", export.len(), export.path.display()));
+                            pending_export = None;
+                        }
+                        Ok(false) => (),
+                        Err(e) => {
+                            print(format!("! couldn't write {}: {e}", export.path.display()));
+                            pending_export = None;
+                        }
+                    }
                 }
-                ChatrMessage::UserConnected { username } => {
-                    let mut lockout = arc_stdout.lock().await;
-                    lockout
-                        .write_all(format!("{username} connected\n").as_bytes())
-                        .await
-                        .unwrap();
-                    lockout.flush().await.unwrap();
+                if let Some(line) = format_event(&event) {
+                    print(line);
                 }
-                ChatrMessage::Disconnect => break,
-                _ => panic!(),
             }
         }
-    });
+    }
+}
+
+/// How something from the server gets printed, `None` for ones with nothing to show
+fn format_event(event: &ClientEvent) -> Option<String> {
+    match event {
+        ClientEvent::Message(message) => Some(format_chat_message(message)),
+        ClientEvent::History(messages) if !messages.is_empty() => Some(
+            messages
+                .iter()
+                .map(format_chat_message)
+                .collect::<Vec<String>>()
+                .join("\n"),
+        ),
+        ClientEvent::Error(reason) => Some(format!("! {reason}")),
+        ClientEvent::Roster(members) => {
+            let members = members
+                .iter()
+                .map(|(username, status)| format!("{username} ({status})"))
+                .collect::<Vec<String>>();
+            Some(format!("online: {}", members.join(", ")))
+        }
+        ClientEvent::StatusChanged(username, status) => Some(format!("{username} is {status}")),
+        ClientEvent::Motd(text) => Some(text.clone()),
+        ClientEvent::Direct {
+            from,
+            to,
+            content,
+            verified,
+            ..
+        } => Some(if *verified {
+            format!("[dm] {from} → {to}: {content}")
+        } else {
+            format!("[dm] {from} → {to} (unverified): {content}")
+        }),
+        ClientEvent::Joined(room) => Some(match &room.topic {
+            Some(topic) => format!("now in #{}: {}", room.name, topic.text),
+            None => format!("now in #{}", room.name),
+        }),
+        ClientEvent::Other(msg) => format_message(msg),
+        event => {
+            tracing::debug!("not printing {event:?}");
+            None
+        }
+    }
+}
+
+/// How the rest of what the server sends gets printed
+fn format_message(msg: &ChatrMessage) -> Option<String> {
+    match msg {
+        ChatrMessage::SearchResults { hits, more, .. } => {
+            let mut lines = vec![format!("search: {} hits", hits.len())];
+            lines.extend(hits.iter().map(|hit| {
+                format!(
+                    "{} {}",
+                    time::format_time(hit.sent_at),
+                    format_chat_message(hit)
+                )
+            }));
+            if *more {
+                lines.push("/more for the next page".to_string());
+            }
+            Some(lines.join("\n"))
+        }
+        ChatrMessage::ReactionAdded {
+            id,
+            emoji,
+            username,
+        } => Some(format!("{username} reacted {emoji} to [{id}]")),
+        ChatrMessage::ReactionRemoved {
+            id,
+            emoji,
+            username,
+        } => Some(format!("{username} took back {emoji} on [{id}]")),
+        ChatrMessage::JoinRejected { room, reason } => {
+            Some(format!("! can't join #{room}: {reason}"))
+        }
+        ChatrMessage::Invited { room, by } => Some(format!("{by} invited you to #{room}")),
+        ChatrMessage::RoomList { rooms } => {
+            let mut lines = vec!["rooms:".to_string()];
+            lines.extend(rooms.iter().map(|room| {
+                let line = match room.access {
+                    Access::Public => format!("#{} ({} in)", room.name, room.members.len()),
+                    access => format!("#{} ({} in, {access})", room.name, room.members.len()),
+                };
+                match &room.topic {
+                    Some(topic) => format!("{line} {}", topic.text),
+                    None => line,
+                }
+            }));
+            Some(lines.join("\n"))
+        }
+        msg => {
+            tracing::debug!("not printing {msg:?}");
+            None
+        }
+    }
+}
+
+/// `/keys` lists fingerprints to compare with people, changed ones wait for `/verify user`
+fn list_keys(keyring: &SharedKeyring) -> String {
+    let keyring = keyring.lock().unwrap();
+    let mut lines = vec![format!("your key: {}", e2e::fingerprint(&keyring.public()))];
+    lines.extend(
+        keyring
+            .known()
+            .map(|(username, known)| match &known.changed {
+                Some(changed) => format!(
+                    "! {username}: changed to {}, was {}",
+                    e2e::fingerprint(changed),
+                    e2e::fingerprint(&known.key)
+                ),
+                None => {
+                    let mark = if known.verified { "✓" } else { " " };
+                    format!("{mark} {username}: {}", e2e::fingerprint(&known.key))
+                }
+            }),
+    );
+    lines.join("\n")
+}
+
+/// Message with its id in front so it can be replied to, replies say what they answer
+fn format_chat_message(message: &ChatMessage) -> String {
+    let ChatMessage {
+        id,
+        username,
+        kind,
+        content,
+        parent,
+        reactions,
+        attachment,
+        ..
+    } = message;
+    let reply = match parent {
+        Some(parent) => format!("(re {parent}) "),
+        None => String::new(),
+    };
+    let mut line = match kind {
+        MessageKind::Normal => format!("[{id}] {reply}{username}: {content}"),
+        MessageKind::Action => format!("[{id}] {reply}* {username} {content}"),
+        MessageKind::Notice => format!("[{id}] {reply}-{username}- {content}"),
+        MessageKind::System => format!("*** {content}"),
+    };
+    if attachment.is_some() {
+        line.push_str(&format!(" (/download {id})"));
+    }
+    if reactions.is_empty() {
+        return line;
+    }
+    let reactions = reactions
+        .iter()
+        .map(|r| format!("{} {}", r.emoji, r.users.len()))
+        .collect::<Vec<String>>();
+    format!("{line} [{}]", reactions.join(" "))
+}
+
+fn track_users(known_users: &KnownUsers, event: &ClientEvent) {
+    let mut known_users = known_users.lock().unwrap();
+    match event {
+        ClientEvent::UserConnected(username) => {
+            known_users.insert(username.clone());
+        }
+        ClientEvent::UserDisconnected(username) => {
+            known_users.remove(username);
+        }
+        ClientEvent::Roster(members) => {
+            *known_users = members
+                .iter()
+                .map(|(username, _)| username.clone())
+                .collect();
+        }
+        _ => (),
+    }
+}
+
+/// Tab completes the word under the cursor against known usernames, keeping a leading `@`
+struct UsernameCompleter {
+    known_users: KnownUsers,
+}
+
+impl Completer for UsernameCompleter {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let (at, prefix) = match word.strip_prefix('@') {
+            Some(prefix) => ("@", prefix),
+            None => ("", word),
+        };
+        let candidates = self
+            .known_users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|user| user.starts_with(prefix))
+            .map(|user| Pair {
+                display: user.clone(),
+                replacement: format!("{at}{user}"),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+impl Hinter for UsernameCompleter {
+    type Hint = String;
 }
+impl Highlighter for UsernameCompleter {}
+impl Validator for UsernameCompleter {}
+impl Helper for UsernameCompleter {}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use chatr::bot::{Bot, Request};
+use clap::Parser;
+use rand_core::{OsRng, RngCore};
+use tokio_util::sync::CancellationToken;
+
+/// Most dice one roll can throw
+const MAX_DICE: u32 = 100;
+/// Most sides a die can have
+const MAX_SIDES: u32 = 1000;
+
+#[derive(clap::Parser, Debug, Clone)]
+struct DiceBotArgs {
+    /// Server to connect to
+    #[arg(default_value = "localhost:1999")]
+    host: String,
+    #[arg(short, long, default_value = "dicebot")]
+    username: String,
+    /// Room to sit in instead of the lobby
+    #[arg(short, long)]
+    room: Option<String>,
+    /// Roll a d20 for the room every this many seconds
+    #[arg(long)]
+    roll_every: Option<u64>,
+}
+
+/// Example bot, echoes and rolls dice
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let DiceBotArgs {
+        host,
+        username,
+        room,
+        roll_every,
+    } = DiceBotArgs::parse();
+    let mut bot = Bot::new(username)
+        .command(
+            "echo",
+            "say it back, like !echo hello",
+            |request| async move { request.reply(request.args.clone()).await },
+        )
+        .command("roll", "roll dice, like !roll 2d6", roll)
+        .on_mention(|request| async move {
+            let reply = format!("hi {}, try !help", request.message.username);
+            request.reply(reply).await
+        });
+    if let Some(room) = room {
+        bot = bot.with_room(room);
+    }
+    if let Some(secs) = roll_every {
+        bot = bot.every(Duration::from_secs(secs), |bot| async move {
+            bot.say(format!("d20 of the moment: {}", die(20))).await
+        });
+    }
+    let ct = CancellationToken::new();
+    let ct_one = ct.clone();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        ct_one.cancel();
+    });
+    if let Err(e) = bot.run(&host, ct).await {
+        eprintln!("couldn't log in: {e}");
+    }
+}
+
+async fn roll(request: Request) {
+    let reply = match parse_dice(&request.args) {
+        Some((dice, sides)) => {
+            let rolls: Vec<u32> = (0..dice).map(|_| die(sides)).collect();
+            let total: u32 = rolls.iter().sum();
+            match rolls.len() {
+                1 => format!("{dice}d{sides}: {total}"),
+                _ => {
+                    let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+                    format!("{dice}d{sides}: {} = {total}", rolls.join(" + "))
+                }
+            }
+        }
+        None => format!("can't roll {}, try 2d6", request.args),
+    };
+    request.reply(reply).await
+}
+
+/// `2d6` as 2 dice of 6 sides, `d20` as one, nothing as 1d6
+fn parse_dice(spec: &str) -> Option<(u32, u32)> {
+    if spec.is_empty() {
+        return Some((1, 6));
+    }
+    let (dice, sides) = spec.split_once('d')?;
+    let dice = match dice {
+        "" => 1,
+        dice => dice.parse().ok()?,
+    };
+    let sides = sides.parse().ok()?;
+    ((1..=MAX_DICE).contains(&dice) && (2..=MAX_SIDES).contains(&sides)).then_some((dice, sides))
+}
+
+fn die(sides: u32) -> u32 {
+    OsRng.next_u32() % sides + 1
+}
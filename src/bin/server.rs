@@ -1,19 +1,50 @@
-use std::{collections::HashSet, path::Path, sync::Arc};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
 use chatr::{
-    ChatrMessage, ReceiverFromClient, SenderToServer, Username,
-    chatroom::{
-        AdminMsg, Chatroom, ClientLoginResult, UnauthenticatedClient, process_client_login,
-    },
+    admin::DEFAULT_ADMIN_SOCKET,
+    audit::{Audit, DEFAULT_AUDIT_KEEP, DEFAULT_AUDIT_LOG, DEFAULT_AUDIT_MAX_BYTES},
+    chatroom::AdminMsg,
+    export::{self, ExportQuery},
+    room::Rooms,
+    server::{ChatrServer, DEFAULT_FILES_DIR},
 };
 use clap::Parser;
-use tokio::{io::AsyncWriteExt, net::TcpListener, sync::mpsc};
-use tokio_util::sync::CancellationToken;
+use tokio::{
+    io::AsyncBufReadExt,
+    sync::{mpsc, oneshot},
+};
 
 #[derive(clap::Parser, Debug, Clone)]
 struct ServerArgs {
     host: String,
     banned_usernames: Option<String>,
+    /// Where shared files are kept
+    #[arg(long, default_value = DEFAULT_FILES_DIR)]
+    files_dir: PathBuf,
+    /// Where room topics and access are kept between runs
+    #[arg(long, default_value = "chatr_rooms.json")]
+    rooms_file: PathBuf,
+    /// Message of the day sent to everyone as they join, or a file to read it from
+    #[arg(long)]
+    motd: Option<String>,
+    /// Unix socket `chatr-admin` talks to the running server through
+    #[arg(long, default_value = DEFAULT_ADMIN_SOCKET)]
+    admin_socket: PathBuf,
+    /// Serve Prometheus metrics over HTTP on this address, like 127.0.0.1:9100
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Where logins, disconnects and moderation get recorded, one JSON object per line
+    #[arg(long, default_value = DEFAULT_AUDIT_LOG)]
+    audit_log: PathBuf,
+    /// Rotate the audit log once it's this many bytes, 0 to never rotate
+    #[arg(long, default_value_t = DEFAULT_AUDIT_MAX_BYTES)]
+    audit_max_bytes: u64,
+    /// How many rotated audit logs to keep
+    #[arg(long, default_value_t = DEFAULT_AUDIT_KEEP)]
+    audit_keep: usize,
 }
 
 /// Server binary
@@ -24,96 +55,102 @@ async fn main() {
     let ServerArgs {
         banned_usernames,
         host,
+        files_dir,
+        motd,
+        rooms_file,
+        admin_socket,
+        metrics_addr,
+        audit_log,
+        audit_max_bytes,
+        audit_keep,
     } = ServerArgs::parse();
-    let banned_usernames: Arc<HashSet<String>> = Arc::new(match banned_usernames {
+    let server = ChatrServer::bind(host).await.unwrap();
+    // Bans made while running are written back when they came from a file
+    let server = match banned_usernames {
+        Some(string) if Path::new(&string).exists() => {
+            server.with_ban_file(PathBuf::from(string)).unwrap()
+        }
         Some(string) => {
-            let as_path = Path::new(&string);
-            if as_path.exists() {
-                let file_str = std::fs::read_to_string(as_path).unwrap();
-                HashSet::from_iter(file_str.split(",").map(|s| s.trim().to_string()))
-            } else {
-                HashSet::from_iter(string.split(",").map(|s| s.trim().to_string()))
-            }
+            server.with_banned_usernames(string.split(",").map(|s| s.trim().to_string()))
         }
-        None => HashSet::default(),
-    });
-
-    // Bind to host, create chatroom
-    let server = TcpListener::bind(host).await.unwrap();
-    let chatroom = Chatroom::new();
-    // Channels for comms
-    let (sender_to_chatroom, mut receiver_from_clients): (SenderToServer, ReceiverFromClient) =
-        mpsc::channel::<(Username, ChatrMessage)>(1024);
-    let (admin_send, admin_recv) = mpsc::channel::<AdminMsg>(1024);
-    // Run chatroom
-    chatroom.run(admin_recv);
-    let admin_send_one = admin_send.clone();
-    // Fan in listener for all clients/users
-    tokio::spawn(async move {
-        while let Some((user, msg)) = receiver_from_clients.recv().await {
-            tracing::trace!("recv from {user} msg {msg:?}");
-            match msg {
-                ChatrMessage::SentMessage { content } => admin_send_one
-                    .send(AdminMsg::DispatchMsg(user, content))
-                    .await
-                    .unwrap(),
-                ChatrMessage::Disconnect => {
-                    admin_send_one
-                        .send(AdminMsg::RemoveClient(user))
-                        .await
-                        .unwrap();
-                }
-                _ => (),
-            }
+        None => server,
+    };
+    let motd = motd.map(|motd| {
+        let as_path = Path::new(&motd);
+        if as_path.exists() {
+            std::fs::read_to_string(as_path)
+                .unwrap()
+                .trim_end()
+                .to_string()
+        } else {
+            motd
         }
     });
-    // Socket listener accepting new connections
-    tokio::spawn(async move {
-        loop {
-            let (socket, addr) = server.accept().await.unwrap();
-            let send_link = sender_to_chatroom.clone();
-            let admin_send = admin_send.clone();
-            tracing::debug!("new socket {}", addr);
-            let maybe_new_client =
-                process_client_login(UnauthenticatedClient::new(socket), &banned_usernames).await;
-            let mut new_client = match maybe_new_client {
-                Ok(result) => match result {
-                    ClientLoginResult::Accept(authenticated_client) => {
-                        tracing::info!("adding client: {}", authenticated_client.username);
-                        authenticated_client
-                    }
-                    ClientLoginResult::Reject {
-                        mut socket,
-                        username,
-                    } => {
-                        socket
-                            .write_all(
-                                &borsh::to_vec(&ChatrMessage::LoginRejected {
-                                    reason: format!("{username} is not allowed"),
-                                })
-                                .unwrap(),
-                            )
-                            .await
-                            .unwrap();
-                        return;
-                    }
-                },
-                Err(e) => {
-                    tracing::error!("fucked up {e}");
-                    return;
+    let rooms = Rooms::load(rooms_file).unwrap();
+    let audit = Audit::open(audit_log, audit_max_bytes, audit_keep)
+        .await
+        .unwrap();
+    let server = server
+        .with_rooms(rooms)
+        .with_motd(motd)
+        .with_files_dir(files_dir)
+        .with_admin_socket(Some(admin_socket))
+        .with_metrics_addr(metrics_addr)
+        .with_audit(audit)
+        .start()
+        .await
+        .unwrap();
+    // Commands typed into the server's terminal
+    tokio::spawn(run_console(server.admin()));
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = server.stopped() => tracing::info!("shut down by admin"),
+    }
+    server.shutdown().await;
+}
+
+/// Read admin commands from stdin until it closes, for now just
+/// `export <jsonl|text|html> <path> [room:name] [after:when] [before:when]`
+async fn run_console(admin_send: mpsc::Sender<AdminMsg>) {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        let result = match line.split_once(' ') {
+            Some(("export", args)) => match export::parse_export(args) {
+                Ok((format, path, query)) => {
+                    export_to_file(&admin_send, format, &path, query).await
                 }
-            };
-            let user = new_client.username.clone();
-            new_client.login_accepted().await.unwrap();
-            let (client_send, client_recv) = mpsc::channel(1024);
-            tracing::debug!("run {user}");
-            // Client spawned when verified
-            new_client.run(send_link, client_recv, CancellationToken::new());
-            admin_send
-                .send(AdminMsg::AddClient(user, client_send))
-                .await
-                .unwrap();
+                Err(reason) => Err(reason),
+            },
+            _ if line.is_empty() => continue,
+            _ => Err(format!("don't know the command {line}")),
+        };
+        match result {
+            Ok(done) => println!("{done}"),
+            Err(reason) => eprintln!("{reason}"),
         }
-    });
-    tokio::signal::ctrl_c().await.unwrap();
+    }
+}
+
+async fn export_to_file(
+    admin_send: &mpsc::Sender<AdminMsg>,
+    format: export::ExportFormat,
+    path: &Path,
+    query: ExportQuery,
+) -> Result<String, String> {
+    let title = export::title(&query);
+    let (reply, messages) = oneshot::channel();
+    admin_send
+        .send(AdminMsg::ExportTo(query, reply))
+        .await
+        .unwrap();
+    let messages = messages.await.map_err(|e| e.to_string())?;
+    tokio::fs::write(path, export::export(&messages, format, &title))
+        .await
+        .map_err(|e| format!("couldn't write {}: {e}", path.display()))?;
+    Ok(format!(
+        "exported {} messages to {}",
+        messages.len(),
+        path.display()
+    ))
 }
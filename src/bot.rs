@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    ChatMessage, ChatrMessage, Content, MessageKind, Username, client::ClientConnection,
+    markup::mentions_user, room::RoomName,
+};
+
+/// Wait before the first reconnect, doubling each failed attempt
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest wait between reconnects
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Commands start with this unless told otherwise
+pub const DEFAULT_PREFIX: &str = "!";
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type Handler<T> = Arc<dyn Fn(T) -> BoxFuture + Send + Sync>;
+
+fn handler<T, F, Fut>(f: F) -> Handler<T>
+where
+    F: Fn(T) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |t| Box::pin(f(t)))
+}
+
+/// Chat bot answering commands like `!deploy` and mentions, set up with its builder methods and
+/// started with [`Bot::run`], which reconnects whenever the connection drops
+pub struct Bot {
+    username: Username,
+    prefix: String,
+    /// Room to be in, joined again on every reconnect
+    room: Option<RoomName>,
+    commands: BTreeMap<String, Command>,
+    mention: Option<Handler<Request>>,
+    tasks: Vec<(Duration, Handler<BotHandle>)>,
+    reconnect_delay: Duration,
+}
+
+struct Command {
+    help: String,
+    handler: Handler<Request>,
+}
+
+/// Sends for a running [`Bot`], messages wait while it's reconnecting
+#[derive(Debug, Clone)]
+pub struct BotHandle {
+    outbox: mpsc::Sender<ChatrMessage>,
+}
+
+impl BotHandle {
+    pub async fn send(&self, msg: ChatrMessage) {
+        // Only fails once the bot has stopped, when there's no one to tell
+        let _ = self.outbox.send(msg).await;
+    }
+    /// Say something in the bot's room, as a notice so other bots leave it alone
+    pub async fn say(&self, content: impl Into<Content>) {
+        self.send(ChatrMessage::SentMessage {
+            kind: MessageKind::Notice,
+            content: content.into(),
+            parent: None,
+        })
+        .await
+    }
+}
+
+/// Message a command or mention handler was called for
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub message: ChatMessage,
+    /// What came after the command name, trimmed, the whole content for mentions
+    pub args: String,
+    pub bot: BotHandle,
+}
+
+impl Request {
+    /// Answer in a thread on the message, as a notice so other bots leave it alone
+    pub async fn reply(&self, content: impl Into<Content>) {
+        self.bot
+            .send(ChatrMessage::SentMessage {
+                kind: MessageKind::Notice,
+                content: content.into(),
+                parent: Some(self.message.id),
+            })
+            .await
+    }
+}
+
+impl Bot {
+    pub fn new(username: impl Into<Username>) -> Self {
+        Self {
+            username: username.into(),
+            prefix: DEFAULT_PREFIX.to_string(),
+            room: None,
+            commands: BTreeMap::new(),
+            mention: None,
+            tasks: Vec::new(),
+            reconnect_delay: RECONNECT_DELAY,
+        }
+    }
+    /// Commands start with `prefix` instead of `!`
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+    /// Sit in `room` instead of the lobby
+    pub fn with_room(mut self, room: impl Into<RoomName>) -> Self {
+        self.room = Some(room.into());
+        self
+    }
+    /// Wait `delay` before the first reconnect instead of [`RECONNECT_DELAY`]
+    pub fn with_reconnect_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_delay = delay;
+        self
+    }
+    /// Call `f` for `!name`, `help` is listed by `!help`
+    pub fn command<F, Fut>(mut self, name: &str, help: &str, f: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let command = Command {
+            help: help.to_string(),
+            handler: handler(f),
+        };
+        self.commands.insert(name.to_string(), command);
+        self
+    }
+    /// Call `f` for messages mentioning the bot that aren't commands
+    pub fn on_mention<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.mention = Some(handler(f));
+        self
+    }
+    /// Call `f` every `period` while running, the first time one `period` after starting
+    pub fn every<F, Fut>(mut self, period: Duration, f: F) -> Self
+    where
+        F: Fn(BotHandle) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.push((period, handler(f)));
+        self
+    }
+    /// Log in to `host` and answer until `ct` is cancelled, reconnecting when the connection
+    /// drops or can't be made. Only a failed login, like being banned, stops it early.
+    pub async fn run(self, host: &str, ct: CancellationToken) -> io::Result<()> {
+        let (outbox, mut outgoing) = mpsc::channel(1024);
+        let handle = BotHandle { outbox };
+        for (period, task) in &self.tasks {
+            let (period, task, handle, ct) = (*period, task.clone(), handle.clone(), ct.clone());
+            tokio::spawn(async move {
+                let start = tokio::time::Instant::now() + period;
+                let mut interval = tokio::time::interval_at(start, period);
+                loop {
+                    tokio::select! {
+                        _ = ct.cancelled() => break,
+                        _ = interval.tick() => task(handle.clone()).await,
+                    }
+                }
+            });
+        }
+        let mut delay = self.reconnect_delay;
+        loop {
+            let connection = tokio::select! {
+                _ = ct.cancelled() => return Ok(()),
+                connection = ClientConnection::new(host) => connection,
+            };
+            match connection {
+                Ok(mut connection) => {
+                    connection.login(self.username.clone()).await?;
+                    tracing::info!("{} connected to {host}", self.username);
+                    delay = self.reconnect_delay;
+                    if self.session(connection, &handle, &mut outgoing, &ct).await {
+                        return Ok(());
+                    }
+                    tracing::warn!("{} lost the connection to {host}", self.username);
+                }
+                Err(e) => tracing::warn!("{} couldn't connect to {host} {e}", self.username),
+            }
+            tokio::select! {
+                _ = ct.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(delay) => (),
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+    /// Answer over `connection` until it drops, or `ct` is cancelled when it hands back true
+    async fn session(
+        &self,
+        connection: ClientConnection,
+        handle: &BotHandle,
+        outgoing: &mut mpsc::Receiver<ChatrMessage>,
+        ct: &CancellationToken,
+    ) -> bool {
+        let (to_bot, mut from_server) = mpsc::channel(1024);
+        let (to_server, from_bot) = mpsc::channel(1024);
+        let tasks = connection.run(to_bot, from_bot, ct.child_token());
+        if let Some(room) = &self.room {
+            let join = ChatrMessage::Join {
+                room: room.clone(),
+                password: None,
+            };
+            let _ = to_server.send(join).await;
+        }
+        loop {
+            tokio::select! {
+                // The connection says goodbye itself
+                _ = ct.cancelled() => {
+                    tasks.finished().await;
+                    return true;
+                }
+                Some(msg) = outgoing.recv() => {
+                    if to_server.send(msg).await.is_err() {
+                        return false;
+                    }
+                }
+                msg = from_server.recv() => match msg {
+                    Some(ChatrMessage::ReceivedMessage { message }) => {
+                        self.dispatch(message, handle)
+                    }
+                    Some(ChatrMessage::JoinRejected { room, reason }) => {
+                        tracing::warn!("{} couldn't join #{room} {reason}", self.username)
+                    }
+                    Some(_) => (),
+                    None => return false,
+                },
+            }
+        }
+    }
+    /// Hand `message` to the handler it's for, if any, in a task of its own
+    fn dispatch(&self, message: ChatMessage, handle: &BotHandle) {
+        if message.username == self.username
+            || matches!(message.kind, MessageKind::Notice | MessageKind::System)
+        {
+            return;
+        }
+        let request = |args: &str| Request {
+            message: message.clone(),
+            args: args.trim().to_string(),
+            bot: handle.clone(),
+        };
+        if let Some(command) = message.content.strip_prefix(&self.prefix) {
+            let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+            let request = request(args);
+            match self.commands.get(name) {
+                Some(command) => {
+                    tokio::spawn((command.handler)(request));
+                }
+                None if name == "help" => {
+                    let help = self.help();
+                    tokio::spawn(async move { request.reply(help).await });
+                }
+                None => {
+                    let prefix = &self.prefix;
+                    let reply = format!("don't know {prefix}{name}, try {prefix}help");
+                    tokio::spawn(async move { request.reply(reply).await });
+                }
+            }
+        } else if let Some(mention) = &self.mention
+            && mentions_user(&message.content, &self.username)
+        {
+            tokio::spawn(mention(request(&message.content)));
+        }
+    }
+    /// Every command with its help, for `!help`
+    fn help(&self) -> String {
+        let mut lines = vec![format!("{}help: list commands", self.prefix)];
+        for (name, command) in &self.commands {
+            lines.push(format!("{}{name}: {}", self.prefix, command.help));
+        }
+        lines.join("\n")
+    }
+}
@@ -1,69 +1,859 @@
 use std::collections::{HashMap, HashSet};
 use std::io;
-use std::sync::Arc;
+use std::sync::{
+    Arc, RwLock,
+    atomic::{AtomicU64, Ordering},
+};
+use std::time::Instant;
 
-use borsh::BorshDeserialize;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::mpsc,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
 };
-use tokio_util::sync::CancellationToken;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{info, instrument, trace};
 
-use crate::{ChatrMessage, Content, ReceiverFromServer, SenderToClient, SenderToServer, Username};
+use crate::{
+    ChatMessage, ChatrMessage, Content, DEFAULT_ROOM, MAX_CONTENT_LEN, MAX_USERNAME_LEN, MessageId,
+    MessageKind, ReceiverFromClient, ReceiverFromServer, SYSTEM_USERNAME, SenderToClient,
+    SenderToServer, SessionId, Status, Username,
+    admin::{ServerStats, UserInfo},
+    audit::{Audit, AuditEvent},
+    e2e::{PublicKey, Sealed},
+    export::{self, ExportQuery},
+    files::{self, Attachment},
+    frame,
+    hook::Hooks,
+    message_log::{self, BACKLOG_LEN, MessageLog},
+    metrics::Metrics,
+    reaction::{self, MAX_EMOJI_PER_MESSAGE},
+    room::{
+        self, Access, MAX_PASSWORD_TRIES, MAX_TOPIC_LEN, PASSWORD_TRY_WINDOW, Role, RoomName, Rooms,
+    },
+    search::{self, SearchQuery},
+    time,
+    typing::TYPING_RELAY_INTERVAL,
+};
 
 /// Messages to manage different chatroom aspects
 pub enum AdminMsg {
-    /// Add a client/user to the chatroom
-    AddClient(Username, SenderToClient),
-    /// Remove a client/user from the chatroom
-    RemoveClient(Username),
-    /// Send a message to all clients
-    DispatchMsg(Username, Content),
+    /// Add a client/user to the chatroom, cancelling the token ends their connection. Answers
+    /// whether they got in, they don't while someone is still connected under that name.
+    AddClient(
+        Username,
+        SessionId,
+        SenderToClient,
+        CancellationToken,
+        oneshot::Sender<bool>,
+    ),
+    /// Remove a client/user from the chatroom, if they're still on that session
+    RemoveClient(Username, SessionId),
+    /// Send a message to all clients, optionally as a reply to an earlier one
+    DispatchMsg(Username, MessageKind, Content, Option<MessageId>),
+    /// Send the list of connected users to one client
+    SendRoster(Username),
+    /// Change a user's status and let everyone know
+    SetStatus(Username, Status),
+    /// Let everyone else know a user is typing
+    Typing(Username),
+    /// Add a user's reaction to a message and let everyone know
+    React(Username, MessageId, String),
+    /// Take back a user's reaction and let everyone know
+    Unreact(Username, MessageId, String),
+    /// Pass a message on to one client
+    SendTo(Username, ChatrMessage),
+    /// Let everyone know a user shared a file
+    Attach(Username, Attachment),
+    /// Send a user a page of history matching their search
+    Search(Username, SearchQuery),
+    /// Send a user the history matching their export
+    Export(Username, ExportQuery),
+    /// Hand back the history matching an export from the server console
+    ExportTo(ExportQuery, oneshot::Sender<Vec<ChatMessage>>),
+    /// Move a user to another room, if they're allowed in
+    Join(Username, RoomName, Option<String>),
+    /// Change or clear the topic of the room a user is in
+    SetTopic(Username, Option<String>),
+    /// Send a user the list of rooms
+    SendRooms(Username),
+    /// Change who can join the room a user is in
+    SetAccess(Username, Access, Option<String>),
+    /// Password for a room a user set, hashed off the chatroom's task
+    PasswordHashed(Username, RoomName, Result<String, String>),
+    /// Whether the password a user gave matched the hash a room had, checked off the
+    /// chatroom's task
+    PasswordChecked(Username, RoomName, String, bool),
+    /// Invite the second user to the room the first is in
+    Invite(Username, Username),
+    /// Take back the second user's invite and role in the room the first is in
+    Revoke(Username, Username),
+    /// Change the second user's role in the room the first is in
+    SetRole(Username, Username, Option<Role>),
+    /// Put a user's public key in the directory for others to seal messages to
+    PublishKey(Username, PublicKey),
+    /// Send the first user the second's public key
+    KeyRequest(Username, Username),
+    /// Pass a sealed message from the first user on to the second, without looking in it
+    SendDirect(Username, Username, Sealed),
+    /// Hand back who is connected, for the admin socket
+    ListUsers(oneshot::Sender<Vec<UserInfo>>),
+    /// Disconnect a user, telling them why, hands back whether they were connected
+    Kick(Username, String, oneshot::Sender<bool>),
+    /// Notice from the server to every room with someone in it
+    Broadcast(Content),
+    /// Hand back how the chatroom is doing
+    Stats(oneshot::Sender<ServerStats>),
+    /// Disconnect everyone before the server stops, answering once done
+    Shutdown(oneshot::Sender<()>),
+}
+/// Usernames not allowed to log in, shared with the admin socket so bans apply right away
+pub type BannedUsernames = Arc<RwLock<HashSet<Username>>>;
+/// Connected client as the chatroom sees it
+#[derive(Debug)]
+pub struct ConnectedClient {
+    id: SessionId,
+    /// Cancelling it ends their connection
+    session: CancellationToken,
+    sender: SenderToClient,
+    metrics: Arc<Metrics>,
+}
+impl ConnectedClient {
+    /// Their connection has ended, even if the chatroom hasn't heard yet
+    fn gone(&self) -> bool {
+        self.session.is_cancelled() || self.sender.is_closed()
+    }
+    /// Queue `msg` for them without waiting. One that's gone, or so far behind their queue is
+    /// full, misses it and gets disconnected, so they can't hold up everyone else.
+    pub fn send(&self, msg: ChatrMessage) {
+        if let Err(e) = self.sender.try_send(msg) {
+            tracing::warn!("dropping a client that can't keep up {e}");
+            self.metrics.dropped(1);
+            self.session.cancel();
+        }
+    }
 }
 #[derive(Default, Debug)]
 /// Representation of the server chatroom
 pub struct Chatroom {
-    clients: HashMap<Username, (CancellationToken, SenderToClient)>,
+    clients: HashMap<Username, ConnectedClient>,
+    log: MessageLog,
+    rooms: Rooms,
+    /// Sent to everyone as they join
+    motd: Option<String>,
+    metrics: Arc<Metrics>,
+    audit: Audit,
+    hooks: Hooks,
+}
+pub async fn send_to_clients(clients: &mut HashMap<Username, ConnectedClient>, msg: ChatrMessage) {
+    for client in clients.values() {
+        client.send(msg.clone())
+    }
 }
-pub async fn send_to_clients(
-    clients: &mut HashMap<Username, (CancellationToken, SenderToClient)>,
+/// Notice from the server itself, logged and shown to users in `room` like any other message
+pub fn system_notice(log: &mut MessageLog, room: &str, content: String) -> ChatrMessage {
+    let message = log
+        .append(
+            room.to_string(),
+            SYSTEM_USERNAME.to_string(),
+            MessageKind::System,
+            content,
+            None,
+        )
+        .clone();
+    ChatrMessage::ReceivedMessage { message }
+}
+/// Send to just `username`
+pub async fn send_to_client(
+    clients: &HashMap<Username, ConnectedClient>,
+    username: &Username,
+    msg: ChatrMessage,
+) {
+    if let Some(client) = clients.get(username) {
+        client.send(msg)
+    }
+}
+/// Send to everyone in `room`
+pub async fn send_to_room(
+    clients: &HashMap<Username, ConnectedClient>,
+    rooms: &Rooms,
+    room: &str,
     msg: ChatrMessage,
 ) {
-    for (_, (_, stc)) in clients.iter_mut() {
-        stc.send(msg.clone()).await.unwrap()
+    for username in rooms.members(room) {
+        send_to_client(clients, username, msg.clone()).await
+    }
+}
+/// Put `username` in `room` with its history, letting both rooms know
+async fn join_room(
+    clients: &HashMap<Username, ConnectedClient>,
+    rooms: &mut Rooms,
+    log: &mut MessageLog,
+    username: &Username,
+    room: &RoomName,
+) {
+    let new_room = !rooms.exists(room);
+    if let Some(left) = rooms.join(username, room)
+        && left != *room
+    {
+        let notice = system_notice(log, &left, format!("{username} left for #{room}"));
+        send_to_room(clients, rooms, &left, notice).await;
+    }
+    let joined = ChatrMessage::Joined {
+        room: rooms.info(room).unwrap(),
+    };
+    send_to_client(clients, username, joined).await;
+    // In as many goes as it takes to stay under the frame limit
+    let recent = log.recent(room, BACKLOG_LEN);
+    let mut recent = recent.iter().peekable();
+    loop {
+        let messages = message_log::next_page(&mut recent, BACKLOG_LEN);
+        send_to_client(clients, username, ChatrMessage::History { messages }).await;
+        if recent.peek().is_none() {
+            break;
+        }
+    }
+    let notice = system_notice(log, room, format!("{username} joined"));
+    send_to_room(clients, rooms, room, notice).await;
+    if new_room {
+        save_rooms(rooms).await;
     }
 }
+/// Let `room` know about a change to who may do what, or `username` why it didn't happen
+async fn acl_changed<T>(
+    clients: &HashMap<Username, ConnectedClient>,
+    rooms: &mut Rooms,
+    log: &mut MessageLog,
+    username: &Username,
+    room: &str,
+    result: Result<T, String>,
+    content: String,
+) {
+    match result {
+        Ok(_) => {
+            let notice = system_notice(log, room, content);
+            send_to_room(clients, rooms, room, notice).await;
+            save_rooms(rooms).await;
+        }
+        Err(reason) => send_to_client(clients, username, ChatrMessage::Error { reason }).await,
+    }
+}
+async fn save_rooms(rooms: &Rooms) {
+    if let Err(e) = rooms.save().await {
+        tracing::error!("couldn't save rooms {e}");
+    }
+}
+/// `username` is gone, let their room and everyone else know
+async fn remove_from_rooms(
+    clients: &mut HashMap<Username, ConnectedClient>,
+    rooms: &mut Rooms,
+    log: &mut MessageLog,
+    username: Username,
+    how: &str,
+) {
+    if let Some(room) = rooms.leave(&username) {
+        let notice = system_notice(log, &room, format!("{username} {how}"));
+        send_to_room(clients, rooms, &room, notice).await;
+    }
+    send_to_clients(clients, ChatrMessage::UserDisconnected { username }).await;
+}
+/// Send to everyone in the same room but `username`
+pub async fn send_to_others(
+    clients: &HashMap<Username, ConnectedClient>,
+    rooms: &Rooms,
+    username: &Username,
+    msg: ChatrMessage,
+) {
+    let room = rooms.room_of(username);
+    for other in rooms.members(room).filter(|u| *u != username) {
+        send_to_client(clients, other, msg.clone()).await
+    }
+}
+/// Tell `username` who is connected and their status, themselves included
+pub async fn send_roster(
+    clients: &HashMap<Username, ConnectedClient>,
+    statuses: &HashMap<Username, Status>,
+    username: &Username,
+) {
+    let Some(client) = clients.get(username) else {
+        return;
+    };
+    let mut members: Vec<(Username, Status)> = clients
+        .keys()
+        .map(|u| (u.clone(), statuses.get(u).cloned().unwrap_or_default()))
+        .collect();
+    members.sort_by(|(a, _), (b, _)| a.cmp(b));
+    client.send(ChatrMessage::Roster { members })
+}
+/// Count a password check for `username`, false once they've had [`MAX_PASSWORD_TRIES`] in
+/// the last [`PASSWORD_TRY_WINDOW`]
+fn try_password(tries: &mut HashMap<Username, (Instant, u32)>, username: &Username) -> bool {
+    let now = Instant::now();
+    tries.retain(|_, (since, _)| now.duration_since(*since) < PASSWORD_TRY_WINDOW);
+    let (_, count) = tries.entry(username.clone()).or_insert((now, 0));
+    *count += 1;
+    *count <= MAX_PASSWORD_TRIES
+}
+/// Messages in the history an export asks for, oldest first
+fn exported(log: &MessageLog, query: &ExportQuery) -> Vec<ChatMessage> {
+    log.iter()
+        .filter(|message| query.matches(message))
+        .cloned()
+        .collect()
+}
 impl Chatroom {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            log: MessageLog::default(),
+            rooms: Rooms::default(),
+            motd: None,
+            metrics: Arc::default(),
+            audit: Audit::default(),
+            hooks: Hooks::default(),
         }
     }
-    pub fn run(self, mut rx: mpsc::Receiver<AdminMsg>) {
-        let Self { mut clients } = self;
-        let ct = CancellationToken::new();
+    /// Tell `hooks` about logins and disconnects
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+    /// Record changes to who can get into rooms in `audit`
+    pub fn with_audit(mut self, audit: Audit) -> Self {
+        self.audit = audit;
+        self
+    }
+    /// Keep track of connected clients in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    /// Rooms to start with, like ones loaded from an earlier run
+    pub fn with_rooms(mut self, rooms: Rooms) -> Self {
+        self.rooms = rooms;
+        self
+    }
+    /// Message of the day everyone gets when they join
+    pub fn with_motd(mut self, motd: Option<String>) -> Self {
+        self.motd = motd;
+        self
+    }
+    /// Handle `rx` until every sender is gone or `ct` is cancelled
+    pub fn run(self, mut rx: mpsc::Receiver<AdminMsg>, ct: CancellationToken) -> JoinHandle<()> {
+        let Self {
+            mut clients,
+            mut log,
+            mut rooms,
+            motd,
+            metrics,
+            audit,
+            hooks,
+        } = self;
+        let mut statuses: HashMap<Username, Status> = HashMap::new();
+        let mut last_typing: HashMap<Username, Instant> = HashMap::new();
+        // Kept after users leave, so a different key under the same name gets noticed
+        let mut keys: HashMap<Username, PublicKey> = HashMap::new();
+        // Password checks made by anyone lately, so guessing can't tie up the blocking threads
+        let mut password_tries: HashMap<Username, (Instant, u32)> = HashMap::new();
+        let started = Instant::now();
+        // Passwords get hashed and checked on blocking threads, which hand back through here
+        let (hashed_send, mut hashed_recv) = mpsc::channel::<AdminMsg>(64);
         tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
+            loop {
+                let msg = tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    Some(msg) = hashed_recv.recv() => msg,
+                    _ = ct.cancelled() => break,
+                };
                 match msg {
-                    AdminMsg::AddClient(username, sender) => {
-                        clients.insert(username.clone(), (ct.clone(), sender));
-                        send_to_clients(&mut clients, ChatrMessage::UserConnected { username })
+                    AdminMsg::AddClient(username, id, sender, session, reply) => {
+                        if clients.get(&username).is_some_and(|old| !old.gone()) {
+                            let _ = reply.send(false);
+                            continue;
+                        }
+                        // Back before we heard their old connection ended, it won't be heard
+                        // from again
+                        if clients.remove(&username).is_some() {
+                            metrics.client_disconnected(&username);
+                            hooks.disconnect(&username);
+                            statuses.remove(&username);
+                            last_typing.remove(&username);
+                            remove_from_rooms(
+                                &mut clients,
+                                &mut rooms,
+                                &mut log,
+                                username.clone(),
+                                "left",
+                            )
                             .await;
+                        }
+                        let _ = reply.send(true);
+                        metrics.client_connected(&username, &sender);
+                        let client = ConnectedClient {
+                            id,
+                            session,
+                            sender,
+                            metrics: metrics.clone(),
+                        };
+                        clients.insert(username.clone(), client);
+                        if let Some(text) = &motd {
+                            let msg = ChatrMessage::Motd { text: text.clone() };
+                            send_to_client(&clients, &username, msg).await;
+                        }
+                        send_roster(&clients, &statuses, &username).await;
+                        let msg = ChatrMessage::UserConnected {
+                            username: username.clone(),
+                        };
+                        send_to_clients(&mut clients, msg).await;
+                        let lobby = DEFAULT_ROOM.to_string();
+                        join_room(&clients, &mut rooms, &mut log, &username, &lobby).await;
+                        // Hooks hear about the login before anything they send
+                        for msg in hooks.login(&username) {
+                            send_to_client(&clients, &username, msg).await;
+                        }
                     }
-                    AdminMsg::RemoveClient(username) => {
+                    AdminMsg::RemoveClient(username, id) => {
+                        // A session that's already been kicked or taken over from
+                        if clients.get(&username).is_none_or(|client| client.id != id) {
+                            continue;
+                        }
                         clients.remove(&username);
-                        send_to_clients(&mut clients, ChatrMessage::UserDisconnected { username })
+                        metrics.client_disconnected(&username);
+                        hooks.disconnect(&username);
+                        statuses.remove(&username);
+                        last_typing.remove(&username);
+                        remove_from_rooms(&mut clients, &mut rooms, &mut log, username, "left")
                             .await;
                     }
-                    AdminMsg::DispatchMsg(username, content) => {
-                        let msg = ChatrMessage::ReceivedMessage { username, content };
+                    AdminMsg::DispatchMsg(username, kind, content, parent) => {
+                        last_typing.remove(&username);
+                        let room = rooms.room_of(&username).to_string();
+                        if let Some(parent) = parent
+                            && log.get(parent).is_none_or(|m| m.room != room)
+                        {
+                            let reason = format!("no message {parent} to reply to");
+                            send_to_client(&clients, &username, ChatrMessage::Error { reason })
+                                .await;
+                            continue;
+                        }
+                        let message = log.append(room.clone(), username, kind, content, parent);
+                        let msg = ChatrMessage::ReceivedMessage {
+                            message: message.clone(),
+                        };
+                        send_to_room(&clients, &rooms, &room, msg).await;
+                    }
+                    AdminMsg::SendRoster(username) => {
+                        send_roster(&clients, &statuses, &username).await
+                    }
+                    AdminMsg::SetStatus(username, status) => {
+                        if !clients.contains_key(&username) {
+                            continue;
+                        }
+                        statuses.insert(username.clone(), status.clone());
+                        let msg = ChatrMessage::StatusChanged { username, status };
                         send_to_clients(&mut clients, msg).await;
                     }
+                    AdminMsg::Typing(username) => {
+                        let now = Instant::now();
+                        let too_soon = last_typing
+                            .get(&username)
+                            .is_some_and(|last| now.duration_since(*last) < TYPING_RELAY_INTERVAL);
+                        if too_soon || !clients.contains_key(&username) {
+                            continue;
+                        }
+                        last_typing.insert(username.clone(), now);
+                        let msg = ChatrMessage::UserTyping {
+                            username: username.clone(),
+                        };
+                        send_to_others(&clients, &rooms, &username, msg).await;
+                    }
+                    AdminMsg::React(username, id, emoji) => {
+                        let room = rooms.room_of(&username).to_string();
+                        let message = log.get_mut(id).filter(|m| m.room == room);
+                        let result = match (reaction::resolve(&emoji), message) {
+                            (None, _) => Err(format!("{emoji} isn't an emoji")),
+                            (_, None) => Err(format!("no message {id} to react to")),
+                            (Some(emoji), Some(message)) => {
+                                let new_emoji = !message.reactions.iter().any(|r| r.emoji == emoji);
+                                if new_emoji && message.reactions.len() >= MAX_EMOJI_PER_MESSAGE {
+                                    Err(format!("message {id} has too many reactions"))
+                                } else {
+                                    Ok(message.add_reaction(&emoji, &username).then_some(emoji))
+                                }
+                            }
+                        };
+                        match result {
+                            Ok(Some(emoji)) => {
+                                let msg = ChatrMessage::ReactionAdded {
+                                    id,
+                                    emoji,
+                                    username,
+                                };
+                                send_to_room(&clients, &rooms, &room, msg).await;
+                            }
+                            Ok(None) => (),
+                            Err(reason) => {
+                                send_to_client(&clients, &username, ChatrMessage::Error { reason })
+                                    .await
+                            }
+                        }
+                    }
+                    AdminMsg::Search(username, query) => {
+                        let readable = log.iter().filter(|m| rooms.can_read(&username, &m.room));
+                        let (hits, more) = search::search(readable, &query);
+                        let msg = ChatrMessage::SearchResults { query, hits, more };
+                        send_to_client(&clients, &username, msg).await
+                    }
+                    AdminMsg::Export(username, query) => {
+                        let mut messages = exported(&log, &query);
+                        messages.retain(|m| rooms.can_read(&username, &m.room));
+                        for msg in export::export_pages(messages) {
+                            send_to_client(&clients, &username, msg).await
+                        }
+                    }
+                    AdminMsg::ExportTo(query, reply) => {
+                        let _ = reply.send(exported(&log, &query));
+                    }
+                    AdminMsg::SendTo(username, msg) => {
+                        send_to_client(&clients, &username, msg).await
+                    }
+                    AdminMsg::Attach(username, attachment) => {
+                        let content = format!(
+                            "shared {} ({})",
+                            attachment.name,
+                            files::human_size(attachment.size)
+                        );
+                        let room = rooms.room_of(&username).to_string();
+                        let message =
+                            log.append(room.clone(), username, MessageKind::Normal, content, None);
+                        message.attachment = Some(attachment);
+                        let msg = ChatrMessage::ReceivedMessage {
+                            message: message.clone(),
+                        };
+                        send_to_room(&clients, &rooms, &room, msg).await;
+                    }
+                    AdminMsg::Unreact(username, id, emoji) => {
+                        let Some(emoji) = reaction::resolve(&emoji) else {
+                            continue;
+                        };
+                        let room = rooms.room_of(&username).to_string();
+                        if log.get_mut(id).is_some_and(|message| {
+                            message.room == room && message.remove_reaction(&emoji, &username)
+                        }) {
+                            let msg = ChatrMessage::ReactionRemoved {
+                                id,
+                                emoji,
+                                username,
+                            };
+                            send_to_room(&clients, &rooms, &room, msg).await;
+                        }
+                    }
+                    AdminMsg::Join(username, name, password) => {
+                        if !clients.contains_key(&username) {
+                            continue;
+                        }
+                        let Some(room) = room::room_name(&name) else {
+                            let reason = format!("{name} isn't a room name");
+                            send_to_client(&clients, &username, ChatrMessage::Error { reason })
+                                .await;
+                            continue;
+                        };
+                        if rooms.room_of(&username) == room {
+                            continue;
+                        }
+                        let hash = rooms
+                            .check_join(&username, &room, password.as_deref())
+                            .and_then(|hash| match hash {
+                                Some(_) if !try_password(&mut password_tries, &username) => {
+                                    Err("too many password tries, wait a minute".to_string())
+                                }
+                                hash => Ok(hash),
+                            });
+                        match (hash, password) {
+                            (Ok(None), _) => {
+                                join_room(&clients, &mut rooms, &mut log, &username, &room).await
+                            }
+                            // Only handed a hash when they gave a password
+                            (Ok(Some(hash)), password) => {
+                                let password = password.unwrap_or_default();
+                                let done = hashed_send.clone();
+                                tokio::task::spawn_blocking(move || {
+                                    let matched = room::password_matches(&password, &hash);
+                                    let checked =
+                                        AdminMsg::PasswordChecked(username, room, hash, matched);
+                                    let _ = done.blocking_send(checked);
+                                });
+                            }
+                            (Err(reason), _) => {
+                                let msg = ChatrMessage::JoinRejected { room, reason };
+                                send_to_client(&clients, &username, msg).await;
+                            }
+                        }
+                    }
+                    AdminMsg::PasswordChecked(username, room, hash, matched) => {
+                        if !clients.contains_key(&username) || rooms.room_of(&username) == room {
+                            continue;
+                        }
+                        // Changed while it was being checked, the new one is what counts
+                        if !matched || rooms.password_hash(&room) != Some(hash.as_str()) {
+                            let reason = "wrong password".to_string();
+                            let msg = ChatrMessage::JoinRejected { room, reason };
+                            send_to_client(&clients, &username, msg).await;
+                            continue;
+                        }
+                        join_room(&clients, &mut rooms, &mut log, &username, &room).await;
+                    }
+                    AdminMsg::SetTopic(username, text) => {
+                        let room = rooms.room_of(&username).to_string();
+                        let text = text.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+                        let reason = if !rooms.can_set_topic(&username, &room) {
+                            Some(format!("you can't change the topic of #{room}"))
+                        } else if text
+                            .as_ref()
+                            .is_some_and(|t| t.chars().count() > MAX_TOPIC_LEN)
+                        {
+                            Some(format!("topics are at most {MAX_TOPIC_LEN} characters"))
+                        } else {
+                            None
+                        };
+                        if let Some(reason) = reason {
+                            send_to_client(&clients, &username, ChatrMessage::Error { reason })
+                                .await;
+                            continue;
+                        }
+                        let topic = rooms.set_topic(&username, &room, text);
+                        save_rooms(&rooms).await;
+                        let content = match &topic {
+                            Some(topic) => format!("{username} set the topic: {}", topic.text),
+                            None => format!("{username} cleared the topic"),
+                        };
+                        let notice = system_notice(&mut log, &room, content);
+                        let msg = ChatrMessage::TopicChanged {
+                            room: room.clone(),
+                            topic,
+                        };
+                        send_to_room(&clients, &rooms, &room, msg).await;
+                        send_to_room(&clients, &rooms, &room, notice).await;
+                    }
+                    AdminMsg::SetAccess(username, access, password) => {
+                        let room = rooms.room_of(&username).to_string();
+                        let password = password.filter(|p| !p.is_empty());
+                        if let (Access::Password, Some(password)) = (access, password) {
+                            // Only those who could set it get it hashed
+                            let allowed = rooms
+                                .check_role(&username, &room, Role::Operator)
+                                .and_then(|_| {
+                                    try_password(&mut password_tries, &username)
+                                        .then_some(())
+                                        .ok_or("too many password tries, wait a minute".to_string())
+                                });
+                            if let Err(reason) = allowed {
+                                send_to_client(&clients, &username, ChatrMessage::Error { reason })
+                                    .await;
+                                continue;
+                            }
+                            let done = hashed_send.clone();
+                            tokio::task::spawn_blocking(move || {
+                                let hash = room::hash_password(&password);
+                                let _ = done
+                                    .blocking_send(AdminMsg::PasswordHashed(username, room, hash));
+                            });
+                            continue;
+                        }
+                        let result = rooms.set_access(&username, &room, access, None);
+                        let detail = Some(access.to_string());
+                        audit.room_action(&username, "set_access", &room, None, detail, &result);
+                        let content = format!("{username} made #{room} {access}");
+                        acl_changed(
+                            &clients, &mut rooms, &mut log, &username, &room, result, content,
+                        )
+                        .await;
+                    }
+                    AdminMsg::PasswordHashed(username, room, hash) => {
+                        // The room they were in when they set it, even if they've moved since
+                        let access = Access::Password;
+                        let result = hash.and_then(|hash| {
+                            rooms.set_access(&username, &room, access, Some(hash))
+                        });
+                        let detail = Some(access.to_string());
+                        audit.room_action(&username, "set_access", &room, None, detail, &result);
+                        let content = format!("{username} made #{room} {access}");
+                        acl_changed(
+                            &clients, &mut rooms, &mut log, &username, &room, result, content,
+                        )
+                        .await;
+                    }
+                    AdminMsg::Invite(username, invitee) => {
+                        let room = rooms.room_of(&username).to_string();
+                        let result = rooms.invite(&username, &room, &invitee);
+                        audit.room_action(
+                            &username,
+                            "invite",
+                            &room,
+                            Some(&invitee),
+                            None,
+                            &result,
+                        );
+                        if result.is_ok() {
+                            let msg = ChatrMessage::Invited {
+                                room: room.clone(),
+                                by: username.clone(),
+                            };
+                            send_to_client(&clients, &invitee, msg).await;
+                        }
+                        let content = format!("{username} invited {invitee}");
+                        acl_changed(
+                            &clients, &mut rooms, &mut log, &username, &room, result, content,
+                        )
+                        .await;
+                    }
+                    AdminMsg::Revoke(username, revoked) => {
+                        let room = rooms.room_of(&username).to_string();
+                        let result = rooms.revoke(&username, &room, &revoked);
+                        audit.room_action(
+                            &username,
+                            "revoke",
+                            &room,
+                            Some(&revoked),
+                            None,
+                            &result,
+                        );
+                        let locked_out = result.as_ref().is_ok_and(|locked_out| *locked_out);
+                        let content = format!("{username} revoked {revoked}");
+                        acl_changed(
+                            &clients, &mut rooms, &mut log, &username, &room, result, content,
+                        )
+                        .await;
+                        if locked_out {
+                            let lobby = DEFAULT_ROOM.to_string();
+                            join_room(&clients, &mut rooms, &mut log, &revoked, &lobby).await;
+                        }
+                    }
+                    AdminMsg::SetRole(username, target, role) => {
+                        let room = rooms.room_of(&username).to_string();
+                        let result = rooms.set_role(&username, &room, &target, role);
+                        let detail = role.map(|role| role.to_string());
+                        audit.room_action(
+                            &username,
+                            "set_role",
+                            &room,
+                            Some(&target),
+                            detail,
+                            &result,
+                        );
+                        let content = match role {
+                            Some(role) => format!("{username} made {target} {role}"),
+                            None => format!("{username} took away {target}'s role"),
+                        };
+                        acl_changed(
+                            &clients, &mut rooms, &mut log, &username, &room, result, content,
+                        )
+                        .await;
+                    }
+                    AdminMsg::SendRooms(username) => {
+                        let msg = ChatrMessage::RoomList {
+                            rooms: rooms.list(),
+                        };
+                        send_to_client(&clients, &username, msg).await
+                    }
+                    AdminMsg::PublishKey(username, key) => {
+                        keys.insert(username, key);
+                    }
+                    AdminMsg::KeyRequest(username, target) => {
+                        let msg = ChatrMessage::PeerKey {
+                            key: keys.get(&target).copied(),
+                            username: target,
+                        };
+                        send_to_client(&clients, &username, msg).await
+                    }
+                    AdminMsg::SendDirect(username, to, sealed) => {
+                        // Not logged, history is shared and this is only for them
+                        if clients.contains_key(&to) {
+                            let msg = ChatrMessage::ReceivedDirect {
+                                from: username,
+                                sent_at: time::now(),
+                                sealed,
+                            };
+                            send_to_client(&clients, &to, msg).await
+                        } else {
+                            let reason = format!("{to} isn't online");
+                            send_to_client(&clients, &username, ChatrMessage::Error { reason })
+                                .await
+                        }
+                    }
+                    AdminMsg::ListUsers(reply) => {
+                        let mut users: Vec<UserInfo> = clients
+                            .keys()
+                            .map(|username| UserInfo {
+                                username: username.clone(),
+                                room: rooms.room_of(username).to_string(),
+                                status: statuses.get(username).cloned().unwrap_or_default(),
+                            })
+                            .collect();
+                        users.sort_by(|a, b| a.username.cmp(&b.username));
+                        let _ = reply.send(users);
+                    }
+                    AdminMsg::Kick(username, reason, reply) => {
+                        // Dropping their sender ends the connection once these are written
+                        let Some(client) = clients.remove(&username) else {
+                            let _ = reply.send(false);
+                            continue;
+                        };
+                        metrics.client_disconnected(&username);
+                        hooks.disconnect(&username);
+                        client.send(ChatrMessage::Error { reason });
+                        client.send(ChatrMessage::Disconnect);
+                        drop(client);
+                        statuses.remove(&username);
+                        last_typing.remove(&username);
+                        remove_from_rooms(
+                            &mut clients,
+                            &mut rooms,
+                            &mut log,
+                            username,
+                            "was kicked",
+                        )
+                        .await;
+                        let _ = reply.send(true);
+                    }
+                    AdminMsg::Broadcast(content) => {
+                        let occupied: Vec<RoomName> = rooms
+                            .list()
+                            .into_iter()
+                            .filter(|room| !room.members.is_empty())
+                            .map(|room| room.name)
+                            .collect();
+                        for room in occupied {
+                            let notice = system_notice(&mut log, &room, content.clone());
+                            send_to_room(&clients, &rooms, &room, notice).await;
+                        }
+                    }
+                    AdminMsg::Stats(reply) => {
+                        let _ = reply.send(ServerStats {
+                            uptime_secs: started.elapsed().as_secs(),
+                            clients: clients.len(),
+                            rooms: rooms.list().len(),
+                            messages: log.len(),
+                        });
+                    }
+                    AdminMsg::Shutdown(reply) => {
+                        let reason = "the server is shutting down".to_string();
+                        send_to_clients(&mut clients, ChatrMessage::Error { reason }).await;
+                        send_to_clients(&mut clients, ChatrMessage::Disconnect).await;
+                        for username in clients.keys() {
+                            hooks.disconnect(username);
+                        }
+                        clients.clear();
+                        metrics.clients_cleared();
+                        save_rooms(&rooms).await;
+                        let _ = reply.send(());
+                    }
                 }
             }
-        });
+        })
     }
     pub async fn add_new_client(
         &mut self,
@@ -75,46 +865,308 @@ impl Chatroom {
         let cancel_token = CancellationToken::new();
         self.clients.insert(
             client.username.clone(),
-            (cancel_token.clone(), sender_to_client),
+            ConnectedClient {
+                id: client.id,
+                session: cancel_token.clone(),
+                sender: sender_to_client,
+                metrics: self.metrics.clone(),
+            },
         );
         client.run(sender_to_server, receiver_from_server, cancel_token);
     }
-    pub async fn remove_client(
-        &mut self,
-        user: String,
-    ) -> Option<(CancellationToken, SenderToClient)> {
+    pub async fn remove_client(&mut self, user: String) -> Option<ConnectedClient> {
         self.clients.remove(&user)
     }
-    pub async fn dispatch_msg(&mut self, username: String, content: String) {
+    pub async fn dispatch_msg(&mut self, username: String, kind: MessageKind, content: String) {
         trace!("got msg from {username} to dispatch. content {content}");
-        let msg = ChatrMessage::ReceivedMessage { username, content };
-        for (_, (_, stc)) in self.clients.iter_mut() {
-            stc.send(msg.clone()).await.unwrap()
+        let message = self
+            .log
+            .append(DEFAULT_ROOM.to_string(), username, kind, content, None)
+            .clone();
+        let msg = ChatrMessage::ReceivedMessage { message };
+        for client in self.clients.values() {
+            client.send(msg.clone())
+        }
+    }
+}
+/// Turn what clients send into work for the chatroom, or for the file store in `files_send`,
+/// until every client's sender is gone or `ct` is cancelled. Everything but Disconnect goes
+/// through `hooks` first.
+pub fn route_client_messages(
+    mut receiver_from_clients: ReceiverFromClient,
+    admin_send: mpsc::Sender<AdminMsg>,
+    files_send: mpsc::Sender<(Username, SessionId, ChatrMessage)>,
+    hooks: Hooks,
+    ct: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        'route: loop {
+            let received = tokio::select! {
+                received = receiver_from_clients.recv() => received,
+                _ = ct.cancelled() => break,
+            };
+            let Some((user, session, msg)) = received else {
+                break;
+            };
+            tracing::trace!("recv from {user} msg {msg:?}");
+            let msg = match msg {
+                ChatrMessage::Disconnect => msg,
+                ChatrMessage::SentMessage { content, .. } if content.len() > MAX_CONTENT_LEN => {
+                    let reason = format!("messages can't be over {MAX_CONTENT_LEN} bytes");
+                    let msg = AdminMsg::SendTo(user, ChatrMessage::Error { reason });
+                    if admin_send.send(msg).await.is_err() {
+                        tracing::error!("chatroom is gone, no longer routing client messages");
+                        break;
+                    }
+                    continue;
+                }
+                msg => {
+                    // Only the server gets to send system notices, a hook can still make one
+                    let msg = match msg {
+                        ChatrMessage::SentMessage {
+                            kind: MessageKind::System,
+                            content,
+                            parent,
+                        } => ChatrMessage::SentMessage {
+                            kind: MessageKind::Normal,
+                            content,
+                            parent,
+                        },
+                        msg => msg,
+                    };
+                    let (msg, replies) = hooks.message(&user, msg);
+                    for reply in replies {
+                        if admin_send
+                            .send(AdminMsg::SendTo(user.clone(), reply))
+                            .await
+                            .is_err()
+                        {
+                            tracing::error!("chatroom is gone, no longer routing client messages");
+                            break 'route;
+                        }
+                    }
+                    let Some(msg) = msg else {
+                        continue;
+                    };
+                    msg
+                }
+            };
+            let admin_msg = match msg {
+                ChatrMessage::SentMessage {
+                    kind,
+                    content,
+                    parent,
+                } => AdminMsg::DispatchMsg(user, kind, content, parent),
+                ChatrMessage::Disconnect => {
+                    let msg = (user.clone(), session, ChatrMessage::Disconnect);
+                    send_to_files(&files_send, msg).await;
+                    AdminMsg::RemoveClient(user, session)
+                }
+                ChatrMessage::RosterRequest => AdminMsg::SendRoster(user),
+                ChatrMessage::SetStatus { status } => AdminMsg::SetStatus(user, status),
+                ChatrMessage::Typing => AdminMsg::Typing(user),
+                ChatrMessage::React { id, emoji } => AdminMsg::React(user, id, emoji),
+                ChatrMessage::Unreact { id, emoji } => AdminMsg::Unreact(user, id, emoji),
+                ChatrMessage::Search { query } => AdminMsg::Search(user, query),
+                ChatrMessage::Export { query } => AdminMsg::Export(user, query),
+                ChatrMessage::Join { room, password } => AdminMsg::Join(user, room, password),
+                ChatrMessage::SetAccess { access, password } => {
+                    AdminMsg::SetAccess(user, access, password)
+                }
+                ChatrMessage::Invite { username } => AdminMsg::Invite(user, username),
+                ChatrMessage::Revoke { username } => AdminMsg::Revoke(user, username),
+                ChatrMessage::SetRole { username, role } => AdminMsg::SetRole(user, username, role),
+                ChatrMessage::SetTopic { topic } => AdminMsg::SetTopic(user, topic),
+                ChatrMessage::RoomsRequest => AdminMsg::SendRooms(user),
+                ChatrMessage::PublishKey { key } => AdminMsg::PublishKey(user, key),
+                ChatrMessage::KeyRequest { username } => AdminMsg::KeyRequest(user, username),
+                ChatrMessage::SendDirect { to, sealed } => AdminMsg::SendDirect(user, to, sealed),
+                msg @ (ChatrMessage::UploadStart { .. }
+                | ChatrMessage::UploadChunk { .. }
+                | ChatrMessage::DownloadRequest { .. }) => {
+                    send_to_files(&files_send, (user, session, msg)).await;
+                    continue;
+                }
+                _ => continue,
+            };
+            if admin_send.send(admin_msg).await.is_err() {
+                tracing::error!("chatroom is gone, no longer routing client messages");
+                break;
+            }
+        }
+    })
+}
+/// Hand `msg` to the file store, chat carries on without it if it's gone
+async fn send_to_files(
+    files_send: &mpsc::Sender<(Username, SessionId, ChatrMessage)>,
+    msg: (Username, SessionId, ChatrMessage),
+) {
+    if files_send.send(msg).await.is_err() {
+        tracing::error!("file store is gone");
+    }
+}
+
+/// Log in everyone connecting to `listener`, handing those let in to the chatroom with their
+/// connections running on `connections`, until the returned task is aborted
+pub fn accept_clients(
+    listener: TcpListener,
+    sender_to_chatroom: SenderToServer,
+    admin_send: mpsc::Sender<AdminMsg>,
+    banned_usernames: BannedUsernames,
+    metrics: Arc<Metrics>,
+    audit: Audit,
+    connections: TaskTracker,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("accept failed {e}");
+                    continue;
+                }
+            };
+            let send_link = sender_to_chatroom.clone();
+            tracing::debug!("new socket {}", addr);
+            let maybe_new_client =
+                process_client_login(UnauthenticatedClient::new(socket), &banned_usernames).await;
+            let rejected = match maybe_new_client {
+                Ok(ClientLoginResult::Accept(new_client)) => {
+                    let user = new_client.username.clone();
+                    let (client_send, client_recv) = mpsc::channel(1024);
+                    let session = CancellationToken::new();
+                    let (reply, added) = oneshot::channel();
+                    let add = AdminMsg::AddClient(
+                        user.clone(),
+                        new_client.id,
+                        client_send,
+                        session.clone(),
+                        reply,
+                    );
+                    if admin_send.send(add).await.is_err() {
+                        tracing::error!("chatroom is gone, no longer taking clients");
+                        return;
+                    }
+                    if added.await.unwrap_or(false) {
+                        tracing::info!("adding client: {user}");
+                        audit.record(AuditEvent::Connect {
+                            peer: Some(addr),
+                            username: Some(user.clone()),
+                            accepted: true,
+                            reason: None,
+                        });
+                        let mut new_client = new_client
+                            .with_metrics(metrics.clone())
+                            .with_audit(audit.clone())
+                            .with_tasks(connections.clone());
+                        // What the chatroom already sent waits in client_send until this is out
+                        if let Err(e) = new_client.login_accepted().await {
+                            tracing::error!("{user} went before login finished {e}");
+                            session.cancel();
+                        }
+                        tracing::debug!("run {user}");
+                        new_client.run(send_link, client_recv, session);
+                        continue;
+                    }
+                    let reason = format!("{user} is already logged in");
+                    (new_client.socket, user, reason, "in_use")
+                }
+                Ok(ClientLoginResult::Reject {
+                    socket,
+                    username,
+                    reason,
+                    cause,
+                }) => (socket, username, reason, cause),
+                Err(e) => {
+                    metrics.login_rejected(match e.kind() {
+                        io::ErrorKind::InvalidData => "invalid",
+                        _ => "io_error",
+                    });
+                    audit.record(AuditEvent::Connect {
+                        peer: Some(addr),
+                        username: None,
+                        accepted: false,
+                        reason: Some(e.to_string()),
+                    });
+                    tracing::error!("fucked up {e}");
+                    continue;
+                }
+            };
+            let (mut socket, username, reason, cause) = rejected;
+            metrics.login_rejected(cause);
+            audit.record(AuditEvent::Connect {
+                peer: Some(addr),
+                username: Some(username.clone()),
+                accepted: false,
+                reason: Some(cause.to_string()),
+            });
+            let reject = ChatrMessage::LoginRejected { reason };
+            if let Err(e) = frame::write_message(&mut socket, &reject).await {
+                tracing::debug!("couldn't tell {username} they were rejected {e}");
+            }
         }
+    })
+}
+/// Why `username` can't be anyone's name, if it can't
+pub fn check_username(username: &str) -> Result<(), String> {
+    if username.trim().is_empty() {
+        Err("usernames can't be empty".to_string())
+    } else if username.eq_ignore_ascii_case(SYSTEM_USERNAME) {
+        Err(format!("{username} is taken by the server"))
+    } else if username.chars().count() > MAX_USERNAME_LEN {
+        Err(format!(
+            "usernames are at most {MAX_USERNAME_LEN} characters"
+        ))
+    } else if username
+        .chars()
+        .any(|c| c.is_control() || c.is_whitespace())
+    {
+        Err("usernames can't have spaces or control characters".to_string())
+    } else {
+        Ok(())
     }
 }
 #[instrument(level = "debug", skip(new_client))]
 pub async fn process_client_login(
     mut new_client: UnauthenticatedClient,
-    banned_usernames: &Arc<HashSet<String>>,
+    banned_usernames: &BannedUsernames,
 ) -> io::Result<ClientLoginResult> {
     let login_request = new_client.login_request().await?;
     info!(?login_request);
     let UnauthenticatedClient(socket, buf) = new_client;
     match login_request {
         ChatrMessage::LoginRequest { username } => {
-            if !banned_usernames.contains(&username) {
+            if let Err(reason) = check_username(&username) {
+                Ok(ClientLoginResult::Reject {
+                    socket,
+                    username,
+                    reason,
+                    cause: "bad_name",
+                })
+            } else if banned_usernames.read().unwrap().contains(&username) {
+                Ok(ClientLoginResult::Reject {
+                    socket,
+                    reason: format!("{username} is not allowed"),
+                    username,
+                    cause: "banned",
+                })
+            } else {
                 trace!("verif login {username}");
                 Ok(ClientLoginResult::Accept(AuthenticatedClient {
                     socket,
                     buf,
                     username,
+                    id: next_session(),
+                    metrics: Arc::default(),
+                    audit: Audit::default(),
+                    tasks: TaskTracker::new(),
                 }))
-            } else {
-                Ok(ClientLoginResult::Reject { socket, username })
             }
         }
-        _ => todo!(),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a login request",
+        )),
     }
 }
 
@@ -123,6 +1175,10 @@ pub enum ClientLoginResult {
     Reject {
         socket: TcpStream,
         username: Username,
+        /// What they're told
+        reason: String,
+        /// Short label for metrics and the audit log, like "banned"
+        cause: &'static str,
     },
 }
 
@@ -132,16 +1188,36 @@ pub struct AuthenticatedClient {
     socket: TcpStream,
     buf: bytes::BytesMut,
     pub username: String,
+    /// Which login this is
+    pub id: SessionId,
+    metrics: Arc<Metrics>,
+    audit: Audit,
+    /// Where the connection's tasks are spawned
+    tasks: TaskTracker,
+}
+/// Id for a new login, never the same twice while the server runs
+fn next_session() -> SessionId {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
 }
 impl AuthenticatedClient {
-    pub async fn login_accepted(&mut self) -> io::Result<usize> {
-        self.socket
-            .write(
-                borsh::to_vec(&ChatrMessage::LoginAccepted)
-                    .unwrap()
-                    .as_slice(),
-            )
-            .await
+    /// Record when this connection ends in `audit`
+    pub fn with_audit(mut self, audit: Audit) -> Self {
+        self.audit = audit;
+        self
+    }
+    /// Count what goes over this connection in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    /// Spawn the connection's tasks on `tasks`, so they can be waited for
+    pub fn with_tasks(mut self, tasks: TaskTracker) -> Self {
+        self.tasks = tasks;
+        self
+    }
+    pub async fn login_accepted(&mut self) -> io::Result<()> {
+        frame::write_message(&mut self.socket, &ChatrMessage::LoginAccepted).await
     }
     #[instrument(level = "debug", skip_all)]
     pub fn run(
@@ -154,11 +1230,18 @@ impl AuthenticatedClient {
             socket,
             mut buf,
             username,
+            id,
+            metrics,
+            audit,
+            tasks,
         } = self;
+        let peer = socket.peer_addr().ok();
+        let connected_at = Instant::now();
         let (mut socket_reader, mut socket_writer) = socket.into_split();
         let u = username.clone();
         let ct_one = cancel_token.clone();
-        tokio::spawn(async move {
+        let writer_metrics = metrics.clone();
+        tasks.spawn(async move {
             tracing::debug!("spawn recv loop");
             loop {
                 tokio::select! {
@@ -166,45 +1249,76 @@ impl AuthenticatedClient {
                         info!("{} cancel", u);
                         break;
                     }
-                    Some(msg) = rx.recv() => {
+                    msg = rx.recv() => {
+                        // The chatroom let go of us, like when kicked
+                        let Some(msg) = msg else {
+                            break;
+                        };
                         trace!("{} recv from server {:?}", u, msg);
-                        socket_writer
-                            .write_all(&borsh::to_vec(&msg).unwrap())
-                            .await
-                            .unwrap();
-                        socket_writer.flush().await.unwrap();
+                        if let Err(e) = frame::write_message(&mut socket_writer, &msg).await {
+                            tracing::error!("{} send failed {e}", u);
+                            writer_metrics.dropped(1 + rx.len());
+                            break;
+                        }
+                        writer_metrics.message_out(frame::frame_len(&msg));
                     }
                 }
             }
+            // Stop reading too, nothing they send would go anywhere
+            ct_one.cancel();
             info!("end writer: {}", u);
         });
-        tokio::spawn(async move {
+        tasks.spawn(async move {
+            // How the connection ended, when it wasn't the server letting go of them
+            let mut ended = None;
             loop {
                 tracing::debug!("spawn send loop");
                 tokio::select! {
+                    biased;
                     _ = cancel_token.cancelled() => {
                         info!("{} cancel", username);
+                        // However it ended, the chatroom needs to hear they're gone
+                        tx.send((username.clone(), id, ChatrMessage::Disconnect))
+                            .await
+                            .unwrap_or_else(|x| tracing::error!(username, ?x));
+                        audit.record(AuditEvent::Disconnect {
+                            peer,
+                            username: username.clone(),
+                            connected_secs: connected_at.elapsed().as_secs(),
+                            reason: ended.take().unwrap_or("dropped by the server".to_string()),
+                        });
                         break;
                     }
-                bytes_read = socket_reader.read(&mut buf) => {
+                    received = frame::read_message(&mut socket_reader, &mut buf) => {
                         tracing::trace!("recv msg");
-                        match bytes_read {
-                            Ok(n) => {
-                                let msg = if n!=0 {
-                                    trace!("{} recv from client {:?}", username, &buf[..n]);
-                                    ChatrMessage::deserialize(&mut &buf[..n]).unwrap()
-                                } else {
-                                    cancel_token.cancel();
-                                    ChatrMessage::Disconnect
-                                };
-                                tracing::trace!(username, ?msg);
-                                tx.send((username.clone(), msg))
-                                    .await
-                                    .unwrap_or_else(|x| tracing::error!(username, ?x));
+                        let msg = match received {
+                            // Said goodbye, handled below like any other ending so the
+                            // chatroom only hears it once
+                            Ok(Some(ChatrMessage::Disconnect)) => {
+                                ended = Some("logged out".to_string());
+                                cancel_token.cancel();
+                                continue;
                             }
-                            Err(_) => todo!(),
-                        }
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => {
+                                ended = Some("closed".to_string());
+                                cancel_token.cancel();
+                                continue;
+                            }
+                            Err(e) => {
+                                tracing::error!(username, "bad message {e}");
+                                ended = Some(format!("error: {e}"));
+                                cancel_token.cancel();
+                                continue;
+                            }
+                        };
+                        metrics.message_in(frame::frame_len(&msg));
+                        tracing::trace!(username, ?msg);
+                        if let Err(x) = tx.send((username.clone(), id, msg)).await {
+                            metrics.dropped(1);
+                            tracing::error!(username, ?x);
                         }
+                    }
 
                 }
             }
@@ -216,25 +1330,18 @@ impl AuthenticatedClient {
 pub struct UnauthenticatedClient(TcpStream, bytes::BytesMut);
 impl UnauthenticatedClient {
     pub fn new(stream: TcpStream) -> Self {
-        Self(stream, bytes::BytesMut::zeroed(1024))
+        Self(stream, bytes::BytesMut::new())
     }
     pub async fn login_request(&mut self) -> Result<ChatrMessage, std::io::Error> {
         tracing::debug!("login_request");
-        self.0.read(&mut self.1).await.and_then(|n| {
-            if n != 0 {
-                ChatrMessage::deserialize(&mut &self.1[..n])
-                    .inspect(|msg| tracing::trace!("{msg:?}"))
-            } else {
-                Ok(ChatrMessage::Disconnect)
-            }
-        })
+        frame::read_message(&mut self.0, &mut self.1)
+            .await
+            .map(|msg| msg.unwrap_or(ChatrMessage::Disconnect))
+            .inspect(|msg| tracing::trace!("{msg:?}"))
     }
     pub async fn on_fail(mut self, reason: String) {
         let msg = ChatrMessage::LoginRejected { reason };
-        self.0
-            .write_all(borsh::to_vec(&msg).unwrap().as_slice())
-            .await
-            .unwrap();
+        frame::write_message(&mut self.0, &msg).await.unwrap();
     }
     pub async fn on_accept(self, username: String) -> AuthenticatedClient {
         let Self(socket, buf) = self;
@@ -242,6 +1349,10 @@ impl UnauthenticatedClient {
             socket,
             buf,
             username,
+            id: next_session(),
+            metrics: Arc::default(),
+            audit: Audit::default(),
+            tasks: TaskTracker::new(),
         }
     }
 }
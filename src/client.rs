@@ -1,97 +1,442 @@
 use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use borsh::BorshDeserialize;
 use bytes::BytesMut;
+use futures_core::Stream;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{self, Receiver, Sender},
+    task::JoinHandle,
 };
 use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
-use crate::{ChatrMessage, Username};
+use crate::{
+    ChatMessage, ChatrMessage, Content, MessageId, MessageKind, Status, Timestamp, Username,
+    e2e::Keyring,
+    frame,
+    room::{RoomInfo, RoomName},
+};
+
+/// How long to wait for the server to close the connection after saying goodbye
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Struct used by clients to represent the connection to the server
 pub struct ClientConnection {
     pub stream: TcpStream,
+    /// Received bytes not yet making up a whole message
     pub buf: bytes::BytesMut,
+    /// Seals and opens direct messages, without one they can't be sent or read
+    keyring: Option<Arc<Mutex<Keyring>>>,
 }
 
 impl ClientConnection {
     pub async fn new(host: &str) -> io::Result<Self> {
         TcpStream::connect(host).await.map(|stream| Self {
             stream,
-            buf: BytesMut::zeroed(1024),
+            buf: BytesMut::new(),
+            keyring: None,
         })
     }
+    /// Handle direct messages with `keyring`, publishing its key once running: Whisper gets
+    /// sealed before it goes out and what arrives comes back opened as Whispered
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(Arc::new(Mutex::new(keyring)));
+        self
+    }
+    /// Keyring shared with the running connection, for showing and verifying fingerprints
+    pub fn keyring(&self) -> Option<Arc<Mutex<Keyring>>> {
+        self.keyring.clone()
+    }
 
     pub async fn login(&mut self, username: Username) -> io::Result<()> {
-        self.stream
-            .write_all(
-                borsh::to_vec(&ChatrMessage::LoginRequest { username })
-                    .unwrap()
-                    .as_slice(),
-            )
-            .await?;
-        self.stream.flush().await.unwrap();
-        match self.stream.read(&mut self.buf).await? {
-            0 => Err(io::Error::new(
+        frame::write_message(&mut self.stream, &ChatrMessage::LoginRequest { username }).await?;
+        // Anything sent right behind the reply stays in buf for run
+        match frame::read_message(&mut self.stream, &mut self.buf).await? {
+            None => Err(io::Error::new(
                 io::ErrorKind::ConnectionRefused,
-                "0 bytes on login",
+                "closed on login",
+            )),
+            Some(ChatrMessage::LoginAccepted) => Ok(()),
+            Some(ChatrMessage::LoginRejected { reason }) => {
+                Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason))
+            }
+            Some(ChatrMessage::Disconnect) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "recv disconnect",
+            )),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "unreasonable msg",
             )),
-            n => match ChatrMessage::deserialize(&mut &self.buf[..n]) {
-                Ok(login_msg) => match login_msg {
-                    ChatrMessage::LoginAccepted => Ok(()),
-                    ChatrMessage::LoginRejected { reason } => {
-                        Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason))
-                    }
-                    ChatrMessage::Disconnect => Err(io::Error::new(
-                        io::ErrorKind::ConnectionRefused,
-                        "recv disconnect",
-                    )),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::ConnectionRefused,
-                        "unreasonable msg",
-                    )),
-                },
-                Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
-            },
         }
     }
+    /// Pass messages between the app and the server until one of them goes away. Cancelling
+    /// `ct`, or dropping the sender of `to_server_from_client`, logs out: Disconnect goes to the
+    /// server and the tasks end once it closes the connection, or after [`CLOSE_TIMEOUT`].
     #[instrument(level = "debug", skip_all)]
     pub fn run(
         self,
         from_server_to_client: Sender<ChatrMessage>,
         mut to_server_from_client: Receiver<ChatrMessage>,
-        _ct: CancellationToken,
-    ) {
-        let ClientConnection { stream, mut buf } = self;
+        ct: CancellationToken,
+    ) -> ConnectionTasks {
+        let ClientConnection {
+            stream,
+            mut buf,
+            keyring,
+        } = self;
         let (mut stream_reader, mut stream_writer) = stream.into_split();
-        tokio::spawn(async move {
-            while let Some(msg_to_send) = to_server_from_client.recv().await {
+        // Lets the reader send too, like asking for a key to open a message with
+        let (to_server, mut from_reader) = mpsc::channel(64);
+        let reader_keyring = keyring.clone();
+        let to_client = from_server_to_client.clone();
+        // Cancelled once we've said goodbye, by the writer when the app went away without one
+        let closing = ct.child_token();
+        let reader_closing = closing.clone();
+        let writer = tokio::spawn(async move {
+            if let Some(keyring) = &keyring {
+                let key = keyring.lock().unwrap().public();
+                let publish = ChatrMessage::PublishKey { key };
+                if let Err(e) = frame::write_message(&mut stream_writer, &publish).await {
+                    tracing::error!("sending to server failed {e}");
+                    return;
+                }
+            }
+            loop {
+                // Whatever the app sent before logging out still goes
+                let msg_to_send = tokio::select! {
+                    biased;
+                    msg = to_server_from_client.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    msg = from_reader.recv() => match msg {
+                        Some(msg) => msg,
+                        // The server went away, the reader has let the app know
+                        None => return,
+                    },
+                    _ = closing.cancelled() => break,
+                };
                 tracing::trace!("{msg_to_send:?}");
-                stream_writer
-                    .write_all(&borsh::to_vec(&msg_to_send).unwrap())
-                    .await
-                    .unwrap();
-                stream_writer.flush().await.unwrap();
+                let (msgs, replies) = match (msg_to_send, &keyring) {
+                    (ChatrMessage::Whisper { to, content }, Some(keyring)) => {
+                        keyring.lock().unwrap().whisper(to, content)
+                    }
+                    (ChatrMessage::KeyRequest { username }, Some(keyring)) => (
+                        vec![keyring.lock().unwrap().request_key(username)],
+                        Vec::new(),
+                    ),
+                    (ChatrMessage::Whisper { .. }, None) => {
+                        let reason = "no keys to send direct messages with".to_string();
+                        (Vec::new(), vec![ChatrMessage::Error { reason }])
+                    }
+                    (msg, _) => (vec![msg], Vec::new()),
+                };
+                for reply in replies {
+                    let _ = to_client.send(reply).await;
+                }
+                for msg in msgs {
+                    if let Err(e) = frame::write_message(&mut stream_writer, &msg).await {
+                        tracing::error!("sending to server failed {e}");
+                        return;
+                    }
+                }
+            }
+            closing.cancel();
+            if let Err(e) =
+                frame::write_message(&mut stream_writer, &ChatrMessage::Disconnect).await
+            {
+                tracing::error!("saying goodbye failed {e}");
             }
+            // Dropping the write half tells the server we're done sending
         });
-        tokio::spawn(async move {
+        let reader = tokio::spawn(async move {
+            let closed = async {
+                reader_closing.cancelled().await;
+                tokio::time::sleep(CLOSE_TIMEOUT).await;
+            };
+            tokio::pin!(closed);
             loop {
-                match stream_reader.read(&mut buf).await {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        from_server_to_client
-                            .send(ChatrMessage::deserialize(&mut &buf[..n]).unwrap())
-                            .await
-                            .unwrap();
+                let received = tokio::select! {
+                    received = frame::read_message(&mut stream_reader, &mut buf) => received,
+                    _ = &mut closed => {
+                        tracing::warn!("server didn't close the connection");
+                        break;
+                    }
+                };
+                let msg = match received {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::error!("reading from server failed {e}");
+                        break;
+                    }
+                };
+                // Logging out, nobody's waiting for anything but the end
+                if reader_closing.is_cancelled() {
+                    continue;
+                }
+                let (msgs, replies) = match (msg, &reader_keyring) {
+                    (
+                        ChatrMessage::ReceivedDirect {
+                            from,
+                            sent_at,
+                            sealed,
+                        },
+                        Some(keyring),
+                    ) => keyring.lock().unwrap().received(from, sent_at, sealed),
+                    (ChatrMessage::PeerKey { username, key }, Some(keyring)) => {
+                        keyring.lock().unwrap().peer_key(username, key)
+                    }
+                    (msg, _) => (Vec::new(), vec![msg]),
+                };
+                for msg in msgs {
+                    let _ = to_server.send(msg).await;
+                }
+                for reply in replies {
+                    if from_server_to_client.send(reply).await.is_err() {
+                        // Nobody's listening any more
+                        reader_closing.cancel();
+                        break;
                     }
-                    Err(_) => todo!(),
                 }
             }
         });
         tracing::info!("run called");
+        ConnectionTasks { writer, reader }
+    }
+}
+
+/// Tasks of a running [`ClientConnection`]
+#[derive(Debug)]
+pub struct ConnectionTasks {
+    pub writer: JoinHandle<()>,
+    pub reader: JoinHandle<()>,
+}
+
+impl ConnectionTasks {
+    /// Wait for both tasks to end
+    pub async fn finished(self) {
+        let _ = self.writer.await;
+        let _ = self.reader.await;
+    }
+}
+
+/// Who to log in as
+pub struct Credentials {
+    pub username: Username,
+    /// Keys for direct messages, they can't be sent or read without
+    pub keyring: Option<Keyring>,
+}
+
+impl Credentials {
+    pub fn new(username: impl Into<Username>) -> Self {
+        Self {
+            username: username.into(),
+            keyring: None,
+        }
+    }
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+}
+
+/// Something from the server, as [`ChatrClient`] hands it out
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// Chat in the room we're in
+    Message(ChatMessage),
+    /// Messages from before we joined a room, oldest first
+    History(Vec<ChatMessage>),
+    /// Direct message, opened, or one we sent
+    Direct {
+        from: Username,
+        to: Username,
+        sent_at: Timestamp,
+        content: Content,
+        /// The other side's key has been checked
+        verified: bool,
+    },
+    UserConnected(Username),
+    UserDisconnected(Username),
+    /// Everyone connected with their status
+    Roster(Vec<(Username, Status)>),
+    StatusChanged(Username, Status),
+    /// Now in this room
+    Joined(RoomInfo),
+    /// Server's message of the day
+    Motd(String),
+    /// Something we asked for couldn't be done
+    Error(String),
+    /// The server ended the session, nothing else is coming
+    Disconnected,
+    /// Anything else, like search results and file transfers
+    Other(ChatrMessage),
+}
+
+impl From<ChatrMessage> for ClientEvent {
+    fn from(msg: ChatrMessage) -> Self {
+        match msg {
+            ChatrMessage::ReceivedMessage { message } => ClientEvent::Message(message),
+            ChatrMessage::History { messages } => ClientEvent::History(messages),
+            ChatrMessage::Whispered {
+                from,
+                to,
+                sent_at,
+                content,
+                verified,
+            } => ClientEvent::Direct {
+                from,
+                to,
+                sent_at,
+                content,
+                verified,
+            },
+            ChatrMessage::UserConnected { username } => ClientEvent::UserConnected(username),
+            ChatrMessage::UserDisconnected { username } => ClientEvent::UserDisconnected(username),
+            ChatrMessage::Roster { members } => ClientEvent::Roster(members),
+            ChatrMessage::StatusChanged { username, status } => {
+                ClientEvent::StatusChanged(username, status)
+            }
+            ChatrMessage::Joined { room } => ClientEvent::Joined(room),
+            ChatrMessage::Motd { text } => ClientEvent::Motd(text),
+            ChatrMessage::Error { reason } => ClientEvent::Error(reason),
+            ChatrMessage::Disconnect => ClientEvent::Disconnected,
+            msg => ClientEvent::Other(msg),
+        }
+    }
+}
+
+/// Logged in connection to a server, sending with its methods and handing out what arrives as
+/// a [`Stream`] of [`ClientEvent`]s. Says goodbye to the server when dropped or when its
+/// [`CancellationToken`] is cancelled, after which the stream ends.
+pub struct ChatrClient {
+    username: Username,
+    to_server: Sender<ChatrMessage>,
+    events: Receiver<ChatrMessage>,
+    keyring: Option<Arc<Mutex<Keyring>>>,
+    ct: CancellationToken,
+    tasks: ConnectionTasks,
+}
+
+impl ChatrClient {
+    pub async fn connect(host: &str, credentials: Credentials) -> io::Result<Self> {
+        let Credentials { username, keyring } = credentials;
+        let mut connection = ClientConnection::new(host).await?;
+        if let Some(keyring) = keyring {
+            connection = connection.with_keyring(keyring);
+        }
+        let keyring = connection.keyring();
+        connection.login(username.clone()).await?;
+        let (to_client, events) = mpsc::channel(1024);
+        let (to_server, from_client) = mpsc::channel(1024);
+        let ct = CancellationToken::new();
+        let tasks = connection.run(to_client, from_client, ct.clone());
+        Ok(Self {
+            username,
+            to_server,
+            events,
+            keyring,
+            ct,
+            tasks,
+        })
+    }
+    pub fn username(&self) -> &Username {
+        &self.username
+    }
+    /// Keyring shared with the connection, for showing and verifying fingerprints
+    pub fn keyring(&self) -> Option<Arc<Mutex<Keyring>>> {
+        self.keyring.clone()
+    }
+    /// Cancelling it disconnects, like dropping the client
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.ct.clone()
+    }
+    /// Next thing from the server, `None` once disconnected
+    pub async fn next_event(&mut self) -> Option<ClientEvent> {
+        self.events.recv().await.map(ClientEvent::from)
+    }
+    /// Send anything, the other methods are shorthands for this
+    pub async fn send(&self, msg: ChatrMessage) -> io::Result<()> {
+        self.to_server
+            .send(msg)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "disconnected"))
+    }
+    /// Plain chat in the room we're in
+    pub async fn send_message(&self, content: impl Into<Content>) -> io::Result<()> {
+        self.send(ChatrMessage::SentMessage {
+            kind: MessageKind::Normal,
+            content: content.into(),
+            parent: None,
+        })
+        .await
+    }
+    /// Answer message `parent` in a thread
+    pub async fn reply(&self, parent: MessageId, content: impl Into<Content>) -> io::Result<()> {
+        self.send(ChatrMessage::SentMessage {
+            kind: MessageKind::Normal,
+            content: content.into(),
+            parent: Some(parent),
+        })
+        .await
+    }
+    /// Move to `room`, `password` for password protected rooms
+    pub async fn join(
+        &self,
+        room: impl Into<RoomName>,
+        password: Option<String>,
+    ) -> io::Result<()> {
+        self.send(ChatrMessage::Join {
+            room: room.into(),
+            password,
+        })
+        .await
+    }
+    /// Encrypted direct message, needs a keyring in the credentials
+    pub async fn dm(&self, to: impl Into<Username>, content: impl Into<Content>) -> io::Result<()> {
+        self.send(ChatrMessage::Whisper {
+            to: to.into(),
+            content: content.into(),
+        })
+        .await
+    }
+    /// Mark the key of `username` as checked, taking their new one if it changed, and send or
+    /// open what was held waiting on it. False if we have no key for them
+    pub async fn verify(&self, username: &str) -> io::Result<bool> {
+        let Some(keyring) = &self.keyring else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no keys"));
+        };
+        if !keyring.lock().unwrap().set_verified(username, true)? {
+            return Ok(false);
+        }
+        self.send(ChatrMessage::KeyRequest {
+            username: username.to_string(),
+        })
+        .await?;
+        Ok(true)
+    }
+    pub async fn set_status(&self, status: Status) -> io::Result<()> {
+        self.send(ChatrMessage::SetStatus { status }).await
+    }
+    /// Say goodbye and wait for the server to close the connection
+    pub async fn disconnect(self) {
+        self.ct.cancel();
+        self.tasks.finished().await;
+    }
+}
+
+impl Stream for ChatrClient {
+    type Item = ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ClientEvent>> {
+        self.events
+            .poll_recv(cx)
+            .map(|msg| msg.map(ClientEvent::from))
     }
 }
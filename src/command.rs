@@ -0,0 +1,153 @@
+use std::path::PathBuf;
+
+use crate::{
+    ChatrMessage, MessageId, MessageKind, Presence, Status,
+    export::{self, ExportFormat, ExportQuery},
+    room::{Access, Role},
+    search::SearchQuery,
+};
+
+/// Commands clients carry out themselves rather than turning into a single message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileCommand {
+    /// `/upload path` shares a file
+    Upload(PathBuf),
+    /// `/download id` saves the file shared in message `id`
+    Download(MessageId),
+    /// `/export format path [filters]` saves the history matching the filters to `path`
+    Export(ExportFormat, PathBuf, ExportQuery),
+}
+
+/// The file command `line` is, if it is one
+pub fn parse_file_command(line: &str) -> Option<FileCommand> {
+    let (command, rest) = line.split_once(' ')?;
+    let rest = rest.trim();
+    match command {
+        "/upload" if !rest.is_empty() => Some(FileCommand::Upload(PathBuf::from(rest))),
+        "/download" => rest.parse().ok().map(FileCommand::Download),
+        "/export" => export::parse_export(rest)
+            .ok()
+            .map(|(format, path, query)| FileCommand::Export(format, path, query)),
+        _ => None,
+    }
+}
+
+/// Turn a line typed into a client into the message to send
+///
+/// Lines starting with one of these commands become requests, anything else is sent as is:
+/// - `/who` asks for the roster
+/// - `/away [text]`, `/busy [text]` and `/back [text]` set your status
+/// - `/me text` sends an action and `/notice text` a notice
+/// - `/reply id text` replies to the message with that id
+/// - `/react id emoji` and `/unreact id emoji` add and take back reactions
+/// - `/search text` looks through the history, see [`SearchQuery::parse`] for filters
+/// - `/join room [password]` moves to another room and `/rooms` lists them
+/// - `/access public|invite|password pw`, `/invite user`, `/revoke user`, `/op user` and
+///   `/deop user` manage who can get into the room you're in
+/// - `/topic text` sets the topic of the room you're in, `/topic` alone clears it
+/// - `/msg user text` sends an encrypted direct message
+pub fn parse_input(line: &str) -> ChatrMessage {
+    let (command, rest) = match line.split_once(' ') {
+        Some((command, rest)) => (command, rest.trim()),
+        None => (line, ""),
+    };
+    let text = (!rest.is_empty()).then(|| rest.to_string());
+    let set_status = |presence| ChatrMessage::SetStatus {
+        status: Status { presence, text },
+    };
+    let send = |kind, content: &str| ChatrMessage::SentMessage {
+        kind,
+        content: content.to_string(),
+        parent: None,
+    };
+    match command {
+        "/who" => ChatrMessage::RosterRequest,
+        "/rooms" => ChatrMessage::RoomsRequest,
+        "/join" if !rest.is_empty() => {
+            let (room, password) = match rest.split_once(' ') {
+                Some((room, password)) => (room, Some(password.trim().to_string())),
+                None => (rest, None),
+            };
+            ChatrMessage::Join {
+                room: room.to_string(),
+                password,
+            }
+        }
+        "/access" => match rest.split_once(' ').unwrap_or((rest, "")) {
+            ("public", "") => ChatrMessage::SetAccess {
+                access: Access::Public,
+                password: None,
+            },
+            ("invite", "") => ChatrMessage::SetAccess {
+                access: Access::InviteOnly,
+                password: None,
+            },
+            ("password", password) if !password.trim().is_empty() => ChatrMessage::SetAccess {
+                access: Access::Password,
+                password: Some(password.trim().to_string()),
+            },
+            _ => send(MessageKind::Normal, line),
+        },
+        "/invite" | "/revoke" | "/op" | "/deop" if !rest.is_empty() && !rest.contains(' ') => {
+            let username = rest.trim_start_matches('@').to_string();
+            match command {
+                "/invite" => ChatrMessage::Invite { username },
+                "/revoke" => ChatrMessage::Revoke { username },
+                "/op" => ChatrMessage::SetRole {
+                    username,
+                    role: Some(Role::Operator),
+                },
+                _ => ChatrMessage::SetRole {
+                    username,
+                    role: None,
+                },
+            }
+        }
+        "/topic" => ChatrMessage::SetTopic {
+            topic: (!rest.is_empty()).then(|| rest.to_string()),
+        },
+        "/away" => set_status(Presence::Away),
+        "/busy" => set_status(Presence::Busy),
+        "/back" => set_status(Presence::Online),
+        "/me" if !rest.is_empty() => send(MessageKind::Action, rest),
+        "/notice" if !rest.is_empty() => send(MessageKind::Notice, rest),
+        "/reply" => match with_id(rest) {
+            Some((id, content)) => ChatrMessage::SentMessage {
+                kind: MessageKind::Normal,
+                content,
+                parent: Some(id),
+            },
+            None => send(MessageKind::Normal, line),
+        },
+        "/react" => match with_id(rest) {
+            Some((id, emoji)) => ChatrMessage::React { id, emoji },
+            None => send(MessageKind::Normal, line),
+        },
+        "/unreact" => match with_id(rest) {
+            Some((id, emoji)) => ChatrMessage::Unreact { id, emoji },
+            None => send(MessageKind::Normal, line),
+        },
+        "/msg" => match rest.split_once(' ') {
+            Some((to, content)) if !content.trim().is_empty() => ChatrMessage::Whisper {
+                to: to.trim_start_matches('@').to_string(),
+                content: content.trim().to_string(),
+            },
+            _ => send(MessageKind::Normal, line),
+        },
+        "/search" => match SearchQuery::parse(rest) {
+            Some(query) => ChatrMessage::Search { query },
+            None => send(MessageKind::Normal, line),
+        },
+        _ => send(MessageKind::Normal, line),
+    }
+}
+
+/// Message id followed by some text
+fn with_id(rest: &str) -> Option<(MessageId, String)> {
+    let (id, text) = rest.split_once(' ')?;
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    Some((id.parse().ok()?, text.to_string()))
+}
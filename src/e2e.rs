@@ -0,0 +1,559 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, AeadCore, Payload},
+};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, StaticSecret};
+
+use crate::{ChatrMessage, Content, Timestamp, Username, files, time};
+
+/// Env var for where identity keys and the keys of others are kept
+pub const KEYS_ENV: &str = "CHATR_KEYS";
+/// Where keys are kept when [`KEYS_ENV`] isn't set
+pub const DEFAULT_KEYS_DIR: &str = ".chatr_keys";
+/// Mixed into every derived key so they can't be mistaken for keys used anywhere else
+const KDF_INFO: &[u8] = b"chatr dm v1";
+/// How far a direct message's own time can be from when it arrived before it's taken for a
+/// replay, clocks being a little off is fine
+pub const MAX_DM_SKEW: Duration = Duration::from_secs(10 * 60);
+/// Direct messages held for one user while their key is sorted out, more are turned away
+pub const MAX_WAITING: usize = 100;
+
+/// X25519 public key
+pub type PublicKey = [u8; 32];
+
+/// Direct message only its recipient can open, the server just passes it along
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct Sealed {
+    /// Sender's one off key for this message
+    pub ephemeral: PublicKey,
+    pub nonce: [u8; 12],
+    /// When the sender sealed it, authenticated with the content unlike the server's time
+    pub sealed_at: Timestamp,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Short form of a key for people to compare, like `3f2a 9c01 …`
+pub fn fingerprint(key: &PublicKey) -> String {
+    let hash: [u8; 32] = Sha256::digest(key).into();
+    let hex = files::hex(&hash);
+    hex.as_bytes()[..32]
+        .chunks(4)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Key for one message from `from` to `to`, both halves of the exchange have to be right so
+/// only the holder of the sender's identity could have made it
+fn message_key(
+    ephemeral_dh: [u8; 32],
+    static_dh: [u8; 32],
+    from: (&str, &PublicKey),
+    to: (&str, &PublicKey),
+    ephemeral: &PublicKey,
+) -> chacha20poly1305::Key {
+    let mut ikm = [0; 64];
+    ikm[..32].copy_from_slice(&ephemeral_dh);
+    ikm[32..].copy_from_slice(&static_dh);
+    let mut info = KDF_INFO.to_vec();
+    for (name, key) in [from, to] {
+        info.extend_from_slice(name.as_bytes());
+        info.push(0);
+        info.extend_from_slice(key);
+    }
+    info.extend_from_slice(ephemeral);
+    let mut key = chacha20poly1305::Key::default();
+    Hkdf::<Sha256>::new(None, &ikm)
+        .expand(&info, &mut key)
+        .unwrap();
+    key
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Our own long lived key pair, what others seal direct messages to
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Identity({})", fingerprint(&self.public))
+    }
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+    /// Seal `content` from `from` (us) so only `to`, holding `their_key`, can open it
+    pub fn seal(
+        &self,
+        from: &str,
+        to: &str,
+        their_key: &PublicKey,
+        content: &str,
+    ) -> io::Result<Sealed> {
+        let their = x25519_dalek::PublicKey::from(*their_key);
+        let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = x25519_dalek::PublicKey::from(&ephemeral_secret).to_bytes();
+        let ephemeral_dh = ephemeral_secret.diffie_hellman(&their);
+        let static_dh = self.secret.diffie_hellman(&their);
+        if !ephemeral_dh.was_contributory() || !static_dh.was_contributory() {
+            return Err(invalid("bad key"));
+        }
+        let key = message_key(
+            ephemeral_dh.to_bytes(),
+            static_dh.to_bytes(),
+            (from, &self.public),
+            (to, their_key),
+            &ephemeral,
+        );
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed_at = time::now();
+        let payload = Payload {
+            msg: content.as_bytes(),
+            aad: &sealed_at.to_le_bytes(),
+        };
+        let ciphertext = ChaCha20Poly1305::new(&key)
+            .encrypt(&nonce, payload)
+            .map_err(|_| invalid("couldn't encrypt"))?;
+        Ok(Sealed {
+            ephemeral,
+            nonce: nonce.into(),
+            sealed_at,
+            ciphertext,
+        })
+    }
+    /// Open a message sealed to us, `to`, by `from` whose key is `their_key`
+    pub fn open(
+        &self,
+        from: &str,
+        to: &str,
+        their_key: &PublicKey,
+        sealed: &Sealed,
+    ) -> io::Result<Content> {
+        let ephemeral_dh = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(sealed.ephemeral));
+        let static_dh = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(*their_key));
+        if !ephemeral_dh.was_contributory() || !static_dh.was_contributory() {
+            return Err(invalid("bad key"));
+        }
+        let key = message_key(
+            ephemeral_dh.to_bytes(),
+            static_dh.to_bytes(),
+            (from, their_key),
+            (to, &self.public),
+            &sealed.ephemeral,
+        );
+        let payload = Payload {
+            msg: &sealed.ciphertext,
+            aad: &sealed.sealed_at.to_le_bytes(),
+        };
+        let plaintext = ChaCha20Poly1305::new(&key)
+            .decrypt(&sealed.nonce.into(), payload)
+            .map_err(|_| invalid("couldn't decrypt, wrong key or tampered with"))?;
+        String::from_utf8(plaintext).map_err(|_| invalid("not text"))
+    }
+}
+
+/// Someone else's key as we last saw it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownKey {
+    pub key: PublicKey,
+    /// Fingerprint checked with them some other way
+    pub verified: bool,
+    /// Different key the server has given for them since, nothing is sealed or opened until
+    /// it's verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed: Option<PublicKey>,
+}
+
+/// What [`Keyring::learn`] made of a key from the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Learned {
+    New,
+    Same,
+    /// Different from the one we had, held until it's verified
+    Changed,
+}
+
+/// Our identity and the keys of everyone we've messaged, pinned the first time we see them
+#[derive(Debug)]
+pub struct Keyring {
+    pub username: Username,
+    identity: Identity,
+    known: BTreeMap<Username, KnownKey>,
+    /// Where known keys are saved
+    path: Option<PathBuf>,
+    /// Direct messages waiting on the key of who they're to or from
+    waiting: HashMap<Username, Vec<Waiting>>,
+    /// Users whose key we've asked the server for since connecting, so changed keys get noticed
+    fresh: HashSet<Username>,
+    /// Users whose key we've asked for and not had yet, keys nobody asked for are ignored
+    requested: HashSet<Username>,
+    /// When and with what nonce each user's recent messages were sealed, so replays are caught
+    seen: HashMap<Username, BTreeSet<(Timestamp, [u8; 12])>>,
+}
+
+/// Direct message held until we have the other side's key
+#[derive(Debug)]
+enum Waiting {
+    Outgoing(Content),
+    /// With the server's time and when it got to us
+    Incoming(Timestamp, Timestamp, Sealed),
+}
+
+impl Keyring {
+    /// Keyring kept only in memory, a new identity every time
+    pub fn new(username: Username) -> Self {
+        Self {
+            username,
+            identity: Identity::generate(),
+            known: BTreeMap::new(),
+            path: None,
+            waiting: HashMap::new(),
+            fresh: HashSet::new(),
+            requested: HashSet::new(),
+            seen: HashMap::new(),
+        }
+    }
+    /// Identity for `username` kept in [`KEYS_ENV`], made the first time
+    pub fn from_env(username: Username) -> io::Result<Self> {
+        let dir = PathBuf::from(std::env::var_os(KEYS_ENV).unwrap_or(DEFAULT_KEYS_DIR.into()));
+        Self::load(dir, username)
+    }
+    /// Identity and known keys for `username` from `dir`, making a new identity if there's none
+    pub fn load(dir: PathBuf, username: Username) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        // Hex so every name gets its own files whatever characters it has
+        let name = files::hex(username.as_bytes());
+        let secret_path = dir.join(format!("{name}.key"));
+        let identity = match std::fs::read(&secret_path) {
+            Ok(bytes) => {
+                let bytes: [u8; 32] = bytes.try_into().map_err(|_| invalid("bad key file"))?;
+                Identity::from_secret(StaticSecret::from(bytes))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                write_private(&secret_path, identity.secret.as_bytes())?;
+                identity
+            }
+            Err(e) => return Err(e),
+        };
+        let path = dir.join(format!("{name}.known.json"));
+        let known = match std::fs::read_to_string(&path) {
+            Ok(known) => serde_json::from_str(&known)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            username,
+            identity,
+            known,
+            path: Some(path),
+            waiting: HashMap::new(),
+            fresh: HashSet::new(),
+            requested: HashSet::new(),
+            seen: HashMap::new(),
+        })
+    }
+    fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => std::fs::write(path, serde_json::to_string_pretty(&self.known)?),
+            None => Ok(()),
+        }
+    }
+    pub fn public(&self) -> PublicKey {
+        self.identity.public()
+    }
+    pub fn get(&self, username: &str) -> Option<&KnownKey> {
+        self.known.get(username)
+    }
+    /// Everyone whose key we have, by name
+    pub fn known(&self) -> impl Iterator<Item = (&Username, &KnownKey)> {
+        self.known.iter()
+    }
+    /// Pin a key the server says `username` has, a different one is held until it's verified
+    pub fn learn(&mut self, username: &Username, key: PublicKey) -> io::Result<Learned> {
+        let learned = match self.known.get_mut(username) {
+            None => {
+                let known = KnownKey {
+                    key,
+                    verified: false,
+                    changed: None,
+                };
+                self.known.insert(username.clone(), known);
+                Learned::New
+            }
+            Some(known) if known.key == key => match known.changed.take() {
+                // Back to the key we had
+                Some(_) => Learned::Same,
+                None => return Ok(Learned::Same),
+            },
+            Some(known) => {
+                if known.changed == Some(key) {
+                    return Ok(Learned::Changed);
+                }
+                known.changed = Some(key);
+                Learned::Changed
+            }
+        };
+        self.save()?;
+        Ok(learned)
+    }
+    /// Mark the key of `username` as checked, or not. Verifying a changed key pins it in place
+    /// of the old one
+    pub fn set_verified(&mut self, username: &str, verified: bool) -> io::Result<bool> {
+        let Some(known) = self.known.get_mut(username) else {
+            return Ok(false);
+        };
+        if verified && let Some(key) = known.changed.take() {
+            known.key = key;
+        }
+        known.verified = verified;
+        self.save()?;
+        Ok(true)
+    }
+    /// Pinned key for `username` that's still good to use
+    fn usable(&self, username: &str) -> io::Result<&PublicKey> {
+        match self.known.get(username) {
+            None => Err(invalid("no key")),
+            Some(known) if known.changed.is_some() => Err(invalid("key changed, verify it first")),
+            Some(known) => Ok(&known.key),
+        }
+    }
+    /// Seal `content` for `to`, whose key we must already have
+    pub fn seal(&self, to: &str, content: &str) -> io::Result<Sealed> {
+        self.identity
+            .seal(&self.username, to, self.usable(to)?, content)
+    }
+    /// Open a message to us from `from`, whose key we must already have
+    pub fn open(&self, from: &str, sealed: &Sealed) -> io::Result<Content> {
+        self.identity
+            .open(from, &self.username, self.usable(from)?, sealed)
+    }
+}
+
+/// Messages for the server and for the app, what a [`Keyring`] step turns into
+pub(crate) type Replies = (Vec<ChatrMessage>, Vec<ChatrMessage>);
+
+impl Keyring {
+    /// Seal a direct message, or hold it and ask for the key first
+    pub(crate) fn whisper(&mut self, to: Username, content: Content) -> Replies {
+        if self.fresh.contains(&to) {
+            return self.send_sealed(to, content);
+        }
+        let changed = self.changed(&to);
+        let waiting = self.waiting.entry(to.clone()).or_default();
+        if waiting.len() >= MAX_WAITING {
+            let reason =
+                format!("too many messages are waiting on {to}'s key, this one wasn't sent");
+            return (Vec::new(), vec![ChatrMessage::Error { reason }]);
+        }
+        waiting.push(Waiting::Outgoing(content));
+        if changed {
+            let reason = format!("holding your message until you verify {to}'s new key");
+            return (Vec::new(), vec![ChatrMessage::Error { reason }]);
+        }
+        if waiting.len() > 1 {
+            return (Vec::new(), Vec::new());
+        }
+        (vec![self.request_key(to)], Vec::new())
+    }
+    /// Open a direct message, or hold it and ask for the sender's key first
+    pub(crate) fn received(
+        &mut self,
+        from: Username,
+        sent_at: Timestamp,
+        sealed: Sealed,
+    ) -> Replies {
+        let arrived_at = time::now();
+        if self.fresh.contains(&from) {
+            let opened = self.opened(from.clone(), sent_at, arrived_at, &sealed);
+            if matches!(opened, ChatrMessage::Whispered { .. }) {
+                return (Vec::new(), vec![opened]);
+            }
+            // They may have come back with a new key, ask again before giving up
+            self.fresh.remove(&from);
+        }
+        let changed = self.changed(&from);
+        let waiting = self.waiting.entry(from.clone()).or_default();
+        if waiting.len() >= MAX_WAITING {
+            let reason =
+                format!("too many messages from {from} are waiting on their key, dropped one");
+            return (Vec::new(), vec![ChatrMessage::Error { reason }]);
+        }
+        waiting.push(Waiting::Incoming(sent_at, arrived_at, sealed));
+        if changed {
+            let reason = format!("holding a message from {from} until you verify their new key");
+            return (Vec::new(), vec![ChatrMessage::Error { reason }]);
+        }
+        if waiting.len() > 1 {
+            return (Vec::new(), Vec::new());
+        }
+        (vec![self.request_key(from)], Vec::new())
+    }
+    /// Whether `username` has a new key that's waiting to be verified
+    fn changed(&self, username: &str) -> bool {
+        self.known
+            .get(username)
+            .is_some_and(|k| k.changed.is_some())
+    }
+    /// Ask the server for a key, only keys we asked for are taken
+    pub(crate) fn request_key(&mut self, username: Username) -> ChatrMessage {
+        self.requested.insert(username.clone());
+        ChatrMessage::KeyRequest { username }
+    }
+    /// Key came back from the server, deal with what was waiting on it
+    pub(crate) fn peer_key(&mut self, username: Username, key: Option<PublicKey>) -> Replies {
+        let mut to_server = Vec::new();
+        let mut to_app = Vec::new();
+        if !self.requested.remove(&username) {
+            tracing::warn!("ignoring a key for {username} nobody asked for");
+            return (to_server, to_app);
+        }
+        let waiting = self.waiting.remove(&username).unwrap_or_default();
+        to_app.push(ChatrMessage::PeerKey {
+            username: username.clone(),
+            key,
+        });
+        let Some(key) = key else {
+            if !waiting.is_empty() {
+                let reason = format!("{username} hasn't published a key to message them with");
+                to_app.push(ChatrMessage::Error { reason });
+            }
+            return (to_server, to_app);
+        };
+        match self.learn(&username, key) {
+            Ok(Learned::Changed) => {
+                let reason = format!(
+                    "{username}'s key changed, check the new one with them and verify it to \
+                     message them again"
+                );
+                to_app.push(ChatrMessage::Error { reason });
+                // Held until verifying the new key asks for it again
+                if !waiting.is_empty() {
+                    self.waiting.insert(username, waiting);
+                }
+                return (to_server, to_app);
+            }
+            Ok(_) => (),
+            Err(e) => to_app.push(ChatrMessage::Error {
+                reason: format!("couldn't save {username}'s key: {e}"),
+            }),
+        }
+        self.fresh.insert(username.clone());
+        for waiting in waiting {
+            match waiting {
+                Waiting::Outgoing(content) => {
+                    let (server, app) = self.send_sealed(username.clone(), content);
+                    to_server.extend(server);
+                    to_app.extend(app);
+                }
+                Waiting::Incoming(sent_at, arrived_at, sealed) => {
+                    to_app.push(self.opened(username.clone(), sent_at, arrived_at, &sealed))
+                }
+            }
+        }
+        (to_server, to_app)
+    }
+    fn send_sealed(&self, to: Username, content: Content) -> Replies {
+        match self.seal(&to, &content) {
+            Ok(sealed) => {
+                let whispered = ChatrMessage::Whispered {
+                    from: self.username.clone(),
+                    to: to.clone(),
+                    sent_at: time::now(),
+                    content,
+                    verified: self.known.get(&to).is_some_and(|k| k.verified),
+                };
+                (
+                    vec![ChatrMessage::SendDirect { to, sealed }],
+                    vec![whispered],
+                )
+            }
+            Err(e) => {
+                let reason = format!("couldn't seal a message to {to}: {e}");
+                (Vec::new(), vec![ChatrMessage::Error { reason }])
+            }
+        }
+    }
+    fn opened(
+        &mut self,
+        from: Username,
+        sent_at: Timestamp,
+        arrived_at: Timestamp,
+        sealed: &Sealed,
+    ) -> ChatrMessage {
+        let opened = self
+            .open(&from, sealed)
+            .and_then(|content| self.first_sight(&from, arrived_at, sealed).map(|_| content));
+        match opened {
+            Ok(content) => ChatrMessage::Whispered {
+                verified: self.known.get(&from).is_some_and(|k| k.verified),
+                from,
+                to: self.username.clone(),
+                sent_at,
+                content,
+            },
+            Err(e) => ChatrMessage::Error {
+                reason: format!("couldn't open a message from {from}: {e}"),
+            },
+        }
+    }
+    /// Turn away a message from `from` sealed long before or after it got to us, or one we've
+    /// already had, so the server can't play old messages back
+    fn first_sight(
+        &mut self,
+        from: &str,
+        arrived_at: Timestamp,
+        sealed: &Sealed,
+    ) -> io::Result<()> {
+        let skew = MAX_DM_SKEW.as_millis() as Timestamp;
+        if sealed.sealed_at.abs_diff(arrived_at) > skew {
+            return Err(invalid(
+                "sealed too long before or after it got here, it may be a replay",
+            ));
+        }
+        let seen = self.seen.entry(from.to_string()).or_default();
+        if !seen.insert((sealed.sealed_at, sealed.nonce)) {
+            return Err(invalid("already had it, it's been replayed"));
+        }
+        // Anything older gets turned away as too old anyway
+        *seen = seen.split_off(&(arrived_at.saturating_sub(skew), [0; 12]));
+        Ok(())
+    }
+}
+
+/// Write a file only we can read
+fn write_private(path: &std::path::Path, data: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, data)
+}
@@ -0,0 +1,299 @@
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{fmt, io};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::{ChatMessage, ChatrMessage, MessageKind, Timestamp, files, message_log, room, time};
+
+/// What an export is written as
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub enum ExportFormat {
+    /// One JSON message per line
+    Jsonl,
+    /// IRC style log
+    Text,
+    /// Standalone page
+    Html,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(ExportFormat::Jsonl),
+            "text" | "txt" => Ok(ExportFormat::Text),
+            "html" => Ok(ExportFormat::Html),
+            other => Err(format!("{other} isn't jsonl, text or html")),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportFormat::Jsonl => write!(f, "jsonl"),
+            ExportFormat::Text => write!(f, "text"),
+            ExportFormat::Html => write!(f, "html"),
+        }
+    }
+}
+
+/// Which history to export
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub struct ExportQuery {
+    /// Only this room, every room when not given
+    pub room: Option<String>,
+    /// Only messages sent at or after this time
+    pub after: Option<Timestamp>,
+    /// Only messages sent before this time
+    pub before: Option<Timestamp>,
+}
+
+impl ExportQuery {
+    /// Parse `room:name`, `after:when` and `before:when`, `when` as in [`time::parse_when`]
+    pub fn parse<'a>(args: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let now = time::now();
+        let mut query = ExportQuery::default();
+        for arg in args {
+            let when = |when| time::parse_when(when, now).ok_or(format!("{when} isn't a time"));
+            match arg.split_once(':') {
+                Some(("room", name)) => {
+                    let name = room::room_name(name).ok_or(format!("{name} isn't a room name"))?;
+                    query.room = Some(name)
+                }
+                Some(("after", after)) => query.after = Some(when(after)?),
+                Some(("before", before)) => query.before = Some(when(before)?),
+                _ => return Err(format!("don't know what {arg} means")),
+            }
+        }
+        Ok(query)
+    }
+    pub fn matches(&self, message: &ChatMessage) -> bool {
+        self.room.as_ref().is_none_or(|room| *room == message.room)
+            && self.after.is_none_or(|after| message.sent_at >= after)
+            && self.before.is_none_or(|before| message.sent_at < before)
+    }
+}
+
+/// Parse what follows `/export`: the format, the file to write and then filters for
+/// [`ExportQuery::parse`]
+pub fn parse_export(args: &str) -> Result<(ExportFormat, PathBuf, ExportQuery), String> {
+    let mut args = args.split_whitespace();
+    let (Some(format), Some(path)) = (args.next(), args.next()) else {
+        return Err("export needs a format and a file, like `export html log.html`".to_string());
+    };
+    Ok((
+        format.parse()?,
+        PathBuf::from(path),
+        ExportQuery::parse(args)?,
+    ))
+}
+
+/// Heading for an export of `query`
+pub fn title(query: &ExportQuery) -> String {
+    match &query.room {
+        Some(room) => format!("chatr log: #{room}"),
+        None => "chatr log".to_string(),
+    }
+}
+
+/// Split history into ExportMessages small enough to send, the last one marked done
+pub fn export_pages(messages: Vec<ChatMessage>) -> Vec<ChatrMessage> {
+    let mut messages = messages.iter().peekable();
+    let mut pages = vec![message_log::next_page(&mut messages, usize::MAX)];
+    while messages.peek().is_some() {
+        pages.push(message_log::next_page(&mut messages, usize::MAX));
+    }
+    let last = pages.len() - 1;
+    pages
+        .into_iter()
+        .enumerate()
+        .map(|(i, messages)| ChatrMessage::ExportMessages {
+            messages,
+            done: i == last,
+        })
+        .collect()
+}
+
+/// History written out in `format`, `title` heads the HTML page
+pub fn export(messages: &[ChatMessage], format: ExportFormat, title: &str) -> String {
+    match format {
+        ExportFormat::Jsonl => messages
+            .iter()
+            .map(|message| serde_json::to_string(message).unwrap() + "\n")
+            .collect(),
+        ExportFormat::Text => messages.iter().map(text_line).collect(),
+        ExportFormat::Html => html_page(messages, title),
+    }
+}
+
+/// `[2025-01-31 14:05] [lobby] #12 <alice> hello`, more lines of content get indented under it
+fn text_line(message: &ChatMessage) -> String {
+    let ChatMessage {
+        id,
+        sent_at,
+        room,
+        username,
+        kind,
+        content,
+        parent,
+        attachment,
+        ..
+    } = message;
+    let mut content = content.replace('\n', "\n    ");
+    if let Some(parent) = parent {
+        content = format!("(re #{parent}) {content}");
+    }
+    if let Some(attachment) = attachment {
+        let _ = write!(content, " [{}]", files::hex(&attachment.sha256));
+    }
+    let line = match kind {
+        MessageKind::Normal => format!("#{id} <{username}> {content}"),
+        MessageKind::Action => format!("#{id} * {username} {content}"),
+        MessageKind::Notice => format!("#{id} -{username}- {content}"),
+        MessageKind::System => format!("*** {content}"),
+    };
+    format!("[{}] [{room}] {line}\n", time::format_time(*sent_at))
+}
+
+fn html_page(messages: &[ChatMessage], title: &str) -> String {
+    let mut page = String::new();
+    let title = escape(title);
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>\n\
+         body {{ font-family: sans-serif; max-width: 60em; margin: 2em auto; }}\n\
+         .msg {{ margin: 0.3em 0; white-space: pre-wrap; }}\n\
+         .msg time, .room, .reply, .system {{ color: #777; }}\n\
+         .action {{ font-style: italic; color: #a0a; }}\n\
+         .notice {{ color: #a60; }}\n\
+         .reactions {{ color: #555; font-size: 0.9em; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    for message in messages {
+        page.push_str(&html_message(message));
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+fn html_message(message: &ChatMessage) -> String {
+    let ChatMessage {
+        id,
+        sent_at,
+        room,
+        username,
+        kind,
+        content,
+        parent,
+        reactions,
+        attachment,
+    } = message;
+    let username = escape(username);
+    let content = escape(content);
+    let class = match kind {
+        MessageKind::Normal => "msg",
+        MessageKind::Action => "msg action",
+        MessageKind::Notice => "msg notice",
+        MessageKind::System => "msg system",
+    };
+    let body = match kind {
+        MessageKind::Normal => format!("<b>{username}</b>: {content}"),
+        MessageKind::Action => format!("* {username} {content}"),
+        MessageKind::Notice => format!("-<b>{username}</b>- {content}"),
+        MessageKind::System => format!("*** {content}"),
+    };
+    let mut html = format!(
+        "<div class=\"{class}\" id=\"m{id}\"><time>{}</time> <span class=\"room\">#{}</span> ",
+        time::format_time(*sent_at),
+        escape(room)
+    );
+    if let Some(parent) = parent {
+        let _ = write!(
+            html,
+            "<a class=\"reply\" href=\"#m{parent}\">↳ #{parent}</a> "
+        );
+    }
+    html.push_str(&body);
+    if let Some(attachment) = attachment {
+        let _ = write!(
+            html,
+            " <span class=\"reply\">📎 {} ({})</span>",
+            escape(&attachment.name),
+            files::human_size(attachment.size)
+        );
+    }
+    if !reactions.is_empty() {
+        let reactions = reactions
+            .iter()
+            .map(|r| format!("{} {}", escape(&r.emoji), r.users.len()))
+            .collect::<Vec<String>>();
+        let _ = write!(
+            html,
+            " <span class=\"reactions\">{}</span>",
+            reactions.join(" ")
+        );
+    }
+    html.push_str("</div>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Client side export, collects ExportMessages until the last one and writes them out
+#[derive(Debug)]
+pub struct PendingExport {
+    pub format: ExportFormat,
+    pub path: PathBuf,
+    title: String,
+    messages: Vec<ChatMessage>,
+}
+
+impl PendingExport {
+    pub fn new(format: ExportFormat, path: PathBuf, query: &ExportQuery) -> Self {
+        Self {
+            format,
+            path,
+            title: title(query),
+            messages: Vec::new(),
+        }
+    }
+    /// Add a page of history, writing the file once `done`
+    pub async fn receive(&mut self, messages: &[ChatMessage], done: bool) -> io::Result<bool> {
+        self.messages.extend_from_slice(messages);
+        if !done {
+            return Ok(false);
+        }
+        let exported = export(&self.messages, self.format, &self.title);
+        tokio::fs::write(&self.path, exported).await?;
+        Ok(true)
+    }
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{ChatrMessage, MessageId, SessionId, Username, chatroom::AdminMsg};
+
+/// Largest file that can be shared
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// Most file data sent in one message
+pub const CHUNK_LEN: usize = 64 * 1024;
+/// Env var naming where clients save downloads, the current directory otherwise
+pub const DOWNLOADS_ENV: &str = "CHATR_DOWNLOADS";
+/// Most uploads one user can have on the go, finished or not
+pub const MAX_PENDING_UPLOADS: usize = 4;
+/// Unfinished uploads untouched for this long are thrown away
+pub const PART_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often the server looks for old unfinished uploads
+const PART_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PART_EXTENSION: &str = "part";
+
+/// SHA-256 of a file's contents, files are stored and asked for by it
+pub type FileHash = [u8; 32];
+
+/// File shared in the chatroom
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub size: u64,
+    pub sha256: FileHash,
+}
+
+impl Attachment {
+    /// Short form of the hash for showing to people
+    pub fn short_hash(&self) -> String {
+        hex(&self.sha256)[..12].to_string()
+    }
+}
+
+/// Lowercase hex of some bytes, like a hash
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Size in the largest unit that keeps it above 1, like "12.3 KiB"
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Just the file name part of `name`, so a shared file can't be saved outside where it's meant
+/// to go
+pub fn safe_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?;
+    let valid = !name.starts_with('.') && name.len() <= 255 && !name.chars().any(char::is_control);
+    valid.then(|| name.to_string())
+}
+
+/// Upload the server is waiting on the rest of
+#[derive(Debug)]
+struct PendingUpload {
+    name: String,
+    size: u64,
+    received: u64,
+}
+
+/// Server side blob storage, files go in `dir` named by their hash
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+    fn blob_path(&self, sha256: &FileHash) -> PathBuf {
+        self.dir.join(hex(sha256))
+    }
+    /// Where `username` is uploading `sha256` to, everyone has their own so people sending the
+    /// same file at once don't write into each other's
+    fn part_path(&self, username: &str, sha256: &FileHash) -> PathBuf {
+        self.dir.join(format!(
+            "{}{}.{PART_EXTENSION}",
+            part_prefix(username),
+            hex(sha256)
+        ))
+    }
+    /// How many unfinished uploads `username` has lying around
+    async fn parts_of(&self, username: &str) -> io::Result<usize> {
+        let prefix = part_prefix(username);
+        let mut parts = 0;
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(PART_EXTENSION) {
+                parts += 1;
+            }
+        }
+        Ok(parts)
+    }
+    /// Throw away unfinished uploads nobody has added to in [`PART_MAX_AGE`]
+    async fn sweep_parts(&self) -> io::Result<()> {
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != PART_EXTENSION) {
+                continue;
+            }
+            let modified = entry.metadata().await?.modified()?;
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            if age > PART_MAX_AGE {
+                tracing::debug!("removing abandoned upload {path:?}");
+                fs::remove_file(&path).await?;
+            }
+        }
+        Ok(())
+    }
+    /// Handle file transfer requests from clients in their own task, so disk access never holds
+    /// up the chatroom. Replies and finished uploads go through `admin`. Uploads belong to the
+    /// session that started them, and go when it disconnects. Stops when `ct` is cancelled.
+    pub fn run(
+        self,
+        mut rx: mpsc::Receiver<(Username, SessionId, ChatrMessage)>,
+        admin: mpsc::Sender<AdminMsg>,
+        ct: CancellationToken,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            if let Err(e) = fs::create_dir_all(&self.dir).await {
+                tracing::error!("can't create files dir {:?} {e}", self.dir);
+                return;
+            }
+            let mut uploads: HashMap<(SessionId, FileHash), PendingUpload> = HashMap::new();
+            let mut sweep = tokio::time::interval(PART_SWEEP_INTERVAL);
+            loop {
+                let (username, session, msg) = tokio::select! {
+                    received = rx.recv() => match received {
+                        Some(received) => received,
+                        None => break,
+                    },
+                    _ = sweep.tick() => {
+                        if let Err(e) = self.sweep_parts().await {
+                            tracing::error!("couldn't clear out old uploads {e}");
+                        }
+                        continue;
+                    }
+                    _ = ct.cancelled() => break,
+                };
+                let result = match msg {
+                    ChatrMessage::UploadStart { name, size, sha256 } => {
+                        self.start_upload(&mut uploads, &username, session, name, size, sha256)
+                            .await
+                    }
+                    ChatrMessage::UploadChunk {
+                        sha256,
+                        offset,
+                        data,
+                    } => {
+                        self.upload_chunk(&mut uploads, &username, session, sha256, offset, data)
+                            .await
+                    }
+                    ChatrMessage::DownloadRequest { sha256, offset } => self
+                        .download(username.clone(), sha256, offset, admin.clone())
+                        .await
+                        .map(|_| None),
+                    ChatrMessage::Disconnect => {
+                        uploads.retain(|(s, _), _| *s != session);
+                        Ok(None)
+                    }
+                    _ => Ok(None),
+                };
+                let reply = match result {
+                    Ok(Some(reply)) => reply,
+                    Ok(None) => continue,
+                    Err(e) => AdminMsg::SendTo(
+                        username,
+                        ChatrMessage::Error {
+                            reason: e.to_string(),
+                        },
+                    ),
+                };
+                if admin.send(reply).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+    /// Start an upload, or pick up where an earlier one stopped
+    async fn start_upload(
+        &self,
+        uploads: &mut HashMap<(SessionId, FileHash), PendingUpload>,
+        username: &Username,
+        session: SessionId,
+        name: String,
+        size: u64,
+        sha256: FileHash,
+    ) -> io::Result<Option<AdminMsg>> {
+        let name = safe_name(&name).ok_or_else(|| invalid(format!("{name} isn't a file name")))?;
+        if size == 0 {
+            return Err(invalid(format!("{name} is empty")));
+        }
+        if size > MAX_FILE_SIZE {
+            return Err(invalid(format!(
+                "{name} is over the {} limit",
+                human_size(MAX_FILE_SIZE)
+            )));
+        }
+        // Even a file we already have has to be sent in full, having its hash isn't having it
+        let part = self.part_path(username, &sha256);
+        let started = fs::metadata(&part).await.ok();
+        if started.is_none() && !uploads.contains_key(&(session, sha256)) {
+            // On disk, or started but with nothing written yet
+            let unwritten = uploads
+                .iter()
+                .filter(|((s, _), upload)| *s == session && upload.received == 0)
+                .count();
+            if self.parts_of(username).await? + unwritten >= MAX_PENDING_UPLOADS {
+                return Err(invalid(format!(
+                    "you can only have {MAX_PENDING_UPLOADS} uploads going at once"
+                )));
+            }
+        }
+        let mut received = started.map_or(0, |m| m.len());
+        if received > size {
+            fs::remove_file(&part).await?;
+            received = 0;
+        }
+        uploads.insert(
+            (session, sha256),
+            PendingUpload {
+                name,
+                size,
+                received,
+            },
+        );
+        if received == size {
+            return self.finish_upload(uploads, username, session, sha256).await;
+        }
+        let ready = ChatrMessage::UploadReady {
+            sha256,
+            offset: received,
+        };
+        Ok(Some(AdminMsg::SendTo(username.clone(), ready)))
+    }
+    async fn upload_chunk(
+        &self,
+        uploads: &mut HashMap<(SessionId, FileHash), PendingUpload>,
+        username: &Username,
+        session: SessionId,
+        sha256: FileHash,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> io::Result<Option<AdminMsg>> {
+        let key = (session, sha256);
+        let upload = uploads
+            .get_mut(&key)
+            .ok_or_else(|| invalid("chunk for an upload that wasn't started".to_string()))?;
+        if offset != upload.received {
+            return Err(invalid(format!(
+                "{} chunk at {offset}, expected {}",
+                upload.name, upload.received
+            )));
+        }
+        if data.len() > CHUNK_LEN || offset + data.len() as u64 > upload.size {
+            uploads.remove(&key);
+            return Err(invalid("chunk runs past the end of the file".to_string()));
+        }
+        let mut part = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.part_path(username, &sha256))
+            .await?;
+        part.write_all(&data).await?;
+        upload.received += data.len() as u64;
+        if upload.received < upload.size {
+            return Ok(None);
+        }
+        part.flush().await?;
+        self.finish_upload(uploads, username, session, sha256).await
+    }
+    /// Check the whole file arrived intact and move it into place
+    async fn finish_upload(
+        &self,
+        uploads: &mut HashMap<(SessionId, FileHash), PendingUpload>,
+        username: &Username,
+        session: SessionId,
+        sha256: FileHash,
+    ) -> io::Result<Option<AdminMsg>> {
+        let Some(upload) = uploads.remove(&(session, sha256)) else {
+            return Ok(None);
+        };
+        let part = self.part_path(username, &sha256);
+        if hash_file(&part).await? != sha256 {
+            fs::remove_file(&part).await?;
+            return Err(invalid(format!("{} failed its checksum", upload.name)));
+        }
+        fs::rename(&part, self.blob_path(&sha256)).await?;
+        let attachment = Attachment {
+            name: upload.name,
+            size: upload.size,
+            sha256,
+        };
+        Ok(Some(AdminMsg::Attach(username.clone(), attachment)))
+    }
+    /// Send a stored file from `offset` on in its own task
+    async fn download(
+        &self,
+        username: Username,
+        sha256: FileHash,
+        offset: u64,
+        admin: mpsc::Sender<AdminMsg>,
+    ) -> io::Result<()> {
+        let path = self.blob_path(&sha256);
+        let mut file = fs::File::open(&path)
+            .await
+            .map_err(|_| invalid(format!("no file {}", hex(&sha256))))?;
+        let size = file.metadata().await?.len();
+        if offset > size {
+            return Err(invalid(format!("offset {offset} is past the end")));
+        }
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        tokio::spawn(async move {
+            let mut offset = offset;
+            loop {
+                let mut data = vec![0; CHUNK_LEN.min((size - offset) as usize)];
+                if let Err(e) = file.read_exact(&mut data).await {
+                    tracing::error!("reading {path:?} failed {e}");
+                    break;
+                }
+                let len = data.len() as u64;
+                let chunk = ChatrMessage::DownloadChunk {
+                    sha256,
+                    offset,
+                    size,
+                    data,
+                };
+                if admin
+                    .send(AdminMsg::SendTo(username.clone(), chunk))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                offset += len;
+                if offset >= size {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// SHA-256 of the file at `path`, read a chunk at a time
+async fn hash_file(path: &Path) -> io::Result<FileHash> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_LEN];
+    loop {
+        let len = file.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(hasher.finalize().into());
+        }
+        hasher.update(&buf[..len]);
+    }
+}
+
+/// Start of the names of `username`'s unfinished uploads, hashed so any name fits in a file
+/// name
+fn part_prefix(username: &str) -> String {
+    format!("{}-", hex(&Sha256::digest(username.as_bytes())))
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+/// Something a transfer needs the client to do
+#[derive(Debug)]
+pub enum TransferEvent {
+    /// Upload the server is ready for, send its chunks on
+    Upload(Upload),
+    /// Download finished and checked out, saved here
+    Downloaded(PathBuf),
+}
+
+#[derive(Debug)]
+struct PendingDownload {
+    name: String,
+    part: PathBuf,
+}
+
+/// File being uploaded, read a chunk at a time as it's sent
+#[derive(Debug)]
+pub struct Upload {
+    file: fs::File,
+    sha256: FileHash,
+    offset: u64,
+    size: u64,
+}
+
+impl Upload {
+    /// Next chunk to send the server, None once it's all gone
+    pub async fn next_chunk(&mut self) -> io::Result<Option<ChatrMessage>> {
+        if self.offset >= self.size {
+            return Ok(None);
+        }
+        let len = CHUNK_LEN.min((self.size - self.offset) as usize);
+        let mut data = vec![0; len];
+        self.file.read_exact(&mut data).await?;
+        let chunk = ChatrMessage::UploadChunk {
+            sha256: self.sha256,
+            offset: self.offset,
+            data,
+        };
+        self.offset += len as u64;
+        Ok(Some(chunk))
+    }
+}
+
+/// Client side of file transfers, remembers shared files and what is on its way
+#[derive(Debug, Default)]
+pub struct Transfers {
+    /// Where downloads are saved
+    dir: PathBuf,
+    attachments: HashMap<MessageId, Attachment>,
+    /// Files we've asked to upload and how big they were
+    uploads: HashMap<FileHash, (PathBuf, u64)>,
+    downloads: HashMap<FileHash, PendingDownload>,
+}
+
+impl Transfers {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            ..Default::default()
+        }
+    }
+    /// Downloads go where [`DOWNLOADS_ENV`] says, or the current directory
+    pub fn from_env() -> Self {
+        Self::new(std::env::var_os(DOWNLOADS_ENV).unwrap_or_else(|| ".".into()))
+    }
+    pub fn attachment(&self, id: MessageId) -> Option<&Attachment> {
+        self.attachments.get(&id)
+    }
+    /// Ask to upload the file at `path`
+    pub async fn upload(&mut self, path: &Path) -> io::Result<ChatrMessage> {
+        let name = path
+            .file_name()
+            .and_then(|name| safe_name(&name.to_string_lossy()))
+            .ok_or_else(|| invalid(format!("{path:?} isn't a file")))?;
+        let size = fs::metadata(path).await?.len();
+        if size > MAX_FILE_SIZE {
+            return Err(invalid(format!(
+                "{name} is over the {} limit",
+                human_size(MAX_FILE_SIZE)
+            )));
+        }
+        let sha256 = hash_file(path).await?;
+        self.uploads.insert(sha256, (path.to_path_buf(), size));
+        Ok(ChatrMessage::UploadStart { name, size, sha256 })
+    }
+    /// Ask for the file shared in message `id`, carrying on from any earlier partial download
+    pub async fn download(&mut self, id: MessageId) -> io::Result<ChatrMessage> {
+        let attachment = self
+            .attachments
+            .get(&id)
+            .ok_or_else(|| invalid(format!("message {id} has no file")))?;
+        let name = safe_name(&attachment.name).unwrap_or_else(|| attachment.short_hash());
+        let part = self.dir.join(format!(
+            "{name}.{}.{PART_EXTENSION}",
+            attachment.short_hash()
+        ));
+        let offset = fs::metadata(&part).await.map(|m| m.len()).unwrap_or(0);
+        let sha256 = attachment.sha256;
+        self.downloads
+            .insert(sha256, PendingDownload { name, part });
+        Ok(ChatrMessage::DownloadRequest { sha256, offset })
+    }
+    /// Keep track of shared files and move transfers along as messages arrive
+    pub async fn handle(&mut self, msg: &ChatrMessage) -> io::Result<Option<TransferEvent>> {
+        match msg {
+            ChatrMessage::ReceivedMessage { message } => {
+                if let Some(attachment) = &message.attachment {
+                    self.attachments.insert(message.id, attachment.clone());
+                }
+                Ok(None)
+            }
+            ChatrMessage::History { messages } => {
+                for message in messages {
+                    if let Some(attachment) = &message.attachment {
+                        self.attachments.insert(message.id, attachment.clone());
+                    }
+                }
+                Ok(None)
+            }
+            ChatrMessage::UploadReady { sha256, offset } => {
+                let Some((path, size)) = self.uploads.remove(sha256) else {
+                    return Ok(None);
+                };
+                let mut file = fs::File::open(&path).await?;
+                let offset = (*offset).min(size);
+                file.seek(io::SeekFrom::Start(offset)).await?;
+                Ok(Some(TransferEvent::Upload(Upload {
+                    file,
+                    sha256: *sha256,
+                    offset,
+                    size,
+                })))
+            }
+            ChatrMessage::DownloadChunk {
+                sha256,
+                offset,
+                size,
+                data,
+            } => self.download_chunk(sha256, *offset, *size, data).await,
+            _ => Ok(None),
+        }
+    }
+    async fn download_chunk(
+        &mut self,
+        sha256: &FileHash,
+        offset: u64,
+        size: u64,
+        data: &[u8],
+    ) -> io::Result<Option<TransferEvent>> {
+        let Some(download) = self.downloads.get(sha256) else {
+            return Ok(None);
+        };
+        let mut part = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&download.part)
+            .await?;
+        let have = part.metadata().await?.len();
+        if have != offset {
+            let reason = format!("{} chunk at {offset}, expected {have}", download.name);
+            self.downloads.remove(sha256);
+            return Err(invalid(reason));
+        }
+        part.write_all(data).await?;
+        part.flush().await?;
+        if offset + (data.len() as u64) < size {
+            return Ok(None);
+        }
+        let download = self.downloads.remove(sha256).unwrap();
+        if hash_file(&download.part).await? != *sha256 {
+            fs::remove_file(&download.part).await?;
+            return Err(invalid(format!("{} failed its checksum", download.name)));
+        }
+        let mut path = self.dir.join(&download.name);
+        if fs::try_exists(&path).await? {
+            path = self
+                .dir
+                .join(format!("{}-{}", &hex(sha256)[..8], download.name));
+        }
+        fs::rename(&download.part, &path).await?;
+        Ok(Some(TransferEvent::Downloaded(path)))
+    }
+}
@@ -0,0 +1,86 @@
+use std::io;
+
+use borsh::BorshDeserialize;
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::ChatrMessage;
+
+/// Largest message either end accepts, comfortably above a file chunk
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// Frames start with their length as a big endian u32
+const LEN_PREFIX: usize = 4;
+
+/// Message as it goes over the wire, length prefixed borsh
+pub fn encode(msg: &ChatrMessage) -> io::Result<Vec<u8>> {
+    let body = borsh::to_vec(msg)?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} byte message is over the frame limit", body.len()),
+        ));
+    }
+    let mut frame = Vec::with_capacity(LEN_PREFIX + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Bytes `msg` takes up on the wire, prefix included
+pub fn frame_len(msg: &ChatrMessage) -> usize {
+    LEN_PREFIX + borsh::object_length(msg).unwrap_or(0)
+}
+
+/// Take the first whole message out of `buf`, `None` until enough bytes have arrived
+pub fn decode(buf: &mut BytesMut) -> io::Result<Option<ChatrMessage>> {
+    let Some(prefix) = buf.get(..LEN_PREFIX) else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{len} byte frame is over the limit"),
+        ));
+    }
+    if buf.len() < LEN_PREFIX + len {
+        buf.reserve(LEN_PREFIX + len - buf.len());
+        return Ok(None);
+    }
+    buf.advance(LEN_PREFIX);
+    let body = buf.split_to(len);
+    ChatrMessage::try_from_slice(&body).map(Some)
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &ChatrMessage,
+) -> io::Result<()> {
+    writer.write_all(&encode(msg)?).await?;
+    writer.flush().await
+}
+
+/// Read the next message, keeping whatever arrives behind it in `buf` for the next call
+///
+/// `None` when the other end closed between messages. Cancel safe, so it can sit in a
+/// `select!`.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+) -> io::Result<Option<ChatrMessage>> {
+    loop {
+        if let Some(msg) = decode(buf)? {
+            return Ok(Some(msg));
+        }
+        if reader.read_buf(buf).await? == 0 {
+            return if buf.is_empty() {
+                Ok(None)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed mid message",
+                ))
+            };
+        }
+    }
+}
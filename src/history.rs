@@ -0,0 +1,270 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use rustyline::history::{History, SearchDirection, SearchResult};
+
+/// Env var pointing at a file to persist sent message history in
+pub const HISTORY_ENV: &str = "CHATR_HISTORY";
+/// Most entries kept in memory before the oldest get dropped
+pub const DEFAULT_MAX_LEN: usize = 1000;
+
+/// Sent-message history shared by the chatr clients
+///
+/// Navigated with [`InputHistory::older`]/[`InputHistory::newer`] by the TUI composer and
+/// plugged straight into a rustyline `Editor` by the line client. When backed by a file every
+/// accepted entry is appended to it as one line.
+#[derive(Debug)]
+pub struct InputHistory {
+    entries: VecDeque<String>,
+    max_len: usize,
+    /// Index of the entry being shown while navigating, `None` when editing a fresh line
+    cursor: Option<usize>,
+    /// Line that was being typed before navigation started, given back when moving past the
+    /// newest entry
+    draft: String,
+    file: Option<PathBuf>,
+}
+
+impl Default for InputHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputHistory {
+    /// In-memory only history
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            max_len: DEFAULT_MAX_LEN,
+            cursor: None,
+            draft: String::new(),
+            file: None,
+        }
+    }
+    /// History persisted to `path`, loading whatever is already there
+    pub fn with_file(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut history = Self::new();
+        if path.exists() {
+            history.load_file(&path)?;
+        }
+        history.file = Some(path);
+        Ok(history)
+    }
+    /// History persisted to the file named by [`HISTORY_ENV`], or in-memory if it isn't set
+    pub fn from_env() -> io::Result<Self> {
+        match std::env::var_os(HISTORY_ENV) {
+            Some(path) => Self::with_file(path),
+            None => Ok(Self::new()),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn entry(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+    /// Entries from oldest to newest
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.entries.iter().map(String::as_str)
+    }
+    /// Record a sent line, appending it to the history file if there is one
+    ///
+    /// Blank lines and repeats of the newest entry are ignored, returns whether the line was
+    /// kept.
+    pub fn push(&mut self, line: &str) -> io::Result<bool> {
+        self.reset_navigation();
+        if !self.insert(line) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.file {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{line}")?;
+        }
+        Ok(true)
+    }
+    /// Step back to an older entry, `current` is stashed the first time so it can be restored
+    pub fn older(&mut self, current: &str) -> Option<&str> {
+        let index = match self.cursor {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+            Some(0) => 0,
+            Some(i) => i - 1,
+        };
+        self.cursor = Some(index);
+        self.entry(index)
+    }
+    /// Step forward to a newer entry, past the newest the stashed draft is given back
+    pub fn newer(&mut self) -> Option<&str> {
+        match self.cursor {
+            None => None,
+            Some(i) if i + 1 < self.entries.len() => {
+                self.cursor = Some(i + 1);
+                self.entry(i + 1)
+            }
+            Some(_) => {
+                self.cursor = None;
+                Some(self.draft.as_str())
+            }
+        }
+    }
+    /// Forget where navigation was, the next [`InputHistory::older`] starts at the newest entry
+    pub fn reset_navigation(&mut self) {
+        self.cursor = None;
+        self.draft.clear();
+    }
+    /// Reverse search for the newest entry containing `term`, at or before `start`
+    ///
+    /// `None` for `start` searches from the newest entry.
+    pub fn search_back(&self, term: &str, start: Option<usize>) -> Option<(usize, &str)> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let start = start
+            .unwrap_or(self.entries.len() - 1)
+            .min(self.entries.len() - 1);
+        (0..=start)
+            .rev()
+            .map(|i| (i, self.entries[i].as_str()))
+            .find(|(_, entry)| entry.contains(term))
+    }
+
+    fn insert(&mut self, line: &str) -> bool {
+        if self.max_len == 0
+            || line.trim().is_empty()
+            || self.entries.back().is_some_and(|last| last == line)
+        {
+            return false;
+        }
+        if self.entries.len() == self.max_len {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(line.to_string());
+        true
+    }
+    fn load_file(&mut self, path: &Path) -> io::Result<()> {
+        for line in fs::read_to_string(path)?.lines() {
+            self.insert(line);
+        }
+        Ok(())
+    }
+    fn write_file(&self, path: &Path, append: bool) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)?;
+        for entry in &self.entries {
+            writeln!(file, "{entry}")?;
+        }
+        Ok(())
+    }
+    fn search_with(
+        &self,
+        start: usize,
+        dir: SearchDirection,
+        test: impl Fn(&str) -> Option<usize>,
+    ) -> Option<SearchResult<'_>> {
+        if start >= self.entries.len() {
+            return None;
+        }
+        let hit = |idx: usize| {
+            test(&self.entries[idx]).map(|pos| SearchResult {
+                entry: Cow::Borrowed(self.entries[idx].as_str()),
+                idx,
+                pos,
+            })
+        };
+        match dir {
+            SearchDirection::Reverse => (0..=start).rev().find_map(hit),
+            SearchDirection::Forward => (start..self.entries.len()).find_map(hit),
+        }
+    }
+}
+
+/// Lets the line client hand the same history to rustyline
+impl History for InputHistory {
+    fn get(&self, index: usize, _: SearchDirection) -> rustyline::Result<Option<SearchResult<'_>>> {
+        Ok(self.entry(index).map(|entry| SearchResult {
+            entry: Cow::Borrowed(entry),
+            idx: index,
+            pos: 0,
+        }))
+    }
+    fn add(&mut self, line: &str) -> rustyline::Result<bool> {
+        Ok(self.push(line)?)
+    }
+    fn add_owned(&mut self, line: String) -> rustyline::Result<bool> {
+        Ok(self.push(&line)?)
+    }
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    fn set_max_len(&mut self, len: usize) -> rustyline::Result<()> {
+        self.max_len = len;
+        if self.entries.len() > len {
+            self.entries.drain(..self.entries.len() - len);
+        }
+        Ok(())
+    }
+    fn ignore_dups(&mut self, _: bool) -> rustyline::Result<()> {
+        Ok(())
+    }
+    fn ignore_space(&mut self, _: bool) {}
+    fn save(&mut self, path: &Path) -> rustyline::Result<()> {
+        Ok(self.write_file(path, false)?)
+    }
+    fn append(&mut self, path: &Path) -> rustyline::Result<()> {
+        // Entries already land in our own file as they are pushed
+        if self.file.as_deref() == Some(path) {
+            return Ok(());
+        }
+        Ok(self.write_file(path, true)?)
+    }
+    fn load(&mut self, path: &Path) -> rustyline::Result<()> {
+        Ok(self.load_file(path)?)
+    }
+    fn clear(&mut self) -> rustyline::Result<()> {
+        self.entries.clear();
+        self.reset_navigation();
+        Ok(())
+    }
+    fn search(
+        &self,
+        term: &str,
+        start: usize,
+        dir: SearchDirection,
+    ) -> rustyline::Result<Option<SearchResult<'_>>> {
+        if term.is_empty() {
+            return Ok(None);
+        }
+        Ok(self.search_with(start, dir, |entry| entry.find(term)))
+    }
+    fn starts_with(
+        &self,
+        term: &str,
+        start: usize,
+        dir: SearchDirection,
+    ) -> rustyline::Result<Option<SearchResult<'_>>> {
+        if term.is_empty() {
+            return Ok(None);
+        }
+        Ok(self.search_with(start, dir, |entry| {
+            entry.starts_with(term).then_some(term.len())
+        }))
+    }
+}
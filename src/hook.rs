@@ -0,0 +1,84 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::{ChatrMessage, Username};
+
+/// What a [`MessageHook`] wants done with a message
+#[derive(Debug, Clone)]
+pub enum HookAction {
+    /// Carry on with this message, the one given or a rewritten one
+    Pass(ChatrMessage),
+    /// Stop here, nothing else sees it
+    Drop,
+    /// Stop here and send these to the sender instead, no one else hears about it
+    Reply(Vec<ChatrMessage>),
+}
+
+/// Server plugin that sees what clients send before the chatroom does, for filters, bots and the
+/// like. Registered with [`ChatrServer::with_hook`](crate::server::ChatrServer::with_hook), hooks
+/// run in the order they were registered and each sees what the one before passed on.
+pub trait MessageHook: Send + 'static {
+    /// `username` sent `msg`, everything but Disconnect comes through here
+    fn on_message(&mut self, username: &Username, msg: ChatrMessage) -> HookAction {
+        let _ = username;
+        HookAction::Pass(msg)
+    }
+    /// `username` logged in, anything handed back is sent just to them after they've joined
+    fn on_login(&mut self, username: &Username) -> Vec<ChatrMessage> {
+        let _ = username;
+        Vec::new()
+    }
+    /// `username`'s connection ended, however it ended
+    fn on_disconnect(&mut self, username: &Username) {
+        let _ = username;
+    }
+}
+
+/// Hooks a server runs, cheap to clone and shared by the tasks that call them
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Arc<Mutex<Vec<Box<dyn MessageHook>>>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hooks({})", self.hooks.lock().unwrap().len())
+    }
+}
+
+impl Hooks {
+    /// Run `hook` after the ones already added
+    pub fn push(&self, hook: impl MessageHook) {
+        self.hooks.lock().unwrap().push(Box::new(hook));
+    }
+    /// Pass `msg` through every hook in turn, handing back what's left of it, if anything, and
+    /// what to send back to `username`
+    pub fn message(
+        &self,
+        username: &Username,
+        mut msg: ChatrMessage,
+    ) -> (Option<ChatrMessage>, Vec<ChatrMessage>) {
+        for hook in self.hooks.lock().unwrap().iter_mut() {
+            match hook.on_message(username, msg) {
+                HookAction::Pass(passed) => msg = passed,
+                HookAction::Drop => return (None, Vec::new()),
+                HookAction::Reply(replies) => return (None, replies),
+            }
+        }
+        (Some(msg), Vec::new())
+    }
+    /// Tell every hook `username` logged in, handing back what they want sent to them
+    pub fn login(&self, username: &Username) -> Vec<ChatrMessage> {
+        self.hooks
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .flat_map(|hook| hook.on_login(username))
+            .collect()
+    }
+    pub fn disconnect(&self, username: &Username) {
+        for hook in self.hooks.lock().unwrap().iter_mut() {
+            hook.on_disconnect(username);
+        }
+    }
+}
@@ -1,18 +1,67 @@
+use std::fmt;
+
 use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+
+use crate::{
+    e2e::{PublicKey, Sealed},
+    export::ExportQuery,
+    files::{Attachment, FileHash},
+    reaction::Reaction,
+    room::{Access, Role, RoomInfo, RoomName, Topic},
+    search::SearchQuery,
+};
+pub mod admin;
+pub mod audit;
+pub mod bot;
 pub mod chatroom;
 pub mod client;
+pub mod command;
+pub mod e2e;
+pub mod export;
+pub mod files;
+pub mod frame;
+pub mod history;
+pub mod hook;
+pub mod markup;
+pub mod message_log;
+pub mod metrics;
+pub mod reaction;
+pub mod room;
+pub mod search;
+pub mod server;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+pub mod time;
+pub mod typing;
 
 pub type Username = String;
 pub type Content = String;
+/// Assigned by the chatroom to every message it dispatches, increasing over time
+pub type MessageId = u64;
+/// Milliseconds since the unix epoch
+pub type Timestamp = u64;
+/// Tells logins apart, so whatever an old connection does can't touch a newer one under the
+/// same name
+pub type SessionId = u64;
 pub type SenderToClient = Sender<ChatrMessage>;
-pub type SenderToServer = Sender<(Username, ChatrMessage)>;
-pub type ReceiverFromClient = Receiver<(Username, ChatrMessage)>;
+pub type SenderToServer = Sender<(Username, SessionId, ChatrMessage)>;
+pub type ReceiverFromClient = Receiver<(Username, SessionId, ChatrMessage)>;
 pub type ReceiverFromServer = Receiver<ChatrMessage>;
 
+/// Username system notices are sent under
+pub const SYSTEM_USERNAME: &str = "chatr";
+/// Room everyone starts in
+pub const DEFAULT_ROOM: &str = "lobby";
+/// Longest message content the server takes, in bytes
+pub const MAX_CONTENT_LEN: usize = 16 * 1024;
+/// Longest username the server takes, in characters
+pub const MAX_USERNAME_LEN: usize = 32;
+
 /// Message schema
-#[derive(Debug, Clone, BorshDeserialize, BorshSerialize)]
+#[derive(Debug, Clone, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 pub enum ChatrMessage {
     /// LoginRequest sent when a user is trying to connect to the chatroom
     LoginRequest { username: String },
@@ -20,18 +69,252 @@ pub enum ChatrMessage {
     LoginAccepted,
     /// Received when user rejected, reason given
     LoginRejected { reason: String },
-    /// User sends message without username to save space
-    SentMessage { content: Content },
-    /// SentMessage becomes recieved message when the chatroom gets the message and then tags it
-    /// with the username
-    ReceivedMessage {
-        username: Username,
+    /// User sends message without username to save space, `parent` makes it a reply
+    SentMessage {
+        kind: MessageKind,
         content: Content,
+        parent: Option<MessageId>,
     },
-    /// Event emitted on user connection
+    /// SentMessage becomes recieved message when the chatroom gets the message and then tags it
+    /// with the username and an id
+    ReceivedMessage { message: ChatMessage },
+    /// Event emitted on user connection, for keeping rosters up to date
     UserConnected { username: Username },
-    /// Event emitted on user disconnection
+    /// Event emitted on user disconnection, for keeping rosters up to date
     UserDisconnected { username: Username },
     /// Received/Sent when some end of the connection is done
     Disconnect,
+    /// Asks the chatroom who is currently connected
+    RosterRequest,
+    /// Everyone currently connected with their status, sent on login and in reply to a
+    /// RosterRequest
+    Roster { members: Vec<(Username, Status)> },
+    /// User changes their own status
+    SetStatus { status: Status },
+    /// Event emitted when a user's status changes
+    StatusChanged { username: Username, status: Status },
+    /// User is typing, sent debounced while their message buffer changes
+    Typing,
+    /// Relayed Typing, shown until a timeout or their message arrives
+    UserTyping { username: Username },
+    /// Recent messages from before the user joined, oldest first, sent on login, split over
+    /// several when they're too long for one
+    History { messages: Vec<ChatMessage> },
+    /// Sent back when something the user asked for can't be done
+    Error { reason: String },
+    /// React to a message with an emoji or `:shortcode:`
+    React { id: MessageId, emoji: String },
+    /// Take back a reaction
+    Unreact { id: MessageId, emoji: String },
+    /// Event emitted when someone reacts to a message
+    ReactionAdded {
+        id: MessageId,
+        emoji: String,
+        username: Username,
+    },
+    /// Event emitted when someone takes back a reaction
+    ReactionRemoved {
+        id: MessageId,
+        emoji: String,
+        username: Username,
+    },
+    /// Start uploading a file, or resume an upload that got cut off
+    UploadStart {
+        name: String,
+        size: u64,
+        sha256: FileHash,
+    },
+    /// Server wants the file from `offset` on, where an earlier attempt stopped
+    UploadReady { sha256: FileHash, offset: u64 },
+    /// Piece of an upload, sent in order
+    UploadChunk {
+        sha256: FileHash,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Ask for a shared file from `offset` on
+    DownloadRequest { sha256: FileHash, offset: u64 },
+    /// Piece of a download, the last one reaches `size`
+    DownloadChunk {
+        sha256: FileHash,
+        offset: u64,
+        size: u64,
+        data: Vec<u8>,
+    },
+    /// Look through the chatroom history
+    Search { query: SearchQuery },
+    /// Page of hits for a Search, newest first, `more` when there's another page
+    SearchResults {
+        query: SearchQuery,
+        hits: Vec<ChatMessage>,
+        more: bool,
+    },
+    /// Ask for the history matching `query` to save locally
+    Export { query: ExportQuery },
+    /// Part of the history for an Export, oldest first, `done` on the last part
+    ExportMessages {
+        messages: Vec<ChatMessage>,
+        done: bool,
+    },
+    /// Server's message of the day, sent right after LoginAccepted
+    Motd { text: String },
+    /// Move to another room, making it if there's no room by that name, `password` for
+    /// password protected rooms
+    Join {
+        room: RoomName,
+        password: Option<String>,
+    },
+    /// Now in `room`, its history follows
+    Joined { room: RoomInfo },
+    /// Change the topic of the room you're in, `None` clears it
+    SetTopic { topic: Option<String> },
+    /// Topic of `room` changed
+    TopicChanged {
+        room: RoomName,
+        topic: Option<Topic>,
+    },
+    /// Ask for every room
+    RoomsRequest,
+    /// Every room, in reply to a RoomsRequest
+    RoomList { rooms: Vec<RoomInfo> },
+    /// Couldn't join `room`, and why
+    JoinRejected { room: RoomName, reason: String },
+    /// Change who can join the room you're in, operators only
+    SetAccess {
+        access: Access,
+        password: Option<String>,
+    },
+    /// Let a user into the room you're in, operators only
+    Invite { username: Username },
+    /// Take back a user's invite or operator role in the room you're in, operators only
+    Revoke { username: Username },
+    /// Make a user an operator of the room you're in or not, owner only
+    SetRole {
+        username: Username,
+        role: Option<Role>,
+    },
+    /// You were invited to `room`
+    Invited { room: RoomName, by: Username },
+    /// Key others should seal direct messages to us with, sent after login
+    PublishKey { key: PublicKey },
+    /// Ask for the key a user published
+    KeyRequest { username: Username },
+    /// Key `username` published, if they have
+    PeerKey {
+        username: Username,
+        key: Option<PublicKey>,
+    },
+    /// Direct message sealed for `to`, the server can't read it
+    SendDirect { to: Username, sealed: Sealed },
+    /// Sealed direct message passed on by the server
+    ReceivedDirect {
+        from: Username,
+        sent_at: Timestamp,
+        sealed: Sealed,
+    },
+    /// Direct message in the clear, only between an app and its
+    /// [`ClientConnection`](client::ClientConnection) which seals it into a SendDirect
+    Whisper { to: Username, content: Content },
+    /// Direct message opened by the [`ClientConnection`](client::ClientConnection), or one we
+    /// sent, `verified` when the other side's key has been checked
+    Whispered {
+        from: Username,
+        to: Username,
+        sent_at: Timestamp,
+        content: Content,
+        verified: bool,
+    },
+}
+
+/// Message as dispatched by the chatroom and kept in its history
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    /// When the chatroom got it
+    pub sent_at: Timestamp,
+    /// Room it was sent in
+    pub room: RoomName,
+    pub username: Username,
+    pub kind: MessageKind,
+    pub content: Content,
+    /// Message this replies to
+    pub parent: Option<MessageId>,
+    /// Reactions so far, in the order each emoji was first used
+    pub reactions: Vec<Reaction>,
+    /// File shared with this message
+    pub attachment: Option<Attachment>,
+}
+
+/// What sort of message some content is, decides how clients show it
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+)]
+pub enum MessageKind {
+    /// Plain chat
+    #[default]
+    Normal,
+    /// Emote describing what the user is doing, sent with `/me`
+    Action,
+    /// Informational message that bots and scripts shouldn't reply to
+    Notice,
+    /// Generated by the server for joins, topic changes, moderation and the like, clients
+    /// can't send these
+    System,
+}
+
+/// How available a user is
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+)]
+pub enum Presence {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+/// Presence along with an optional custom status message
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub struct Status {
+    pub presence: Presence,
+    pub text: Option<String>,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Away => write!(f, "away"),
+            Presence::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.text {
+            Some(text) => write!(f, "{}: {text}", self.presence),
+            None => write!(f, "{}", self.presence),
+        }
+    }
 }
@@ -0,0 +1,290 @@
+/// Inline styling a span of message content can have
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InlineStyle {
+    /// `**bold**`
+    pub bold: bool,
+    /// `*italic*` or `_italic_`
+    pub italic: bool,
+    /// `` `code` ``, never has any other style
+    pub code: bool,
+    /// `~~strikethrough~~`
+    pub strikethrough: bool,
+}
+
+/// Piece of message content sharing one style
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StyledSpan {
+    pub text: String,
+    pub style: InlineStyle,
+    /// Target when the span is a `[text](url)` link or a bare url
+    pub link: Option<String>,
+    /// Who is mentioned when the span is an `@username`
+    pub mention: Option<String>,
+}
+
+/// Line of message content, or a fenced code block spanning several lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkupBlock {
+    Line(Vec<StyledSpan>),
+    Code {
+        /// Whatever followed the opening fence
+        lang: Option<String>,
+        lines: Vec<String>,
+    },
+}
+
+/// Url schemes allowed in links, anything else stays plain text
+const LINK_SCHEMES: [&str; 3] = ["https://", "http://", "mailto:"];
+const FENCE: &str = "```";
+/// Furthest a link or styled span reaches, longer ones stay plain text. Keeps parsing linear
+/// however many delimiters are left unclosed.
+const MAX_SPAN_LEN: usize = 1024;
+
+/// Parse message content into lines of styled spans and fenced code blocks
+///
+/// Only a small, safe subset is understood: bold, italic, inline code, strikethrough, links,
+/// `@username` mentions and ``` fences. Control characters are replaced so content can't drive
+/// the terminal. Unclosed delimiters are left as plain text, an unclosed fence runs to the end
+/// of the content.
+pub fn parse(content: &str) -> Vec<MarkupBlock> {
+    let content = sanitize(content);
+    let mut blocks = Vec::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        match line.trim_start().strip_prefix(FENCE) {
+            Some(lang) => {
+                let lang = lang.trim();
+                let code = lines
+                    .by_ref()
+                    .take_while(|line| !line.trim_start().starts_with(FENCE))
+                    .map(str::to_string)
+                    .collect();
+                blocks.push(MarkupBlock::Code {
+                    lang: (!lang.is_empty()).then(|| lang.to_string()),
+                    lines: code,
+                });
+            }
+            None => blocks.push(MarkupBlock::Line(parse_inline(line))),
+        }
+    }
+    blocks
+}
+
+/// Parse one line of content into styled spans
+pub fn parse_inline(line: &str) -> Vec<StyledSpan> {
+    let mut spans = Vec::new();
+    parse_spans(&sanitize(line), InlineStyle::default(), None, &mut spans);
+    spans
+}
+
+/// Content with control characters swapped out, tabs become spaces
+pub fn sanitize(content: &str) -> String {
+    content
+        .chars()
+        .map(|c| match c {
+            '\n' => '\n',
+            '\t' => ' ',
+            c if c.is_control() => char::REPLACEMENT_CHARACTER,
+            c => c,
+        })
+        .collect()
+}
+
+/// Usernames mentioned with `@username` in content, outside of code
+pub fn mentions(content: &str) -> Vec<String> {
+    parse(content)
+        .into_iter()
+        .filter_map(|block| match block {
+            MarkupBlock::Line(spans) => Some(spans),
+            MarkupBlock::Code { .. } => None,
+        })
+        .flatten()
+        .filter_map(|span| span.mention)
+        .collect()
+}
+
+/// Whether content mentions `username`
+pub fn mentions_user(content: &str, username: &str) -> bool {
+    mentions(content).iter().any(|mention| mention == username)
+}
+
+fn parse_spans(text: &str, style: InlineStyle, prev: Option<char>, out: &mut Vec<StyledSpan>) {
+    let mut plain = String::new();
+    let mut prev = prev;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some((span_text, link, after)) = link_at(rest) {
+            flush(&mut plain, style, out);
+            out.push(StyledSpan {
+                text: span_text.to_string(),
+                style,
+                link: Some(link.to_string()),
+                mention: None,
+            });
+            prev = span_text.chars().last();
+            rest = after;
+            continue;
+        }
+        if let Some((username, after)) = mention_at(rest, prev) {
+            flush(&mut plain, style, out);
+            out.push(StyledSpan {
+                text: format!("@{username}"),
+                style,
+                link: None,
+                mention: Some(username.to_string()),
+            });
+            prev = username.chars().last();
+            rest = after;
+            continue;
+        }
+        if let Some((inner, inner_style, after)) = delimited_at(rest, style, prev) {
+            flush(&mut plain, style, out);
+            if inner_style.code {
+                out.push(StyledSpan {
+                    text: inner.to_string(),
+                    style: inner_style,
+                    ..Default::default()
+                });
+            } else {
+                parse_spans(inner, inner_style, prev, out);
+            }
+            prev = rest[..rest.len() - after.len()].chars().last();
+            rest = after;
+            continue;
+        }
+        plain.push(c);
+        prev = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    flush(&mut plain, style, out);
+}
+
+fn flush(plain: &mut String, style: InlineStyle, out: &mut Vec<StyledSpan>) {
+    if !plain.is_empty() {
+        out.push(StyledSpan {
+            text: std::mem::take(plain),
+            style,
+            ..Default::default()
+        });
+    }
+}
+
+/// `[text](url)` or a bare url at the start of `rest`, giving the text, url and what follows
+fn link_at(rest: &str) -> Option<(&str, &str, &str)> {
+    if let Some(after_bracket) = rest.strip_prefix('[') {
+        let close = within_span(after_bracket).find(']')?;
+        let text = &after_bracket[..close];
+        let after_text = after_bracket[close + 1..].strip_prefix('(')?;
+        let close = within_span(after_text).find(')')?;
+        let url = &after_text[..close];
+        if text.is_empty() || !is_link(url) {
+            return None;
+        }
+        return Some((text, url, &after_text[close + 1..]));
+    }
+    if !is_link(rest) {
+        return None;
+    }
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    // Trailing punctuation usually ends the sentence rather than the url
+    let url = rest[..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+    Some((url, url, &rest[url.len()..]))
+}
+
+/// As much of `text` as a span can reach
+fn within_span(text: &str) -> &str {
+    &text[..text.floor_char_boundary(MAX_SPAN_LEN)]
+}
+
+/// `@username` at the start of `rest`, not when it's part of a word like an email address
+fn mention_at(rest: &str, prev: Option<char>) -> Option<(&str, &str)> {
+    if prev.is_some_and(char::is_alphanumeric) {
+        return None;
+    }
+    let name = rest.strip_prefix('@')?;
+    let end = name
+        .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+        .unwrap_or(name.len());
+    // A trailing dot ends the sentence
+    let name = name[..end].trim_end_matches('.');
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, &rest[1 + name.len()..]))
+}
+
+fn is_link(text: &str) -> bool {
+    LINK_SCHEMES
+        .iter()
+        .any(|scheme| text.starts_with(scheme) && text.len() > scheme.len())
+}
+
+/// Delimited run at the start of `rest`, giving its inner text, the style inside it and what
+/// follows the closing delimiter
+fn delimited_at(
+    rest: &str,
+    style: InlineStyle,
+    prev: Option<char>,
+) -> Option<(&str, InlineStyle, &str)> {
+    let (delim, inner_style) = if rest.starts_with('`') {
+        (
+            "`",
+            InlineStyle {
+                code: true,
+                ..InlineStyle::default()
+            },
+        )
+    } else if rest.starts_with("**") {
+        ("**", InlineStyle { bold: true, ..style })
+    } else if rest.starts_with("~~") {
+        (
+            "~~",
+            InlineStyle {
+                strikethrough: true,
+                ..style
+            },
+        )
+    } else if rest.starts_with('*') {
+        ("*", InlineStyle { italic: true, ..style })
+    } else if rest.starts_with('_') && !prev.is_some_and(char::is_alphanumeric) {
+        // Only at word boundaries so snake_case stays as it is
+        ("_", InlineStyle { italic: true, ..style })
+    } else {
+        return None;
+    };
+    let body = &rest[delim.len()..];
+    if body.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let close = find_closing(within_span(body), delim)?;
+    let inner = &body[..close];
+    let after = &body[close + delim.len()..];
+    if inner.is_empty() || inner.ends_with(char::is_whitespace) {
+        return None;
+    }
+    if delim == "_" && after.starts_with(char::is_alphanumeric) {
+        return None;
+    }
+    Some((inner, inner_style, after))
+}
+
+/// Where `delim` closes in `body`, skipping over code spans and, for `*`, over `**`
+fn find_closing(body: &str, delim: &str) -> Option<usize> {
+    let mut i = 0;
+    while i < body.len() {
+        let rest = &body[i..];
+        if delim != "`" && rest.starts_with('`') {
+            i += 1 + rest[1..].find('`')? + 1;
+            continue;
+        }
+        if delim == "*" && rest.starts_with("**") {
+            i += 2 + rest[2..].find("**")? + 2;
+            continue;
+        }
+        if rest.starts_with(delim) {
+            return Some(i);
+        }
+        i += rest.chars().next()?.len_utf8();
+    }
+    None
+}
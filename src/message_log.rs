@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::iter::Peekable;
+
+use crate::{
+    ChatMessage, Content, MessageId, MessageKind, Username, frame::MAX_FRAME_LEN, room::RoomName,
+    time,
+};
+
+/// Most messages the chatroom keeps by default
+pub const DEFAULT_LOG_CAPACITY: usize = 10_000;
+/// How many recent messages a user gets sent when they join
+pub const BACKLOG_LEN: usize = 50;
+/// Roughly how much history goes in one message, leaving room under the frame limit
+pub const PAGE_BYTES: usize = MAX_FRAME_LEN / 2;
+
+/// Take up to `max_len` of `messages`, no more than fit in [`PAGE_BYTES`] but always at least
+/// one so a page never comes back empty while there's more
+pub fn next_page<'a>(
+    messages: &mut Peekable<impl Iterator<Item = &'a ChatMessage>>,
+    max_len: usize,
+) -> Vec<ChatMessage> {
+    let encoded_len = |message: &ChatMessage| borsh::object_length(message).unwrap_or(0);
+    let mut page = Vec::new();
+    let mut page_bytes = 0;
+    while page.len() < max_len
+        && let Some(message) = messages
+            .next_if(|message| page.is_empty() || page_bytes + encoded_len(message) <= PAGE_BYTES)
+    {
+        page_bytes += encoded_len(message);
+        page.push(message.clone());
+    }
+    page
+}
+
+/// Chatroom history, hands out message ids and forgets the oldest messages past its capacity
+#[derive(Debug)]
+pub struct MessageLog {
+    messages: VecDeque<ChatMessage>,
+    next_id: MessageId,
+    capacity: usize,
+}
+
+impl Default for MessageLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOG_CAPACITY)
+    }
+}
+
+impl MessageLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            next_id: 1,
+            capacity,
+        }
+    }
+    /// Give a message the next id and remember it
+    pub fn append(
+        &mut self,
+        room: RoomName,
+        username: Username,
+        kind: MessageKind,
+        content: Content,
+        parent: Option<MessageId>,
+    ) -> &mut ChatMessage {
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.messages.push_back(ChatMessage {
+            id,
+            sent_at: time::now(),
+            room,
+            username,
+            kind,
+            content,
+            parent,
+            reactions: Vec::new(),
+            attachment: None,
+        });
+        self.messages.back_mut().unwrap()
+    }
+    pub fn get(&self, id: MessageId) -> Option<&ChatMessage> {
+        // Ids only go up so the log is always sorted by them
+        self.messages
+            .binary_search_by_key(&id, |m| m.id)
+            .ok()
+            .map(|i| &self.messages[i])
+    }
+    pub fn get_mut(&mut self, id: MessageId) -> Option<&mut ChatMessage> {
+        let i = self.messages.binary_search_by_key(&id, |m| m.id).ok()?;
+        self.messages.get_mut(i)
+    }
+    pub fn contains(&self, id: MessageId) -> bool {
+        self.get(id).is_some()
+    }
+    /// The last `n` messages sent in `room`, oldest first
+    pub fn recent(&self, room: &str, n: usize) -> Vec<ChatMessage> {
+        let mut recent: Vec<ChatMessage> = self
+            .messages
+            .iter()
+            .rev()
+            .filter(|m| m.room == room)
+            .take(n)
+            .cloned()
+            .collect();
+        recent.reverse();
+        recent
+    }
+    /// Everything still kept, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &ChatMessage> {
+        self.messages.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc::WeakSender,
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{ChatrMessage, SenderToClient, Username};
+
+/// How far back the per second rates look
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// How often counters get sampled for the rates
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Biggest request head the metrics endpoint reads before giving up on it
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Counters and gauges for a running server, shared by the chatroom and every connection
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    /// Messages read from clients
+    messages_in: AtomicU64,
+    /// Messages written to clients
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Messages that never made it to where they were going
+    dropped: AtomicU64,
+    login_rejections: Mutex<BTreeMap<String, u64>>,
+    /// Queue to each connected client, weak so a client going away isn't held up by it
+    queues: Mutex<BTreeMap<Username, WeakSender<ChatrMessage>>>,
+    /// When and how many messages had gone in and out, oldest first
+    samples: Mutex<VecDeque<(Instant, u64, u64)>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            login_rejections: Mutex::new(BTreeMap::new()),
+            queues: Mutex::new(BTreeMap::new()),
+            samples: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+/// Everything in [`Metrics`] at one moment, for the admin socket
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSummary {
+    pub uptime_secs: u64,
+    pub connected_clients: usize,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub messages_in_per_sec: f64,
+    pub messages_out_per_sec: f64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub dropped_messages: u64,
+    /// Rejected logins by why
+    pub login_rejections: BTreeMap<String, u64>,
+    /// Messages waiting to be written to each client
+    pub queue_depths: BTreeMap<Username, usize>,
+}
+
+impl Metrics {
+    pub fn message_in(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn message_out(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn dropped(&self, messages: usize) {
+        self.dropped.fetch_add(messages as u64, Ordering::Relaxed);
+    }
+    /// Login turned away, `reason` like `banned`
+    pub fn login_rejected(&self, reason: &str) {
+        *self
+            .login_rejections
+            .lock()
+            .unwrap()
+            .entry(reason.to_string())
+            .or_default() += 1;
+    }
+    pub fn client_connected(&self, username: &Username, sender: &SenderToClient) {
+        self.queues
+            .lock()
+            .unwrap()
+            .insert(username.clone(), sender.downgrade());
+    }
+    pub fn client_disconnected(&self, username: &Username) {
+        self.queues.lock().unwrap().remove(username);
+    }
+    pub fn clients_cleared(&self) {
+        self.queues.lock().unwrap().clear();
+    }
+    /// Sample the counters every second so rates can be worked out, until `ct` is cancelled
+    pub fn run_sampler(self: &Arc<Self>, ct: CancellationToken) -> JoinHandle<()> {
+        let metrics = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => metrics.sample(Instant::now()),
+                    _ = ct.cancelled() => break,
+                }
+            }
+        })
+    }
+    fn sample(&self, now: Instant) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((
+            now,
+            self.messages_in.load(Ordering::Relaxed),
+            self.messages_out.load(Ordering::Relaxed),
+        ));
+        while samples
+            .front()
+            .is_some_and(|(at, ..)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            samples.pop_front();
+        }
+    }
+    /// Messages in and out per second over the last [`RATE_WINDOW`], since starting if
+    /// nothing has been sampled
+    fn rates(&self) -> (f64, f64) {
+        let now = Instant::now();
+        let messages_in = self.messages_in.load(Ordering::Relaxed);
+        let messages_out = self.messages_out.load(Ordering::Relaxed);
+        let (since, before_in, before_out) = self
+            .samples
+            .lock()
+            .unwrap()
+            .front()
+            .copied()
+            .unwrap_or((self.started, 0, 0));
+        let secs = now.duration_since(since).as_secs_f64();
+        if secs <= 0.0 {
+            return (0.0, 0.0);
+        }
+        (
+            (messages_in - before_in) as f64 / secs,
+            (messages_out - before_out) as f64 / secs,
+        )
+    }
+    fn queue_depths(&self) -> BTreeMap<Username, usize> {
+        self.queues
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(username, queue)| {
+                let queue = queue.upgrade()?;
+                Some((username.clone(), queue.max_capacity() - queue.capacity()))
+            })
+            .collect()
+    }
+    pub fn summary(&self) -> MetricsSummary {
+        let (messages_in_per_sec, messages_out_per_sec) = self.rates();
+        let queue_depths = self.queue_depths();
+        MetricsSummary {
+            uptime_secs: self.started.elapsed().as_secs(),
+            connected_clients: queue_depths.len(),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            messages_in_per_sec,
+            messages_out_per_sec,
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            dropped_messages: self.dropped.load(Ordering::Relaxed),
+            login_rejections: self.login_rejections.lock().unwrap().clone(),
+            queue_depths,
+        }
+    }
+    /// Everything in the Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let summary = self.summary();
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, String)>| {
+            let _ = writeln!(text, "# HELP chatr_{name} {help}");
+            let _ = writeln!(text, "# TYPE chatr_{name} {kind}");
+            for (labels, value) in values {
+                let _ = writeln!(text, "chatr_{name}{labels} {value}");
+            }
+        };
+        let one = |value: String| vec![(String::new(), value)];
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since the server started",
+            one(summary.uptime_secs.to_string()),
+        );
+        metric(
+            "connected_clients",
+            "gauge",
+            "Clients connected right now",
+            one(summary.connected_clients.to_string()),
+        );
+        metric(
+            "messages_in_total",
+            "counter",
+            "Messages read from clients",
+            one(summary.messages_in.to_string()),
+        );
+        metric(
+            "messages_out_total",
+            "counter",
+            "Messages written to clients",
+            one(summary.messages_out.to_string()),
+        );
+        metric(
+            "messages_in_per_second",
+            "gauge",
+            "Messages read from clients per second, over the last 10 seconds",
+            one(summary.messages_in_per_sec.to_string()),
+        );
+        metric(
+            "messages_out_per_second",
+            "gauge",
+            "Messages written to clients per second, over the last 10 seconds",
+            one(summary.messages_out_per_sec.to_string()),
+        );
+        metric(
+            "bytes_in_total",
+            "counter",
+            "Bytes read from clients",
+            one(summary.bytes_in.to_string()),
+        );
+        metric(
+            "bytes_out_total",
+            "counter",
+            "Bytes written to clients",
+            one(summary.bytes_out.to_string()),
+        );
+        metric(
+            "dropped_messages_total",
+            "counter",
+            "Messages that never made it to where they were going",
+            one(summary.dropped_messages.to_string()),
+        );
+        metric(
+            "login_rejections_total",
+            "counter",
+            "Logins turned away, by reason",
+            summary
+                .login_rejections
+                .iter()
+                .map(|(reason, count)| {
+                    (
+                        format!("{{reason=\"{}\"}}", label(reason)),
+                        count.to_string(),
+                    )
+                })
+                .collect(),
+        );
+        metric(
+            "client_queue_depth",
+            "gauge",
+            "Messages waiting to be written to each client",
+            summary
+                .queue_depths
+                .iter()
+                .map(|(username, depth)| {
+                    (
+                        format!("{{username=\"{}\"}}", label(username)),
+                        depth.to_string(),
+                    )
+                })
+                .collect(),
+        );
+        text
+    }
+    /// Answer `GET /metrics` on `addr` with [`Metrics::prometheus`] until `ct` is cancelled
+    pub async fn serve(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        ct: CancellationToken,
+    ) -> io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = ct.cancelled() => break,
+                };
+                let mut stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::error!("metrics accept failed {e}");
+                        break;
+                    }
+                };
+                let metrics = self.clone();
+                tokio::spawn(async move {
+                    let response = match read_request_line(&mut stream).await {
+                        Ok(line) if line.starts_with("GET /metrics ") => {
+                            http_response("200 OK", &metrics.prometheus())
+                        }
+                        Ok(_) => http_response("404 Not Found", "try /metrics\n"),
+                        Err(e) => {
+                            tracing::debug!("bad metrics request {e}");
+                            return;
+                        }
+                    };
+                    if let Err(e) = stream.write_all(response.as_bytes()).await {
+                        tracing::debug!("metrics response failed {e}");
+                    }
+                });
+            }
+        }))
+    }
+}
+
+/// First line of an HTTP request, once the whole head has arrived
+async fn read_request_line(stream: &mut tokio::net::TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD || stream.read_buf(&mut head).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "incomplete request",
+            ));
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    Ok(head.lines().next().unwrap_or_default().to_string())
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Label value with quotes and backslashes escaped
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,95 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::{ChatMessage, Username};
+
+/// Longest an emoji can be in bytes, enough for flags and skin tone sequences
+pub const MAX_EMOJI_LEN: usize = 32;
+/// Most different emoji one message can collect
+pub const MAX_EMOJI_PER_MESSAGE: usize = 20;
+/// Reactions clients offer on a single key, in order
+pub const QUICK_REACTIONS: [&str; 5] = ["👍", "❤️", "😂", "🎉", "👀"];
+
+/// Shortcodes understood in place of the emoji itself
+const SHORTCODES: [(&str, &str); 14] = [
+    ("+1", "👍"),
+    ("thumbsup", "👍"),
+    ("-1", "👎"),
+    ("thumbsdown", "👎"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("smile", "😄"),
+    ("tada", "🎉"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("rocket", "🚀"),
+    ("check", "✅"),
+    ("x", "❌"),
+    ("thinking", "🤔"),
+];
+
+/// Everyone who reacted to a message with one emoji
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    /// In the order they reacted
+    pub users: Vec<Username>,
+}
+
+/// The emoji a reaction means, `:shortcode:` or the emoji itself, `None` for anything else
+pub fn resolve(emoji: &str) -> Option<String> {
+    let emoji = emoji.trim();
+    if let Some(code) = emoji.strip_prefix(':').and_then(|e| e.strip_suffix(':')) {
+        return SHORTCODES
+            .iter()
+            .find(|(shortcode, _)| *shortcode == code)
+            .map(|(_, emoji)| emoji.to_string());
+    }
+    // Emoji aren't alphanumeric, which keeps reactions from being used as tiny messages
+    let valid = !emoji.is_empty()
+        && emoji.len() <= MAX_EMOJI_LEN
+        && !emoji
+            .chars()
+            .any(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_control() || c.is_ascii());
+    valid.then(|| emoji.to_string())
+}
+
+impl ChatMessage {
+    /// Add `username`'s reaction, returning whether they hadn't already reacted with it
+    pub fn add_reaction(&mut self, emoji: &str, username: &Username) -> bool {
+        match self.reactions.iter_mut().find(|r| r.emoji == emoji) {
+            Some(reaction) if reaction.users.contains(username) => false,
+            Some(reaction) => {
+                reaction.users.push(username.clone());
+                true
+            }
+            None => {
+                self.reactions.push(Reaction {
+                    emoji: emoji.to_string(),
+                    users: vec![username.clone()],
+                });
+                true
+            }
+        }
+    }
+    /// Take back `username`'s reaction, returning whether there was one
+    pub fn remove_reaction(&mut self, emoji: &str, username: &Username) -> bool {
+        let Some(i) = self.reactions.iter().position(|r| r.emoji == emoji) else {
+            return false;
+        };
+        let reaction = &mut self.reactions[i];
+        let before = reaction.users.len();
+        reaction.users.retain(|u| u != username);
+        let removed = before != reaction.users.len();
+        if reaction.users.is_empty() {
+            self.reactions.remove(i);
+        }
+        removed
+    }
+    /// Whether `username` reacted with `emoji`
+    pub fn has_reaction(&self, emoji: &str, username: &Username) -> bool {
+        self.reactions
+            .iter()
+            .any(|r| r.emoji == emoji && r.users.contains(username))
+    }
+}
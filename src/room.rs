@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use borsh::{BorshDeserialize, BorshSerialize};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+
+use crate::{DEFAULT_ROOM, Timestamp, Username, time};
+
+/// Longest room name allowed
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+/// Longest topic allowed, in characters
+pub const MAX_TOPIC_LEN: usize = 300;
+/// Most rooms there can be, joining a new one past this is turned away. Empty rooms nobody set
+/// anything up in are dropped, so only rooms in use or set up count.
+pub const MAX_ROOMS: usize = 1000;
+/// Most rooms one user can own
+pub const MAX_OWNED_ROOMS: usize = 10;
+/// Most password checks one user gets in [`PASSWORD_TRY_WINDOW`], joining or setting one
+pub const MAX_PASSWORD_TRIES: u32 = 5;
+pub const PASSWORD_TRY_WINDOW: Duration = Duration::from_secs(60);
+
+pub type RoomName = String;
+
+/// What a room is about, and who said so
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct Topic {
+    pub text: String,
+    pub set_by: Username,
+    pub set_at: Timestamp,
+}
+
+/// Who can join a room
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+)]
+pub enum Access {
+    /// Anyone
+    #[default]
+    Public,
+    /// Only those invited
+    InviteOnly,
+    /// Anyone with the password, or invited
+    Password,
+}
+
+/// Standing in a room beyond being allowed in, operators can change the topic, access and who
+/// is invited, the owner can also make operators
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub enum Role {
+    Owner,
+    Operator,
+}
+
+/// Room as clients see it
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: RoomName,
+    pub topic: Option<Topic>,
+    pub access: Access,
+    /// Who is in it, sorted
+    pub members: Vec<Username>,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Public => write!(f, "public"),
+            Access::InviteOnly => write!(f, "invite only"),
+            Access::Password => write!(f, "password protected"),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Owner => write!(f, "owner"),
+            Role::Operator => write!(f, "operator"),
+        }
+    }
+}
+
+/// Room name typed by a user, without the leading `#`, if it's allowed: letters, digits, `-`
+/// and `_`, up to [`MAX_ROOM_NAME_LEN`]
+pub fn room_name(name: &str) -> Option<RoomName> {
+    let name = name.trim().trim_start_matches('#').to_lowercase();
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_ROOM_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(name)
+}
+
+/// Who may do what in a room, kept across restarts
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Acl {
+    pub access: Access,
+    /// Argon2 hash of the password as a PHC string, salt and all, for [`Access::Password`]
+    password: Option<String>,
+    roles: BTreeMap<Username, Role>,
+    invited: BTreeSet<Username>,
+}
+
+impl Acl {
+    fn owned_by(username: &Username) -> Self {
+        Self {
+            roles: BTreeMap::from([(username.clone(), Role::Owner)]),
+            ..Self::default()
+        }
+    }
+    pub fn role(&self, username: &Username) -> Option<Role> {
+        self.roles.get(username).copied()
+    }
+    /// Let in without needing the password
+    fn welcome(&self, username: &Username) -> bool {
+        self.access == Access::Public
+            || self.roles.contains_key(username)
+            || self.invited.contains(username)
+    }
+    /// Nothing here but the owner, so nothing to keep once the room is empty
+    fn untouched(&self) -> bool {
+        self.access == Access::Public
+            && self.invited.is_empty()
+            && self.roles.values().all(|role| *role == Role::Owner)
+    }
+}
+
+/// Whether `password` is the one hashed into `hash`. Slow on purpose, so it's kept off the
+/// chatroom's task.
+pub fn password_matches(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Argon2 hash of `password` with a random salt, as a PHC string. Slow on purpose like
+/// [`password_matches`].
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("couldn't hash the password: {e}"))
+}
+
+/// What gets saved for each room
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedRoom {
+    topic: Option<Topic>,
+    acl: Acl,
+}
+
+/// Chatroom's rooms, everyone is in exactly one of them and starts in [`DEFAULT_ROOM`]
+#[derive(Debug)]
+pub struct Rooms {
+    rooms: HashMap<RoomName, Room>,
+    /// Which room each user is in
+    joined: HashMap<Username, RoomName>,
+    /// Where topics and access are saved
+    path: Option<PathBuf>,
+    /// No new rooms once there are this many
+    max_rooms: usize,
+}
+
+#[derive(Debug, Default)]
+struct Room {
+    topic: Option<Topic>,
+    acl: Acl,
+    members: BTreeSet<Username>,
+}
+
+impl Room {
+    /// Nothing worth keeping once everyone has left
+    fn disposable(&self) -> bool {
+        self.topic.is_none() && self.acl.untouched()
+    }
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Self {
+            rooms: HashMap::from([(DEFAULT_ROOM.to_string(), Room::default())]),
+            joined: HashMap::new(),
+            path: None,
+            max_rooms: MAX_ROOMS,
+        }
+    }
+}
+
+impl Rooms {
+    /// Rooms saved at `path` by an earlier run, changes get saved back there
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut rooms = Self::default();
+        match std::fs::read_to_string(&path) {
+            Ok(saved) => {
+                let saved: BTreeMap<RoomName, SavedRoom> = serde_json::from_str(&saved)?;
+                for (name, SavedRoom { topic, acl }) in saved {
+                    let room = Room {
+                        topic,
+                        acl,
+                        members: BTreeSet::new(),
+                    };
+                    if !room.disposable() || name == DEFAULT_ROOM {
+                        rooms.rooms.insert(name, room);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        rooms.path = Some(path);
+        Ok(rooms)
+    }
+    /// Turn away joining new rooms once there are `max_rooms`, [`MAX_ROOMS`] otherwise
+    pub fn with_max_rooms(mut self, max_rooms: usize) -> Self {
+        self.max_rooms = max_rooms;
+        self
+    }
+    /// Write topics and access out, if loaded from a file, leaving out rooms with nothing to
+    /// keep
+    pub async fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let saved: BTreeMap<&RoomName, SavedRoom> = self
+            .rooms
+            .iter()
+            .filter(|(name, room)| !room.disposable() || *name == DEFAULT_ROOM)
+            .map(|(name, room)| {
+                let saved = SavedRoom {
+                    topic: room.topic.clone(),
+                    acl: room.acl.clone(),
+                };
+                (name, saved)
+            })
+            .collect();
+        let part = path.with_extension("part");
+        tokio::fs::write(&part, serde_json::to_string_pretty(&saved)?).await?;
+        tokio::fs::rename(part, path).await
+    }
+    /// Whether `username` may join `room`, new rooms are open to anyone while there's space for
+    /// them. Gives back the password hash the password they gave has to match, if they're only
+    /// let in with it, see [`password_matches`].
+    pub fn check_join(
+        &self,
+        username: &Username,
+        room: &str,
+        password: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        let Some(acl) = self.rooms.get(room).map(|r| &r.acl) else {
+            if self.rooms.len() >= self.max_rooms {
+                return Err("there are too many rooms to make another".to_string());
+            }
+            let owned = self
+                .rooms
+                .values()
+                .filter(|r| r.acl.role(username) == Some(Role::Owner))
+                .count();
+            if owned >= MAX_OWNED_ROOMS {
+                return Err(format!("you can't own more than {MAX_OWNED_ROOMS} rooms"));
+            }
+            return Ok(None);
+        };
+        if acl.welcome(username) {
+            return Ok(None);
+        }
+        match (acl.access, password, &acl.password) {
+            (Access::Password, Some(_), Some(hash)) => Ok(Some(hash.clone())),
+            (Access::Password, _, _) => Err(format!("#{room} needs a password")),
+            _ => Err(format!("#{room} is invite only")),
+        }
+    }
+    /// Hash of the password for `room`, if it has one
+    pub fn password_hash(&self, room: &str) -> Option<&str> {
+        self.rooms.get(room)?.acl.password.as_deref()
+    }
+    /// Move `username` into `room`, making it theirs if it's new, gives back the room they left
+    pub fn join(&mut self, username: &Username, room: &RoomName) -> Option<RoomName> {
+        let left = self.leave(username);
+        self.rooms
+            .entry(room.clone())
+            .or_insert_with(|| Room {
+                acl: Acl::owned_by(username),
+                ..Room::default()
+            })
+            .members
+            .insert(username.clone());
+        self.joined.insert(username.clone(), room.clone());
+        left
+    }
+    /// Take `username` out of whichever room they're in, gives back that room. It goes once
+    /// it's empty if nobody set anything up in it.
+    pub fn leave(&mut self, username: &Username) -> Option<RoomName> {
+        let room = self.joined.remove(username)?;
+        if let Some(r) = self.rooms.get_mut(&room) {
+            r.members.remove(username);
+            if r.members.is_empty() && r.disposable() && room != DEFAULT_ROOM {
+                self.rooms.remove(&room);
+            }
+        }
+        Some(room)
+    }
+    /// Room `username` is in, the lobby for anyone not yet placed
+    pub fn room_of(&self, username: &Username) -> &str {
+        self.joined.get(username).map_or(DEFAULT_ROOM, |r| r)
+    }
+    pub fn exists(&self, room: &str) -> bool {
+        self.rooms.contains_key(room)
+    }
+    pub fn members(&self, room: &str) -> impl Iterator<Item = &Username> {
+        self.rooms.get(room).into_iter().flat_map(|r| &r.members)
+    }
+    pub fn info(&self, room: &str) -> Option<RoomInfo> {
+        let r = self.rooms.get(room)?;
+        Some(RoomInfo {
+            name: room.to_string(),
+            topic: r.topic.clone(),
+            access: r.acl.access,
+            members: r.members.iter().cloned().collect(),
+        })
+    }
+    /// Every room, sorted by name
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let mut names: Vec<&RoomName> = self.rooms.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .filter_map(|name| self.info(name))
+            .collect()
+    }
+    pub fn role(&self, username: &Username, room: &str) -> Option<Role> {
+        self.rooms.get(room)?.acl.role(username)
+    }
+    /// Whether `username` may see what's been said in `room`, for searches and exports
+    pub fn can_read(&self, username: &Username, room: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|r| r.acl.welcome(username) || r.members.contains(username))
+    }
+    /// Whether `username` may change the topic of `room`, its operators if it has any,
+    /// otherwise anyone in it
+    pub fn can_set_topic(&self, username: &Username, room: &str) -> bool {
+        self.rooms.get(room).is_some_and(|r| {
+            if r.acl.roles.is_empty() {
+                r.members.contains(username)
+            } else {
+                r.acl.roles.contains_key(username)
+            }
+        })
+    }
+    /// Change or clear the topic of `room`, gives back the new one
+    pub fn set_topic(
+        &mut self,
+        username: &Username,
+        room: &str,
+        text: Option<String>,
+    ) -> Option<Topic> {
+        let topic = text.map(|text| Topic {
+            text,
+            set_by: username.clone(),
+            set_at: time::now(),
+        });
+        if let Some(r) = self.rooms.get_mut(room) {
+            r.topic = topic.clone();
+        }
+        topic
+    }
+    /// Whether `username` has the role `need`, or a better one, in `room`
+    pub fn check_role(&self, username: &Username, room: &str, need: Role) -> Result<(), String> {
+        if room == DEFAULT_ROOM {
+            return Err(format!("#{DEFAULT_ROOM} is open to everyone"));
+        }
+        let acl = self
+            .rooms
+            .get(room)
+            .map(|r| &r.acl)
+            .ok_or(format!("no room #{room}"))?;
+        match (acl.role(username), need) {
+            (Some(Role::Owner), _) | (Some(Role::Operator), Role::Operator) => Ok(()),
+            _ => Err(format!("you need to be {need} of #{room} for that")),
+        }
+    }
+    /// Access control of `room` if `username` is allowed to change it
+    fn acl_mut(&mut self, username: &Username, room: &str, need: Role) -> Result<&mut Acl, String> {
+        self.check_role(username, room, need)?;
+        Ok(&mut self.rooms.get_mut(room).unwrap().acl)
+    }
+    /// Change who can join `room`, a password hash from [`hash_password`] is needed for
+    /// [`Access::Password`]
+    pub fn set_access(
+        &mut self,
+        username: &Username,
+        room: &str,
+        access: Access,
+        password_hash: Option<String>,
+    ) -> Result<(), String> {
+        let acl = self.acl_mut(username, room, Role::Operator)?;
+        acl.password = match (access, password_hash) {
+            (Access::Password, Some(hash)) => Some(hash),
+            (Access::Password, None) => return Err("a password is needed".to_string()),
+            _ => None,
+        };
+        acl.access = access;
+        Ok(())
+    }
+    /// Let `invitee` into `room` however it's locked
+    pub fn invite(
+        &mut self,
+        username: &Username,
+        room: &str,
+        invitee: &Username,
+    ) -> Result<(), String> {
+        let acl = self.acl_mut(username, room, Role::Operator)?;
+        acl.invited.insert(invitee.clone());
+        Ok(())
+    }
+    /// Take back an invite and any operator role, gives back whether `revoked` is in `room`
+    /// and no longer allowed to be. Only the owner can revoke an operator
+    pub fn revoke(
+        &mut self,
+        username: &Username,
+        room: &str,
+        revoked: &Username,
+    ) -> Result<bool, String> {
+        let acl = self.acl_mut(username, room, Role::Operator)?;
+        match (acl.role(revoked), acl.role(username)) {
+            (Some(Role::Owner), _) => return Err(format!("{revoked} owns #{room}")),
+            (Some(Role::Operator), Some(Role::Operator)) => {
+                return Err(format!("you need to be owner of #{room} for that"));
+            }
+            _ => (),
+        }
+        acl.invited.remove(revoked);
+        acl.roles.remove(revoked);
+        let locked_out = !acl.welcome(revoked);
+        Ok(locked_out && self.room_of(revoked) == room)
+    }
+    /// Make `target` an operator of `room`, or not when `role` is `None`
+    pub fn set_role(
+        &mut self,
+        username: &Username,
+        room: &str,
+        target: &Username,
+        role: Option<Role>,
+    ) -> Result<(), String> {
+        let acl = self.acl_mut(username, room, Role::Owner)?;
+        match role {
+            _ if acl.role(target) == Some(Role::Owner) => {
+                return Err(format!("{target} owns #{room}"));
+            }
+            Some(Role::Owner) => return Err("rooms only have one owner".to_string()),
+            Some(role) => acl.roles.insert(target.clone(), role),
+            None => acl.roles.remove(target),
+        };
+        Ok(())
+    }
+}
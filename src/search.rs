@@ -0,0 +1,98 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::{ChatMessage, MessageKind, Timestamp, Username, message_log, room, time};
+
+/// Most hits sent back for one page of results
+pub const SEARCH_PAGE_LEN: usize = 20;
+
+/// What to look for in the chatroom history, every filter given has to match
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub struct SearchQuery {
+    /// Found anywhere in the content, ignoring case
+    pub text: String,
+    /// Only messages from this user
+    pub from: Option<Username>,
+    /// Only messages in this room
+    pub room: Option<String>,
+    /// Only messages sent at or after this time
+    pub after: Option<Timestamp>,
+    /// Only messages sent before this time
+    pub before: Option<Timestamp>,
+    /// Which page of results, newest first, starting from 0
+    pub page: u32,
+}
+
+impl SearchQuery {
+    /// Parse what follows `/search`, plain words are the text to find and these narrow it down:
+    /// `from:user`, `room:name`, `after:when` and `before:when` where `when` is a date like
+    /// `2025-01-31` or how long ago like `2h`
+    pub fn parse(args: &str) -> Option<Self> {
+        let now = time::now();
+        let mut query = SearchQuery::default();
+        let mut words = Vec::new();
+        for word in args.split_whitespace() {
+            match word.split_once(':') {
+                Some(("from", user)) if !user.is_empty() => {
+                    query.from = Some(user.trim_start_matches('@').to_string())
+                }
+                Some(("room", room)) => query.room = Some(room::room_name(room)?),
+                Some(("after", when)) => query.after = Some(time::parse_when(when, now)?),
+                Some(("before", when)) => query.before = Some(time::parse_when(when, now)?),
+                _ => words.push(word),
+            }
+        }
+        query.text = words.join(" ");
+        let empty = query.text.is_empty()
+            && query.from.is_none()
+            && query.room.is_none()
+            && query.after.is_none()
+            && query.before.is_none();
+        (!empty).then_some(query)
+    }
+    /// Whether a message matches, server notices never do
+    pub fn matches(&self, message: &ChatMessage) -> bool {
+        message.kind != MessageKind::System
+            && self
+                .from
+                .as_ref()
+                .is_none_or(|from| *from == message.username)
+            && self.room.as_ref().is_none_or(|room| *room == message.room)
+            && self.after.is_none_or(|after| message.sent_at >= after)
+            && self.before.is_none_or(|before| message.sent_at < before)
+            && (self.text.is_empty()
+                || message
+                    .content
+                    .to_lowercase()
+                    .contains(&self.text.to_lowercase()))
+    }
+    /// Same search, the page after
+    pub fn next_page(&self) -> Self {
+        Self {
+            page: self.page + 1,
+            ..self.clone()
+        }
+    }
+}
+
+/// One page of matches, newest first, from messages oldest first. Pages hold fewer than
+/// [`SEARCH_PAGE_LEN`] when long messages would make them too big to send.
+pub fn search<'a>(
+    messages: impl DoubleEndedIterator<Item = &'a ChatMessage>,
+    query: &SearchQuery,
+) -> (Vec<ChatMessage>, bool) {
+    let mut hits = messages
+        .rev()
+        .filter(|message| query.matches(message))
+        .peekable();
+    for _ in 0..query.page {
+        if message_log::next_page(&mut hits, SEARCH_PAGE_LEN).is_empty() {
+            break;
+        }
+    }
+    let page = message_log::next_page(&mut hits, SEARCH_PAGE_LEN);
+    let more = hits.peek().is_some();
+    (page, more)
+}
@@ -0,0 +1,292 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    sync::{mpsc, oneshot},
+    task::{AbortHandle, JoinHandle},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::{
+    ChatrMessage, ReceiverFromClient, SenderToServer, SessionId, Username,
+    admin::AdminSocket,
+    audit::Audit,
+    chatroom::{AdminMsg, BannedUsernames, Chatroom, accept_clients, route_client_messages},
+    files::FileStore,
+    hook::{Hooks, MessageHook},
+    metrics::Metrics,
+    room::Rooms,
+};
+
+/// Where shared files are kept unless told otherwise
+pub const DEFAULT_FILES_DIR: &str = "chatr_files";
+/// How long shutdown waits for connections to finish saying goodbye
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Whole chat server, set up with the `with_*` methods and started with [`ChatrServer::start`]
+pub struct ChatrServer {
+    listener: TcpListener,
+    banned_usernames: HashSet<Username>,
+    /// Bans made while running are written back here
+    ban_file: Option<PathBuf>,
+    rooms: Rooms,
+    motd: Option<String>,
+    files_dir: PathBuf,
+    /// No admin socket without one
+    admin_socket: Option<PathBuf>,
+    metrics: Arc<Metrics>,
+    /// Where Prometheus metrics get served, if anywhere
+    metrics_addr: Option<SocketAddr>,
+    audit: Audit,
+    hooks: Hooks,
+}
+
+impl ChatrServer {
+    /// Server for clients connecting to `listener`, with no bans, rooms that aren't saved, no
+    /// admin socket and no audit log
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            banned_usernames: HashSet::new(),
+            ban_file: None,
+            rooms: Rooms::default(),
+            motd: None,
+            files_dir: PathBuf::from(DEFAULT_FILES_DIR),
+            admin_socket: None,
+            metrics: Arc::default(),
+            metrics_addr: None,
+            audit: Audit::default(),
+            hooks: Hooks::default(),
+        }
+    }
+    /// Listen on `addr`, port 0 picks a free one
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        TcpListener::bind(addr).await.map(Self::new)
+    }
+    /// Turn these users away
+    pub fn with_banned_usernames(mut self, usernames: impl IntoIterator<Item = Username>) -> Self {
+        self.banned_usernames.extend(usernames);
+        self
+    }
+    /// Turn away the comma separated users in `path`, writing bans made while running back to it
+    pub fn with_ban_file(mut self, path: PathBuf) -> io::Result<Self> {
+        let banned = std::fs::read_to_string(&path)?;
+        self.banned_usernames.extend(
+            banned
+                .split(",")
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+        );
+        self.ban_file = Some(path);
+        Ok(self)
+    }
+    /// Rooms to start with, like ones loaded from an earlier run
+    pub fn with_rooms(mut self, rooms: Rooms) -> Self {
+        self.rooms = rooms;
+        self
+    }
+    /// Message of the day everyone gets when they join
+    pub fn with_motd(mut self, motd: Option<String>) -> Self {
+        self.motd = motd;
+        self
+    }
+    pub fn with_files_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.files_dir = dir.into();
+        self
+    }
+    /// Listen for `chatr-admin` on this Unix socket
+    pub fn with_admin_socket(mut self, path: Option<PathBuf>) -> Self {
+        self.admin_socket = path;
+        self
+    }
+    /// Count into `metrics` instead of a fresh set
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    /// Serve Prometheus metrics over HTTP on `addr`
+    pub fn with_metrics_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.metrics_addr = addr;
+        self
+    }
+    /// Record logins, disconnects and moderation in `audit`
+    pub fn with_audit(mut self, audit: Audit) -> Self {
+        self.audit = audit;
+        self
+    }
+    /// Run `hook` on what clients send, after any hooks already added
+    pub fn with_hook(self, hook: impl MessageHook) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+    /// Start every task the server runs on the current runtime
+    pub async fn start(self) -> io::Result<ServerHandle> {
+        let Self {
+            listener,
+            banned_usernames,
+            ban_file,
+            rooms,
+            motd,
+            files_dir,
+            admin_socket,
+            metrics,
+            metrics_addr,
+            audit,
+            hooks,
+        } = self;
+        let addr = listener.local_addr()?;
+        let banned_usernames: BannedUsernames = Arc::new(RwLock::new(banned_usernames));
+        let cancel = CancellationToken::new();
+        let mut tasks = vec![metrics.run_sampler(cancel.clone())];
+        if let Some(addr) = metrics_addr {
+            tasks.push(metrics.clone().serve(addr, cancel.clone()).await?);
+        }
+        // Channels for comms
+        let (sender_to_chatroom, receiver_from_clients): (SenderToServer, ReceiverFromClient) =
+            mpsc::channel(1024);
+        let (admin_send, admin_recv) = mpsc::channel::<AdminMsg>(1024);
+        let chatroom = Chatroom::new()
+            .with_rooms(rooms)
+            .with_motd(motd)
+            .with_metrics(metrics.clone())
+            .with_audit(audit.clone())
+            .with_hooks(hooks.clone())
+            .run(admin_recv, cancel.clone());
+        tasks.push(chatroom);
+        // File transfers get their own task so big uploads don't hold up chat
+        let (files_send, files_recv) = mpsc::channel::<(Username, SessionId, ChatrMessage)>(1024);
+        tasks.push(FileStore::new(files_dir).run(files_recv, admin_send.clone(), cancel.clone()));
+        let stopped = CancellationToken::new();
+        // Commands from chatr-admin
+        if let Some(path) = &admin_socket {
+            let admin = AdminSocket::new(
+                path.clone(),
+                admin_send.clone(),
+                banned_usernames.clone(),
+                stopped.clone(),
+            )
+            .with_ban_file(ban_file)
+            .with_metrics(metrics.clone())
+            .with_audit(audit.clone())
+            .run(cancel.clone())?;
+            tasks.push(admin);
+        }
+        // Fan in listener for all clients/users
+        tasks.push(route_client_messages(
+            receiver_from_clients,
+            admin_send.clone(),
+            files_send,
+            hooks,
+            cancel.clone(),
+        ));
+        // Socket listener accepting new connections
+        let connections = TaskTracker::new();
+        let accepting = accept_clients(
+            listener,
+            sender_to_chatroom,
+            admin_send.clone(),
+            banned_usernames.clone(),
+            metrics.clone(),
+            audit.clone(),
+            connections.clone(),
+        );
+        let stop_accepting = accepting.abort_handle();
+        tasks.push(accepting);
+        Ok(ServerHandle {
+            addr,
+            admin_send,
+            banned_usernames,
+            metrics,
+            admin_socket,
+            audit,
+            stopped,
+            cancel,
+            stop_accepting,
+            connections,
+            tasks: Mutex::new(tasks),
+        })
+    }
+}
+
+/// Running [`ChatrServer`]
+pub struct ServerHandle {
+    addr: SocketAddr,
+    admin_send: mpsc::Sender<AdminMsg>,
+    banned_usernames: BannedUsernames,
+    metrics: Arc<Metrics>,
+    admin_socket: Option<PathBuf>,
+    audit: Audit,
+    /// Cancelled once shut down
+    stopped: CancellationToken,
+    /// Cancelled once everyone is disconnected, stopping every task in `tasks`
+    cancel: CancellationToken,
+    /// Accept loop, aborted on shutdown
+    stop_accepting: AbortHandle,
+    /// Tasks of logged in clients
+    connections: TaskTracker,
+    /// Every other task the server started, joined on shutdown
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ServerHandle {
+    /// Address clients connect to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// Talk to the chatroom directly, like the admin socket does
+    pub fn admin(&self) -> mpsc::Sender<AdminMsg> {
+        self.admin_send.clone()
+    }
+    pub fn banned_usernames(&self) -> BannedUsernames {
+        self.banned_usernames.clone()
+    }
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+    /// Wait for the server to be shut down, by [`ServerHandle::shutdown`] or through the admin
+    /// socket
+    pub async fn stopped(&self) {
+        self.stopped.cancelled().await
+    }
+    /// Stop taking new clients, disconnect everyone, save the rooms, stop every task the server
+    /// started and wait for the audit log to have it all
+    pub async fn shutdown(&self) {
+        self.stop_accepting.abort();
+        self.stopped.cancel();
+        let (reply, done) = oneshot::channel();
+        if self
+            .admin_send
+            .send(AdminMsg::Shutdown(reply))
+            .await
+            .is_ok()
+        {
+            let _ = done.await;
+        }
+        // Connections record their disconnects as they wind down
+        self.connections.close();
+        if tokio::time::timeout(SHUTDOWN_GRACE, self.connections.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!("gave up waiting for connections to close");
+        }
+        self.cancel.cancel();
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in tasks {
+            if let Err(e) = task.await
+                && e.is_panic()
+            {
+                tracing::error!("server task panicked {e}");
+            }
+        }
+        self.audit.flush().await;
+        if let Some(path) = &self.admin_socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    ChatrMessage, MessageKind, Username,
+    chatroom::{AdminMsg, BannedUsernames},
+    client::{ClientConnection, ConnectionTasks},
+    metrics::Metrics,
+    server::{ChatrServer, ServerHandle},
+};
+
+/// How long [`TestClient::recv`] waits before deciding nothing is coming
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps the file stores of servers started by one test binary apart
+static SERVERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whole server running in the test's runtime on an ephemeral port, stopping with the runtime
+pub struct TestServer {
+    handle: ServerHandle,
+    /// Shared files go here, removed on drop
+    files_dir: PathBuf,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with_banned(&[]).await
+    }
+    /// Server that turns away `banned`
+    pub async fn start_with_banned(banned: &[&str]) -> Self {
+        Self::start_with(|server| {
+            server.with_banned_usernames(banned.iter().map(|username| username.to_string()))
+        })
+        .await
+    }
+    /// Server set up by `setup`, which gets one with its own file store
+    pub async fn start_with(setup: impl FnOnce(ChatrServer) -> ChatrServer) -> Self {
+        let files_dir = std::env::temp_dir().join(format!(
+            "chatr-test-{}-{}",
+            std::process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let server = ChatrServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_files_dir(&files_dir);
+        let handle = setup(server).start().await.unwrap();
+        Self { handle, files_dir }
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.handle.local_addr()
+    }
+    /// Talk to the chatroom directly, like the admin socket does
+    pub fn admin(&self) -> mpsc::Sender<AdminMsg> {
+        self.handle.admin()
+    }
+    pub fn banned_usernames(&self) -> BannedUsernames {
+        self.handle.banned_usernames()
+    }
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.handle.metrics()
+    }
+    /// Disconnect everyone and stop taking new clients
+    pub async fn shutdown(&self) {
+        self.handle.shutdown().await
+    }
+    /// Log in as `username`, panicking if that fails
+    pub async fn connect(&self, username: &str) -> TestClient {
+        match self.try_connect(username).await {
+            Ok(client) => client,
+            Err(e) => panic!("{username} couldn't log in: {e}"),
+        }
+    }
+    /// Log in as `username`, the first message received is whatever follows LoginAccepted
+    pub async fn try_connect(&self, username: &str) -> io::Result<TestClient> {
+        let mut connection = ClientConnection::new(&self.addr().to_string()).await?;
+        connection.login(username.to_string()).await?;
+        let (to_app, from_server) = mpsc::channel(1024);
+        let (to_server, from_app) = mpsc::channel(1024);
+        let ct = CancellationToken::new();
+        let tasks = connection.run(to_app, from_app, ct.clone());
+        Ok(TestClient {
+            username: username.to_string(),
+            to_server,
+            from_server,
+            ct,
+            tasks,
+        })
+    }
+    /// Log in as `username` and read everything up to their own joined notice, for tests that
+    /// don't care how logging in goes
+    pub async fn join(&self, username: &str) -> TestClient {
+        let mut client = self.connect(username).await;
+        let joined = format!("ReceivedMessage chatr: {username} joined");
+        while describe(&client.recv().await) != joined {}
+        client
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.files_dir);
+    }
+}
+
+/// Logged in client driven by a test
+pub struct TestClient {
+    pub username: Username,
+    to_server: mpsc::Sender<ChatrMessage>,
+    from_server: mpsc::Receiver<ChatrMessage>,
+    ct: CancellationToken,
+    tasks: ConnectionTasks,
+}
+
+impl TestClient {
+    pub async fn send(&self, msg: ChatrMessage) {
+        self.to_server.send(msg).await.unwrap()
+    }
+    /// Send plain chat
+    pub async fn say(&self, content: &str) {
+        self.send(ChatrMessage::SentMessage {
+            kind: MessageKind::Normal,
+            content: content.to_string(),
+            parent: None,
+        })
+        .await
+    }
+    /// Next message from the server, panicking after [`RECV_TIMEOUT`] or if the connection is
+    /// gone
+    pub async fn recv(&mut self) -> ChatrMessage {
+        match tokio::time::timeout(RECV_TIMEOUT, self.from_server.recv()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => panic!("{} lost the connection", self.username),
+            Err(_) => panic!("{} got nothing for {RECV_TIMEOUT:?}", self.username),
+        }
+    }
+    /// Next `n` messages from the server
+    pub async fn recv_n(&mut self, n: usize) -> Vec<ChatrMessage> {
+        let mut msgs = Vec::with_capacity(n);
+        for _ in 0..n {
+            msgs.push(self.recv().await);
+        }
+        msgs
+    }
+    /// Assert the next messages are exactly `expected`, as [`describe`] puts them
+    pub async fn expect(&mut self, expected: &[&str]) {
+        let received: Vec<String> = self
+            .recv_n(expected.len())
+            .await
+            .iter()
+            .map(describe)
+            .collect();
+        assert_eq!(received, expected, "messages to {}", self.username);
+    }
+    /// Assert nothing arrives for `wait`
+    pub async fn expect_quiet(&mut self, wait: Duration) {
+        if let Ok(Some(msg)) = tokio::time::timeout(wait, self.from_server.recv()).await {
+            panic!("{} got {} out of nowhere", self.username, describe(&msg));
+        }
+    }
+    /// Log out the way apps do, by cancelling the connection, and wait for it to close
+    pub async fn disconnect(self) {
+        self.ct.cancel();
+        self.tasks.finished().await;
+    }
+}
+
+/// Short description of `msg` leaving out ids, timestamps and the like, so tests can compare
+/// what arrived without caring when
+pub fn describe(msg: &ChatrMessage) -> String {
+    match msg {
+        ChatrMessage::ReceivedMessage { message } => {
+            format!("ReceivedMessage {}: {}", message.username, message.content)
+        }
+        ChatrMessage::UserConnected { username } => format!("UserConnected {username}"),
+        ChatrMessage::UserDisconnected { username } => format!("UserDisconnected {username}"),
+        ChatrMessage::Roster { members } => {
+            let usernames: Vec<&str> = members.iter().map(|(u, _)| u.as_str()).collect();
+            format!("Roster {}", usernames.join(", "))
+        }
+        ChatrMessage::Joined { room } => format!("Joined {}", room.name),
+        ChatrMessage::History { messages } => format!("History {}", messages.len()),
+        ChatrMessage::Motd { text } => format!("Motd {text}"),
+        ChatrMessage::Error { reason } => format!("Error {reason}"),
+        msg => format!("{msg:?}"),
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::Username;
+
+/// Least time between typing signals a client sends while someone keeps typing
+pub const TYPING_SEND_INTERVAL: Duration = Duration::from_secs(3);
+/// How long a user shows as typing after their last signal
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Least time between typing signals the chatroom relays for one user
+pub const TYPING_RELAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Debounces typing signals on the sending side
+#[derive(Debug, Default)]
+pub struct TypingDebounce {
+    last_sent: Option<Instant>,
+}
+
+impl TypingDebounce {
+    /// Whether a signal should go out for an edit made at `now`
+    pub fn should_send(&mut self, now: Instant) -> bool {
+        if self
+            .last_sent
+            .is_some_and(|last| now.duration_since(last) < TYPING_SEND_INTERVAL)
+        {
+            return false;
+        }
+        self.last_sent = Some(now);
+        true
+    }
+    /// Message was sent, the next edit signals straight away
+    pub fn reset(&mut self) {
+        self.last_sent = None;
+    }
+}
+
+/// Who is typing on the receiving side, entries time out after [`TYPING_TIMEOUT`]
+#[derive(Debug, Default)]
+pub struct TypingUsers {
+    users: HashMap<Username, Instant>,
+}
+
+impl TypingUsers {
+    pub fn typing(&mut self, username: Username, now: Instant) {
+        self.users.insert(username, now + TYPING_TIMEOUT);
+    }
+    /// User sent their message or left
+    pub fn stopped(&mut self, username: &Username) {
+        self.users.remove(username);
+    }
+    /// Drop anyone who timed out, returning whether anyone did
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let before = self.users.len();
+        self.users.retain(|_, until| *until > now);
+        before != self.users.len()
+    }
+    /// When the next entry times out
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.users.values().min().copied()
+    }
+    /// Typing users in name order
+    pub fn usernames(&self) -> Vec<&Username> {
+        let mut usernames: Vec<&Username> = self.users.keys().collect();
+        usernames.sort();
+        usernames
+    }
+    /// Status line text such as "alice is typing…", `None` when nobody is
+    pub fn describe(&self) -> Option<String> {
+        match self.usernames().as_slice() {
+            [] => None,
+            [one] => Some(format!("{one} is typing…")),
+            [one, two] => Some(format!("{one} and {two} are typing…")),
+            _ => Some("several people are typing…".to_string()),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::os::unix::fs::PermissionsExt;
+
+use chatr::{
+    admin::{self, AdminRequest, AdminResponse},
+    audit::{Audit, AuditEvent, AuditRecord},
+    test_support::TestServer,
+};
+
+#[tokio::test]
+async fn only_the_owner_can_use_the_socket() {
+    let path = std::env::temp_dir().join(format!("chatr-admin-{}.sock", std::process::id()));
+    let socket = path.clone();
+    let server = TestServer::start_with(|server| server.with_admin_socket(Some(socket))).await;
+    let _alice = server.join("alice").await;
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert!(!path.with_extension("bind").exists());
+    match admin::request(&path, &AdminRequest::ListUsers)
+        .await
+        .unwrap()
+    {
+        AdminResponse::Users { users } => assert_eq!(users.len(), 1),
+        response => panic!("expected users, got {response:?}"),
+    }
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn shutdown_leaves_everyone_in_the_audit_log() {
+    let path = std::env::temp_dir().join(format!("chatr-audit-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let audit = Audit::open(path.clone(), 0, 0).await.unwrap();
+    let server = TestServer::start_with(|server| server.with_audit(audit)).await;
+    let _alice = server.join("alice").await;
+    let _bob = server.join("bob").await;
+    server.shutdown().await;
+    let log = std::fs::read_to_string(&path).unwrap();
+    let disconnects: Vec<_> = log
+        .lines()
+        .map(|line| serde_json::from_str::<AuditRecord>(line).unwrap().event)
+        .filter_map(|event| match event {
+            AuditEvent::Disconnect { username, .. } => Some(username),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(disconnects.len(), 2, "{log}");
+    let _ = std::fs::remove_file(&path);
+}
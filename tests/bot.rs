@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use chatr::{
+    ChatrMessage, MessageKind,
+    bot::Bot,
+    chatroom::AdminMsg,
+    test_support::{TestClient, TestServer},
+};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+fn echo_bot() -> Bot {
+    Bot::new("bot")
+        .with_reconnect_delay(Duration::from_millis(50))
+        .command("echo", "say it back", |request| async move {
+            request.reply(request.args.clone()).await
+        })
+        .on_mention(|request| async move {
+            let reply = format!("hi {}", request.message.username);
+            request.reply(reply).await
+        })
+}
+
+/// Start `bot` against `server`, waiting until `alice` sees it join
+async fn start(server: &TestServer, bot: Bot, alice: &mut TestClient) -> CancellationToken {
+    let ct = CancellationToken::new();
+    let host = server.addr().to_string();
+    tokio::spawn({
+        let ct = ct.clone();
+        async move { bot.run(&host, ct).await }
+    });
+    alice
+        .expect(&["UserConnected bot", "ReceivedMessage chatr: bot joined"])
+        .await;
+    ct
+}
+
+#[tokio::test]
+async fn commands_get_threaded_replies() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    start(&server, echo_bot(), &mut alice).await;
+    alice.say("!echo  hello there ").await;
+    let ChatrMessage::ReceivedMessage { message: asked } = alice.recv().await else {
+        panic!("expected alice's message");
+    };
+    let ChatrMessage::ReceivedMessage { message: reply } = alice.recv().await else {
+        panic!("expected the bot's reply");
+    };
+    assert_eq!(reply.username, "bot");
+    assert_eq!(reply.content, "hello there");
+    assert_eq!(reply.kind, MessageKind::Notice);
+    assert_eq!(reply.parent, Some(asked.id));
+}
+
+#[tokio::test]
+async fn help_and_unknown_commands() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    start(&server, echo_bot(), &mut alice).await;
+    alice.say("!help").await;
+    alice
+        .expect(&[
+            "ReceivedMessage alice: !help",
+            "ReceivedMessage bot: !help: list commands\n!echo: say it back",
+        ])
+        .await;
+    alice.say("!deploy").await;
+    alice
+        .expect(&[
+            "ReceivedMessage alice: !deploy",
+            "ReceivedMessage bot: don't know !deploy, try !help",
+        ])
+        .await;
+}
+
+#[tokio::test]
+async fn mentions_answered_but_not_notices() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    start(&server, echo_bot(), &mut alice).await;
+    alice.say("hey @bot").await;
+    alice
+        .expect(&[
+            "ReceivedMessage alice: hey @bot",
+            "ReceivedMessage bot: hi alice",
+        ])
+        .await;
+    alice
+        .send(ChatrMessage::SentMessage {
+            kind: MessageKind::Notice,
+            content: "!echo loop".to_string(),
+            parent: None,
+        })
+        .await;
+    alice.expect(&["ReceivedMessage alice: !echo loop"]).await;
+    alice.expect_quiet(Duration::from_millis(200)).await;
+}
+
+#[tokio::test]
+async fn scheduled_tasks_run() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let bot = echo_bot().every(Duration::from_millis(100), |bot| async move {
+        bot.say("tick").await
+    });
+    let ct = start(&server, bot, &mut alice).await;
+    alice
+        .expect(&["ReceivedMessage bot: tick", "ReceivedMessage bot: tick"])
+        .await;
+    ct.cancel();
+    alice
+        .expect(&["ReceivedMessage chatr: bot left", "UserDisconnected bot"])
+        .await;
+    alice.expect_quiet(Duration::from_millis(300)).await;
+}
+
+#[tokio::test]
+async fn reconnects_after_being_kicked() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    start(&server, echo_bot().with_room("ops"), &mut alice).await;
+    alice
+        .expect(&["ReceivedMessage chatr: bot left for #ops"])
+        .await;
+    let (reply, kicked) = oneshot::channel();
+    let kick = AdminMsg::Kick("bot".to_string(), "out".to_string(), reply);
+    server.admin().send(kick).await.unwrap();
+    assert!(kicked.await.unwrap());
+    alice
+        .expect(&[
+            "UserDisconnected bot",
+            "UserConnected bot",
+            "ReceivedMessage chatr: bot joined",
+            "ReceivedMessage chatr: bot left for #ops",
+        ])
+        .await;
+}
@@ -0,0 +1,315 @@
+use std::path::PathBuf;
+
+use bytes::BytesMut;
+use chatr::{
+    ChatrMessage, Status,
+    client::{ChatrClient, ClientEvent, Credentials},
+    e2e::Keyring,
+    frame,
+    test_support::{RECV_TIMEOUT, TestServer},
+};
+use futures_core::Stream;
+use tokio::net::TcpListener;
+
+/// Next event that `wanted` keeps, skipping the rest
+async fn next_matching<T>(
+    client: &mut ChatrClient,
+    wanted: impl Fn(ClientEvent) -> Option<T>,
+) -> T {
+    let find = async {
+        loop {
+            match client.next_event().await {
+                Some(event) => {
+                    if let Some(found) = wanted(event) {
+                        return found;
+                    }
+                }
+                None => panic!("{} lost the connection", client.username()),
+            }
+        }
+    };
+    tokio::time::timeout(RECV_TIMEOUT, find)
+        .await
+        .expect("nothing matching arrived")
+}
+
+async fn connect(server: &TestServer, username: &str) -> ChatrClient {
+    let credentials = Credentials::new(username);
+    ChatrClient::connect(&server.addr().to_string(), credentials)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn events_are_typed() {
+    let server = TestServer::start().await;
+    let mut alice = connect(&server, "alice").await;
+    let members = next_matching(&mut alice, |event| match event {
+        ClientEvent::Roster(members) => Some(members),
+        _ => None,
+    })
+    .await;
+    assert_eq!(members, vec![("alice".to_string(), Status::default())]);
+    let room = next_matching(&mut alice, |event| match event {
+        ClientEvent::Joined(room) => Some(room.name),
+        _ => None,
+    })
+    .await;
+    assert_eq!(room, "lobby");
+    alice.send_message("hello").await.unwrap();
+    let message = next_matching(&mut alice, |event| match event {
+        ClientEvent::Message(message) if message.username == "alice" => Some(message),
+        _ => None,
+    })
+    .await;
+    assert_eq!(message.content, "hello");
+}
+
+#[tokio::test]
+async fn join_and_reply() {
+    let server = TestServer::start().await;
+    let mut alice = connect(&server, "alice").await;
+    alice.join("games", None).await.unwrap();
+    let room = next_matching(&mut alice, |event| match event {
+        ClientEvent::Joined(room) if room.name == "games" => Some(room.name),
+        _ => None,
+    })
+    .await;
+    assert_eq!(room, "games");
+    alice.send_message("anyone?").await.unwrap();
+    let parent = next_matching(&mut alice, |event| match event {
+        ClientEvent::Message(message) if message.content == "anyone?" => Some(message.id),
+        _ => None,
+    })
+    .await;
+    alice.reply(parent, "guess not").await.unwrap();
+    let reply = next_matching(&mut alice, |event| match event {
+        ClientEvent::Message(message) if message.content == "guess not" => Some(message.parent),
+        _ => None,
+    })
+    .await;
+    assert_eq!(reply, Some(parent));
+}
+
+#[tokio::test]
+async fn direct_messages_are_opened() {
+    let server = TestServer::start().await;
+    let host = server.addr().to_string();
+    let alice = Credentials::new("alice").with_keyring(Keyring::new("alice".to_string()));
+    let alice = ChatrClient::connect(&host, alice).await.unwrap();
+    let bob = Credentials::new("bob").with_keyring(Keyring::new("bob".to_string()));
+    let mut bob = ChatrClient::connect(&host, bob).await.unwrap();
+    alice.dm("bob", "psst").await.unwrap();
+    let (from, content) = next_matching(&mut bob, |event| match event {
+        ClientEvent::Direct { from, content, .. } => Some((from, content)),
+        _ => None,
+    })
+    .await;
+    assert_eq!((from.as_str(), content.as_str()), ("alice", "psst"));
+}
+
+/// Empty directory of its own for keys
+fn keys_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chatr-keys-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[tokio::test]
+async fn changed_keys_hold_messages_until_verified() {
+    let server = TestServer::start().await;
+    let host = server.addr().to_string();
+    let dir = keys_dir("changed");
+    let alice = || {
+        let keyring = Keyring::load(dir.clone(), "alice".to_string()).unwrap();
+        ChatrClient::connect(&host, Credentials::new("alice").with_keyring(keyring))
+    };
+    let bob = || {
+        let keyring = Keyring::new("bob".to_string());
+        ChatrClient::connect(&host, Credentials::new("bob").with_keyring(keyring))
+    };
+    let direct = |event| match event {
+        ClientEvent::Direct { from, content, .. } => Some((from, content)),
+        _ => None,
+    };
+    let (first_alice, mut first_bob) = (alice().await.unwrap(), bob().await.unwrap());
+    first_alice.dm("bob", "hi").await.unwrap();
+    next_matching(&mut first_bob, direct).await;
+    first_alice.disconnect().await;
+    first_bob.disconnect().await;
+
+    // Bob comes back with a new identity, alice remembers the old one
+    let (mut alice, mut bob) = (alice().await.unwrap(), bob().await.unwrap());
+    alice.dm("bob", "still you?").await.unwrap();
+    let error = next_matching(&mut alice, |event| match event {
+        ClientEvent::Error(reason) => Some(reason),
+        _ => None,
+    })
+    .await;
+    assert!(error.contains("key changed"), "{error}");
+    let early = tokio::time::timeout(std::time::Duration::from_millis(200), async {
+        next_matching(&mut bob, direct).await
+    })
+    .await;
+    assert!(early.is_err(), "sealed to a key nobody verified");
+
+    assert!(alice.verify("bob").await.unwrap());
+    let (from, content) = next_matching(&mut bob, direct).await;
+    assert_eq!((from.as_str(), content.as_str()), ("alice", "still you?"));
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn keys_nobody_asked_for_are_ignored() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    let mallory = Keyring::new("mallory".to_string()).public();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = BytesMut::new();
+        frame::read_message(&mut stream, &mut buf).await.unwrap();
+        for msg in [
+            ChatrMessage::LoginAccepted,
+            ChatrMessage::PeerKey {
+                username: "bob".to_string(),
+                key: Some(mallory),
+            },
+            ChatrMessage::RoomList { rooms: Vec::new() },
+        ] {
+            frame::write_message(&mut stream, &msg).await.unwrap();
+        }
+        // Hold the connection open until the client is done
+        while let Ok(Some(_)) = frame::read_message(&mut stream, &mut buf).await {}
+    });
+    let alice = Credentials::new("alice").with_keyring(Keyring::new("alice".to_string()));
+    let mut alice = ChatrClient::connect(&host, alice).await.unwrap();
+    let first = next_matching(&mut alice, Some).await;
+    assert!(
+        matches!(first, ClientEvent::Other(ChatrMessage::RoomList { .. })),
+        "{first:?}"
+    );
+    assert!(
+        alice
+            .keyring()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .get("bob")
+            .is_none()
+    );
+}
+
+#[test]
+fn every_name_gets_its_own_key_files() {
+    let dir = keys_dir("names");
+    let load = |username: &str| Keyring::load(dir.clone(), username.to_string()).unwrap();
+    let names = ["alice", "x/alice", ".alice", "..", "ålice"];
+    let keys: Vec<_> = names.iter().map(|name| load(name).public()).collect();
+    for (i, key) in keys.iter().enumerate() {
+        assert!(!keys[..i].contains(key), "{} shares a key", names[i]);
+        assert_eq!(load(names[i]).public(), *key, "{} wasn't kept", names[i]);
+    }
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn everything_else_comes_through() {
+    let server = TestServer::start().await;
+    let mut alice = connect(&server, "alice").await;
+    alice.send(ChatrMessage::RoomsRequest).await.unwrap();
+    next_matching(&mut alice, |event| match event {
+        ClientEvent::Other(ChatrMessage::RoomList { .. }) => Some(()),
+        _ => None,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn dropping_disconnects() {
+    let server = TestServer::start().await;
+    let mut bob = server.join("bob").await;
+    let alice = connect(&server, "alice").await;
+    bob.expect(&["UserConnected alice", "ReceivedMessage chatr: alice joined"])
+        .await;
+    drop(alice);
+    bob.expect(&[
+        "ReceivedMessage chatr: alice left",
+        "UserDisconnected alice",
+    ])
+    .await;
+}
+
+#[tokio::test]
+async fn cancelling_ends_the_stream() {
+    let server = TestServer::start().await;
+    let mut alice = connect(&server, "alice").await;
+    alice.cancellation_token().cancel();
+    let ended = async {
+        loop {
+            let next = std::future::poll_fn(|cx| std::pin::Pin::new(&mut alice).poll_next(cx));
+            if next.await.is_none() {
+                break;
+            }
+        }
+    };
+    tokio::time::timeout(RECV_TIMEOUT, ended)
+        .await
+        .expect("stream didn't end");
+    assert!(alice.send_message("still here?").await.is_err());
+}
+
+#[tokio::test]
+async fn replayed_direct_messages_are_refused() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    let mut alice = Keyring::new("alice".to_string());
+    let bob = Keyring::new("bob".to_string());
+    alice.learn(&"bob".to_string(), bob.public()).unwrap();
+    let alice_key = alice.public();
+    let sealed = alice.seal("bob", "psst").unwrap();
+    let mut retimed = sealed.clone();
+    retimed.sealed_at += 1;
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = BytesMut::new();
+        frame::read_message(&mut stream, &mut buf).await.unwrap();
+        frame::write_message(&mut stream, &ChatrMessage::LoginAccepted)
+            .await
+            .unwrap();
+        // Once for real, then played back as is and with a new time
+        for sealed in [sealed.clone(), sealed, retimed] {
+            let msg = ChatrMessage::ReceivedDirect {
+                from: "alice".to_string(),
+                sent_at: 0,
+                sealed,
+            };
+            frame::write_message(&mut stream, &msg).await.unwrap();
+        }
+        while let Ok(Some(msg)) = frame::read_message(&mut stream, &mut buf).await {
+            if let ChatrMessage::KeyRequest { username } = msg {
+                let key = ChatrMessage::PeerKey {
+                    username,
+                    key: Some(alice_key),
+                };
+                frame::write_message(&mut stream, &key).await.unwrap();
+            }
+        }
+    });
+    let mut bob = ChatrClient::connect(&host, Credentials::new("bob").with_keyring(bob))
+        .await
+        .unwrap();
+    let mut got = Vec::new();
+    while got.len() < 3 {
+        got.push(
+            next_matching(&mut bob, |event| match event {
+                ClientEvent::Direct { content, .. } => Some(content),
+                ClientEvent::Error(reason) => Some(reason),
+                _ => None,
+            })
+            .await,
+        );
+    }
+    assert_eq!(got[0], "psst");
+    assert!(got[1].contains("replay"), "{}", got[1]);
+    assert!(got[2].contains("tampered"), "{}", got[2]);
+}
@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use bytes::BytesMut;
+use chatr::{
+    ChatrMessage, MessageKind,
+    chatroom::AdminMsg,
+    client::{ClientConnection, ConnectionTasks},
+    frame,
+    test_support::{RECV_TIMEOUT, TestServer, describe},
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+};
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn disconnect_tells_everyone() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    bob.disconnect().await;
+    alice
+        .expect(&["ReceivedMessage chatr: bob left", "UserDisconnected bob"])
+        .await;
+    alice.send(ChatrMessage::RosterRequest).await;
+    alice.expect(&["Roster alice"]).await;
+}
+
+#[tokio::test]
+async fn dropped_connection_tells_everyone() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    let login = ChatrMessage::LoginRequest {
+        username: "bob".to_string(),
+    };
+    frame::write_message(&mut stream, &login).await.unwrap();
+    let accepted = frame::read_message(&mut stream, &mut BytesMut::new()).await;
+    assert!(matches!(accepted, Ok(Some(ChatrMessage::LoginAccepted))));
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    // Gone without a word
+    drop(stream);
+    alice
+        .expect(&["ReceivedMessage chatr: bob left", "UserDisconnected bob"])
+        .await;
+}
+
+#[tokio::test]
+async fn kick_tells_them_why() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    let (reply, kicked) = oneshot::channel();
+    let kick = AdminMsg::Kick("bob".to_string(), "be nice".to_string(), reply);
+    server.admin().send(kick).await.unwrap();
+    assert!(kicked.await.unwrap());
+    bob.expect(&["Error be nice", "Disconnect"]).await;
+    alice
+        .expect(&[
+            "ReceivedMessage chatr: bob was kicked",
+            "UserDisconnected bob",
+        ])
+        .await;
+}
+
+#[tokio::test]
+async fn logging_in_twice_is_refused() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    let Err(e) = server.try_connect("bob").await else {
+        panic!("logged in over bob");
+    };
+    assert_eq!(e.to_string(), "bob is already logged in");
+    alice.expect_quiet(Duration::from_millis(200)).await;
+    // Once they've gone the name is free again
+    bob.disconnect().await;
+    alice
+        .expect(&["ReceivedMessage chatr: bob left", "UserDisconnected bob"])
+        .await;
+    let mut bob = server.join("bob").await;
+    alice.say("welcome back").await;
+    bob.expect(&["ReceivedMessage alice: welcome back"]).await;
+}
+
+#[tokio::test]
+async fn kicked_and_back_stays() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut first_bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    let (reply, kicked) = oneshot::channel();
+    let kick = AdminMsg::Kick("bob".to_string(), "be nice".to_string(), reply);
+    server.admin().send(kick).await.unwrap();
+    assert!(kicked.await.unwrap());
+    // Straight back in, while the old connection may still be closing
+    let mut bob = server.join("bob").await;
+    first_bob.expect(&["Error be nice", "Disconnect"]).await;
+    alice
+        .expect(&[
+            "ReceivedMessage chatr: bob was kicked",
+            "UserDisconnected bob",
+            "UserConnected bob",
+            "ReceivedMessage chatr: bob joined",
+        ])
+        .await;
+    alice.expect_quiet(Duration::from_millis(200)).await;
+    alice.say("welcome back").await;
+    bob.expect(&["ReceivedMessage alice: welcome back"]).await;
+}
+
+#[tokio::test]
+async fn can_log_back_in() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    server.join("bob").await.disconnect().await;
+    alice
+        .expect(&[
+            "UserConnected bob",
+            "ReceivedMessage chatr: bob joined",
+            "ReceivedMessage chatr: bob left",
+            "UserDisconnected bob",
+        ])
+        .await;
+    server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+}
+
+#[tokio::test]
+async fn shutdown_disconnects_everyone() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    server.shutdown().await;
+    alice
+        .expect(&["Error the server is shutting down", "Disconnect"])
+        .await;
+    assert!(server.try_connect("bob").await.is_err());
+}
+
+/// Logged in connection run by the test itself, along with `alice` hearing about it
+async fn run_bob(
+    server: &TestServer,
+    ct: CancellationToken,
+) -> (
+    mpsc::Sender<ChatrMessage>,
+    mpsc::Receiver<ChatrMessage>,
+    ConnectionTasks,
+) {
+    let mut connection = ClientConnection::new(&server.addr().to_string())
+        .await
+        .unwrap();
+    connection.login("bob".to_string()).await.unwrap();
+    let (to_app, from_server) = mpsc::channel(1024);
+    let (to_server, from_app) = mpsc::channel(1024);
+    let tasks = connection.run(to_app, from_app, ct);
+    (to_server, from_server, tasks)
+}
+
+#[tokio::test]
+async fn cancelling_logs_out() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let ct = CancellationToken::new();
+    let (_to_server, mut from_server, tasks) = run_bob(&server, ct.clone()).await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    ct.cancel();
+    tokio::time::timeout(RECV_TIMEOUT, tasks.finished())
+        .await
+        .expect("connection didn't close");
+    // What came before logging out is still there, then nothing
+    while from_server.recv().await.is_some() {}
+    alice
+        .expect(&["ReceivedMessage chatr: bob left", "UserDisconnected bob"])
+        .await;
+}
+
+#[tokio::test]
+async fn said_before_logging_out_still_goes() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let ct = CancellationToken::new();
+    let (to_server, _from_server, tasks) = run_bob(&server, ct.clone()).await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    for n in 0..10 {
+        let msg = ChatrMessage::SentMessage {
+            kind: MessageKind::Normal,
+            content: format!("bye {n}"),
+            parent: None,
+        };
+        to_server.send(msg).await.unwrap();
+    }
+    ct.cancel();
+    tasks.finished().await;
+    for n in 0..10 {
+        alice
+            .expect(&[&format!("ReceivedMessage bob: bye {n}")])
+            .await;
+    }
+    alice
+        .expect(&["ReceivedMessage chatr: bob left", "UserDisconnected bob"])
+        .await;
+}
+
+#[tokio::test]
+async fn app_going_away_logs_out() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let (to_server, from_server, tasks) = run_bob(&server, CancellationToken::new()).await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    drop((to_server, from_server));
+    tokio::time::timeout(RECV_TIMEOUT, tasks.finished())
+        .await
+        .expect("connection didn't close");
+    alice
+        .expect(&["ReceivedMessage chatr: bob left", "UserDisconnected bob"])
+        .await;
+}
+
+#[tokio::test]
+async fn hanging_up_mid_broadcast_keeps_the_server_up() {
+    let server = TestServer::start().await;
+    let alice = server.join("alice").await;
+    let mut streams = Vec::new();
+    for n in 0..20 {
+        let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+        let login = ChatrMessage::LoginRequest {
+            username: format!("user{n}"),
+        };
+        frame::write_message(&mut stream, &login).await.unwrap();
+        streams.push(stream);
+    }
+    for n in 0..200 {
+        alice.say(&format!("spam {n}")).await;
+        if n % 10 == 0 {
+            drop(streams.pop());
+        }
+    }
+    drop(streams);
+    let mut bob = server.join("bob").await;
+    bob.say("still up?").await;
+    // Past whoever is still leaving
+    while describe(&bob.recv().await) != "ReceivedMessage bob: still up?" {}
+}
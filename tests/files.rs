@@ -0,0 +1,157 @@
+use std::time::{Duration, SystemTime};
+
+use chatr::{
+    ChatrMessage,
+    files::{CHUNK_LEN, FileHash, FileStore, MAX_PENDING_UPLOADS, PART_MAX_AGE},
+    test_support::{RECV_TIMEOUT, TestClient, TestServer, describe},
+};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Two chunks of something to share, and its hash
+fn notes() -> (Vec<u8>, FileHash) {
+    let data: Vec<u8> = (0..CHUNK_LEN * 2).map(|i| (i % 251) as u8).collect();
+    let sha256 = Sha256::digest(&data).into();
+    (data, sha256)
+}
+
+/// Ask to upload `data` as notes.txt, and wait for the server to be ready for it from `from`
+async fn start_upload(client: &mut TestClient, data: &[u8], sha256: FileHash, from: u64) {
+    client
+        .send(ChatrMessage::UploadStart {
+            name: "notes.txt".to_string(),
+            size: data.len() as u64,
+            sha256,
+        })
+        .await;
+    match client.recv().await {
+        ChatrMessage::UploadReady { offset, .. } if offset == from => (),
+        msg => panic!("expected to upload from {from}, got {}", describe(&msg)),
+    }
+}
+
+async fn send_chunk(client: &TestClient, data: &[u8], sha256: FileHash, n: usize) {
+    let offset = n * CHUNK_LEN;
+    client
+        .send(ChatrMessage::UploadChunk {
+            sha256,
+            offset: offset as u64,
+            data: data[offset..offset + CHUNK_LEN].to_vec(),
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn same_file_at_once() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    let (data, sha256) = notes();
+    start_upload(&mut alice, &data, sha256, 0).await;
+    send_chunk(&alice, &data, sha256, 0).await;
+    // Starting again waits on the first chunk being written
+    start_upload(&mut alice, &data, sha256, CHUNK_LEN as u64).await;
+    start_upload(&mut bob, &data, sha256, 0).await;
+    for n in 0..2 {
+        send_chunk(&bob, &data, sha256, n).await;
+    }
+    send_chunk(&alice, &data, sha256, 1).await;
+    let mut shared: Vec<String> = alice.recv_n(2).await.iter().map(describe).collect();
+    shared.sort();
+    assert_eq!(
+        shared,
+        [
+            "ReceivedMessage alice: shared notes.txt (128.0 KiB)",
+            "ReceivedMessage bob: shared notes.txt (128.0 KiB)",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn knowing_the_hash_isnt_having_the_file() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let (data, sha256) = notes();
+    start_upload(&mut alice, &data, sha256, 0).await;
+    for n in 0..2 {
+        send_chunk(&alice, &data, sha256, n).await;
+    }
+    alice
+        .expect(&["ReceivedMessage alice: shared notes.txt (128.0 KiB)"])
+        .await;
+
+    let mut mallory = server.join("mallory").await;
+    alice
+        .expect(&[
+            "UserConnected mallory",
+            "ReceivedMessage chatr: mallory joined",
+        ])
+        .await;
+    start_upload(&mut mallory, &data, sha256, 0).await;
+    let garbage = vec![0; data.len()];
+    for n in 0..2 {
+        send_chunk(&mallory, &garbage, sha256, n).await;
+    }
+    mallory
+        .expect(&["Error notes.txt failed its checksum"])
+        .await;
+    alice
+        .expect_quiet(std::time::Duration::from_millis(100))
+        .await;
+}
+
+#[tokio::test]
+async fn uploads_on_the_go_are_capped() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let (data, _) = notes();
+    for n in 0..MAX_PENDING_UPLOADS {
+        start_upload(&mut alice, &data, [n as u8; 32], 0).await;
+    }
+    alice
+        .send(ChatrMessage::UploadStart {
+            name: "notes.txt".to_string(),
+            size: data.len() as u64,
+            sha256: [0xff; 32],
+        })
+        .await;
+    alice
+        .expect(&[&format!(
+            "Error you can only have {MAX_PENDING_UPLOADS} uploads going at once"
+        )])
+        .await;
+}
+
+#[tokio::test]
+async fn abandoned_uploads_get_cleared_out() {
+    let dir = std::env::temp_dir().join(format!("chatr-test-parts-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let old = dir.join("old.part");
+    let fresh = dir.join("fresh.part");
+    for path in [&old, &fresh] {
+        std::fs::write(path, b"half a file").unwrap();
+    }
+    std::fs::File::options()
+        .write(true)
+        .open(&old)
+        .unwrap()
+        .set_modified(SystemTime::now() - PART_MAX_AGE * 2)
+        .unwrap();
+    let (_files_send, files_recv) = mpsc::channel(1);
+    let (admin_send, _admin_recv) = mpsc::channel(1);
+    FileStore::new(&dir).run(files_recv, admin_send, CancellationToken::new());
+    let cleared = async {
+        while old.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(RECV_TIMEOUT, cleared)
+        .await
+        .expect("old upload wasn't cleared out");
+    assert!(fresh.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,188 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chatr::{
+    ChatrMessage, MessageKind, Username,
+    hook::{HookAction, MessageHook},
+    test_support::TestServer,
+};
+
+/// Stars out `word` in chat
+struct Filter {
+    word: &'static str,
+}
+
+impl MessageHook for Filter {
+    fn on_message(&mut self, _: &Username, msg: ChatrMessage) -> HookAction {
+        match msg {
+            ChatrMessage::SentMessage {
+                kind,
+                content,
+                parent,
+            } => HookAction::Pass(ChatrMessage::SentMessage {
+                kind,
+                content: content.replace(self.word, &"*".repeat(self.word.len())),
+                parent,
+            }),
+            msg => HookAction::Pass(msg),
+        }
+    }
+}
+
+/// Answers `!` commands itself and drops anything shouted
+struct Commands;
+
+impl MessageHook for Commands {
+    fn on_message(&mut self, _: &Username, msg: ChatrMessage) -> HookAction {
+        match &msg {
+            ChatrMessage::SentMessage { content, .. } if content.starts_with('!') => {
+                HookAction::Reply(vec![ChatrMessage::Error {
+                    reason: format!("unknown command {content}"),
+                }])
+            }
+            ChatrMessage::SentMessage { content, .. } if content == &content.to_uppercase() => {
+                HookAction::Drop
+            }
+            _ => HookAction::Pass(msg),
+        }
+    }
+}
+
+/// Writes down everything it sees
+#[derive(Clone, Default)]
+struct Recorder {
+    seen: Arc<Mutex<Vec<String>>>,
+}
+
+impl MessageHook for Recorder {
+    fn on_message(&mut self, username: &Username, msg: ChatrMessage) -> HookAction {
+        if let ChatrMessage::SentMessage { content, .. } = &msg {
+            self.seen
+                .lock()
+                .unwrap()
+                .push(format!("{username}: {content}"));
+        }
+        HookAction::Pass(msg)
+    }
+    fn on_login(&mut self, username: &Username) -> Vec<ChatrMessage> {
+        self.seen.lock().unwrap().push(format!("login {username}"));
+        vec![ChatrMessage::Motd {
+            text: format!("welcome {username}"),
+        }]
+    }
+    fn on_disconnect(&mut self, username: &Username) {
+        self.seen
+            .lock()
+            .unwrap()
+            .push(format!("disconnect {username}"));
+    }
+}
+
+#[tokio::test]
+async fn hooks_rewrite_drop_and_reply() {
+    let server = TestServer::start_with(|server| {
+        server
+            .with_hook(Filter { word: "darn" })
+            .with_hook(Commands)
+    })
+    .await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    bob.say("darn it").await;
+    bob.say("!deploy").await;
+    bob.say("HELLO").await;
+    bob.say("bye").await;
+    bob.expect(&[
+        "ReceivedMessage bob: **** it",
+        "Error unknown command !deploy",
+        "ReceivedMessage bob: bye",
+    ])
+    .await;
+    alice
+        .expect(&["ReceivedMessage bob: **** it", "ReceivedMessage bob: bye"])
+        .await;
+    alice.expect_quiet(Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn hooks_run_in_order() {
+    let recorder = Recorder::default();
+    let seen = recorder.seen.clone();
+    let server = TestServer::start_with(|server| {
+        server
+            .with_hook(Filter { word: "darn" })
+            .with_hook(recorder)
+            .with_hook(Commands)
+    })
+    .await;
+    let mut alice = server.join("alice").await;
+    alice.say("oh darn").await;
+    alice.say("!help").await;
+    alice
+        .expect(&[
+            "Motd welcome alice",
+            "ReceivedMessage alice: oh ****",
+            "Error unknown command !help",
+        ])
+        .await;
+    // The recorder sees what the filter passed on, including what the command hook answers
+    assert_eq!(
+        *seen.lock().unwrap(),
+        ["login alice", "alice: oh ****", "alice: !help"]
+    );
+}
+
+#[tokio::test]
+async fn hooks_hear_logins_and_disconnects_once() {
+    let recorder = Recorder::default();
+    let seen = recorder.seen.clone();
+    let server = TestServer::start_with(|server| server.with_hook(recorder)).await;
+    let mut alice = server.join("alice").await;
+    alice.expect(&["Motd welcome alice"]).await;
+    let bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    bob.disconnect().await;
+    alice
+        .expect(&["ReceivedMessage chatr: bob left", "UserDisconnected bob"])
+        .await;
+    alice.expect_quiet(Duration::from_millis(100)).await;
+    assert_eq!(
+        *seen.lock().unwrap(),
+        ["login alice", "login bob", "disconnect bob"]
+    );
+}
+
+#[tokio::test]
+async fn clients_cant_send_system_notices() {
+    let recorder = Recorder::default();
+    let server = TestServer::start_with(|server| server.with_hook(recorder)).await;
+    let mut alice = server.join("alice").await;
+    alice.expect(&["Motd welcome alice"]).await;
+    alice
+        .send(ChatrMessage::SentMessage {
+            kind: MessageKind::System,
+            content: "everyone is banned".to_string(),
+            parent: None,
+        })
+        .await;
+    let ChatrMessage::ReceivedMessage { message } = alice.recv().await else {
+        panic!("expected the message back");
+    };
+    assert_eq!(message.kind, MessageKind::Normal);
+}
+
+#[tokio::test]
+async fn shutdown_lets_go_of_hooks() {
+    let recorder = Recorder::default();
+    let hook = recorder.clone();
+    let server = TestServer::start_with(|server| server.with_hook(hook)).await;
+    let _alice = server.join("alice").await;
+    server.shutdown().await;
+    // Every task that could still call the hook has stopped
+    assert_eq!(Arc::strong_count(&recorder.seen), 1);
+}
@@ -0,0 +1,79 @@
+use std::io;
+
+use chatr::test_support::TestServer;
+
+#[tokio::test]
+async fn login_accepted() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    alice
+        .expect(&[
+            "Roster alice",
+            "UserConnected alice",
+            "Joined lobby",
+            "History 0",
+            "ReceivedMessage chatr: alice joined",
+        ])
+        .await;
+}
+
+#[tokio::test]
+async fn second_login_sees_the_first() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.connect("bob").await;
+    bob.expect(&[
+        "Roster alice, bob",
+        "UserConnected bob",
+        "Joined lobby",
+        "History 1",
+        "ReceivedMessage chatr: bob joined",
+    ])
+    .await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+}
+
+#[tokio::test]
+async fn banned_login_rejected() {
+    let server = TestServer::start_with_banned(&["mallory"]).await;
+    let Err(e) = server.try_connect("mallory").await else {
+        panic!("mallory got in");
+    };
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(e.to_string(), "mallory is not allowed");
+    assert_eq!(server.metrics().summary().login_rejections["banned"], 1);
+}
+
+#[tokio::test]
+async fn rejection_leaves_others_alone() {
+    let server = TestServer::start_with_banned(&["mallory"]).await;
+    let mut alice = server.join("alice").await;
+    assert!(server.try_connect("mallory").await.is_err());
+    // Later logins still get through
+    server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+}
+
+#[tokio::test]
+async fn names_have_to_be_usable() {
+    let server = TestServer::start().await;
+    for (username, reason) in [
+        ("", "usernames can't be empty"),
+        ("chatr", "chatr is taken by the server"),
+        (
+            "bob smith",
+            "usernames can't have spaces or control characters",
+        ),
+        (&"a".repeat(33), "usernames are at most 32 characters"),
+    ] {
+        let Err(e) = server.try_connect(username).await else {
+            panic!("{username:?} got in");
+        };
+        assert_eq!(e.to_string(), reason);
+    }
+    assert_eq!(server.metrics().summary().login_rejections["bad_name"], 4);
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use chatr::markup::{MarkupBlock, StyledSpan, parse};
+
+/// Far longer than parsing anything near this size should take
+const SLOW: Duration = Duration::from_secs(5);
+
+/// Parse `content` as one line, failing if it takes [`SLOW`] or longer
+fn parse_line(content: &str) -> Vec<StyledSpan> {
+    let start = Instant::now();
+    let mut blocks = parse(content);
+    assert!(start.elapsed() < SLOW, "took {:?}", start.elapsed());
+    match blocks.pop() {
+        Some(MarkupBlock::Line(spans)) if blocks.is_empty() => spans,
+        blocks => panic!("expected one line, got {blocks:?}"),
+    }
+}
+
+#[test]
+fn links() {
+    let spans = parse_line("see [the docs](https://example.com) or https://example.org.");
+    let links: Vec<(&str, Option<&str>)> = spans
+        .iter()
+        .map(|span| (span.text.as_str(), span.link.as_deref()))
+        .collect();
+    assert_eq!(
+        links,
+        vec![
+            ("see ", None),
+            ("the docs", Some("https://example.com")),
+            (" or ", None),
+            ("https://example.org", Some("https://example.org")),
+            (".", None),
+        ]
+    );
+}
+
+#[test]
+fn unclosed_brackets_stay_plain() {
+    let content = "[a ".repeat(64000);
+    let spans = parse_line(&content);
+    assert!(spans.iter().all(|span| span.link.is_none()));
+    let text: String = spans.iter().map(|span| span.text.as_str()).collect();
+    assert_eq!(text, content);
+}
+
+#[test]
+fn unclosed_emphasis_stays_plain() {
+    let content = "*a `b ".repeat(32000);
+    let spans = parse_line(&content);
+    assert!(spans.iter().all(|span| span.style == Default::default()));
+}
+
+#[test]
+fn spans_too_long_stay_plain() {
+    let long = "a".repeat(2000);
+    let spans = parse_line(&format!("[{long}](https://example.com) *{long}*"));
+    // The url still stands on its own
+    assert!(spans.iter().all(|span| span.text != long));
+    assert!(spans.iter().all(|span| !span.style.italic));
+}
@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use chatr::{
+    ChatrMessage, MAX_CONTENT_LEN,
+    chatroom::AdminMsg,
+    message_log::BACKLOG_LEN,
+    search::{SEARCH_PAGE_LEN, SearchQuery},
+    test_support::{TestClient, TestServer, describe},
+};
+
+/// Content of each chat message in `msgs`, as `username: content`
+fn said(msgs: &[ChatrMessage]) -> Vec<String> {
+    msgs.iter()
+        .map(|msg| match msg {
+            ChatrMessage::ReceivedMessage { message } => {
+                format!("{}: {}", message.username, message.content)
+            }
+            msg => panic!("expected a message, got {msg:?}"),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn everyone_gets_messages_in_order() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    for n in 0..20 {
+        alice.say(&n.to_string()).await;
+    }
+    let expected: Vec<String> = (0..20).map(|n| format!("alice: {n}")).collect();
+    assert_eq!(said(&alice.recv_n(20).await), expected);
+    assert_eq!(said(&bob.recv_n(20).await), expected);
+}
+
+#[tokio::test]
+async fn everyone_agrees_on_the_order() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    let mut carol = server.join("carol").await;
+    alice
+        .expect(&[
+            "UserConnected bob",
+            "ReceivedMessage chatr: bob joined",
+            "UserConnected carol",
+            "ReceivedMessage chatr: carol joined",
+        ])
+        .await;
+    bob.expect(&["UserConnected carol", "ReceivedMessage chatr: carol joined"])
+        .await;
+    // Both talk at once, however they interleave everyone sees the same thing
+    tokio::join!(
+        async {
+            for n in 0..10 {
+                alice.say(&format!("a{n}")).await;
+            }
+        },
+        async {
+            for n in 0..10 {
+                bob.say(&format!("b{n}")).await;
+            }
+        }
+    );
+    let seen = said(&carol.recv_n(20).await);
+    assert_eq!(said(&alice.recv_n(20).await), seen);
+    assert_eq!(said(&bob.recv_n(20).await), seen);
+    // Each sender's own messages stay in the order they were sent
+    for (username, prefix) in [("alice", "a"), ("bob", "b")] {
+        let theirs: Vec<&String> = seen
+            .iter()
+            .filter(|s| s.starts_with(&format!("{username}:")))
+            .collect();
+        let expected: Vec<String> = (0..10)
+            .map(|n| format!("{username}: {prefix}{n}"))
+            .collect();
+        assert_eq!(theirs, expected.iter().collect::<Vec<_>>());
+    }
+}
+
+#[tokio::test]
+async fn messages_stay_in_their_room() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    bob.send(ChatrMessage::Join {
+        room: "games".to_string(),
+        password: None,
+    })
+    .await;
+    alice
+        .expect(&["ReceivedMessage chatr: bob left for #games"])
+        .await;
+    bob.expect(&[
+        "Joined games",
+        "History 0",
+        "ReceivedMessage chatr: bob joined",
+    ])
+    .await;
+    bob.say("anyone here?").await;
+    bob.expect(&["ReceivedMessage bob: anyone here?"]).await;
+    alice.expect_quiet(Duration::from_millis(200)).await;
+}
+
+#[tokio::test]
+async fn admin_broadcast_reaches_every_room() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    bob.send(ChatrMessage::Join {
+        room: "games".to_string(),
+        password: None,
+    })
+    .await;
+    alice
+        .expect(&["ReceivedMessage chatr: bob left for #games"])
+        .await;
+    bob.expect(&[
+        "Joined games",
+        "History 0",
+        "ReceivedMessage chatr: bob joined",
+    ])
+    .await;
+    server
+        .admin()
+        .send(AdminMsg::Broadcast("back in five".to_string()))
+        .await
+        .unwrap();
+    alice.expect(&["ReceivedMessage chatr: back in five"]).await;
+    bob.expect(&["ReceivedMessage chatr: back in five"]).await;
+}
+
+#[tokio::test]
+async fn oversized_messages_are_refused() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    alice.say(&"x".repeat(MAX_CONTENT_LEN + 1)).await;
+    alice
+        .expect(&[&format!(
+            "Error messages can't be over {MAX_CONTENT_LEN} bytes"
+        )])
+        .await;
+    alice.say(&"x".repeat(MAX_CONTENT_LEN)).await;
+    assert_eq!(alice.recv_n(1).await.len(), 1);
+}
+
+/// Server with a lobby full of messages as long as they can be, and `alice` who sent them
+async fn long_history() -> (TestServer, TestClient) {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    for n in 0..BACKLOG_LEN {
+        alice.say(&format!("{n:x<MAX_CONTENT_LEN$}")).await;
+    }
+    alice.recv_n(BACKLOG_LEN).await;
+    (server, alice)
+}
+
+#[tokio::test]
+async fn long_history_comes_in_pages() {
+    let (server, _alice) = long_history().await;
+    let mut bob = server.connect("bob").await;
+    bob.expect(&["Roster alice, bob", "UserConnected bob", "Joined lobby"])
+        .await;
+    let mut pages = Vec::new();
+    loop {
+        match bob.recv().await {
+            ChatrMessage::History { messages } => pages.push(messages.len()),
+            msg => {
+                assert_eq!(describe(&msg), "ReceivedMessage chatr: bob joined");
+                break;
+            }
+        }
+    }
+    assert!(pages.len() > 1, "{pages:?}");
+    assert_eq!(pages.iter().sum::<usize>(), BACKLOG_LEN);
+}
+
+#[tokio::test]
+async fn long_search_results_come_in_pages() {
+    let (_server, mut alice) = long_history().await;
+    let mut query = SearchQuery {
+        text: "xxx".to_string(),
+        ..SearchQuery::default()
+    };
+    let mut found = 0;
+    loop {
+        alice
+            .send(ChatrMessage::Search {
+                query: query.clone(),
+            })
+            .await;
+        let ChatrMessage::SearchResults { hits, more, .. } = alice.recv().await else {
+            panic!("expected search results");
+        };
+        assert!(!hits.is_empty() && hits.len() <= SEARCH_PAGE_LEN);
+        found += hits.len();
+        if !more {
+            break;
+        }
+        query = query.next_page();
+    }
+    assert_eq!(found, BACKLOG_LEN);
+}
+
+#[test]
+fn far_off_times_dont_parse() {
+    let now = chatr::time::now();
+    assert_eq!(chatr::time::parse_when("99999999999999999d", now), None);
+    assert_eq!(chatr::time::parse_when("999999999999-01-01", now), None);
+    assert_eq!(
+        chatr::time::parse_when("9000000000000000000-01-01", now),
+        None
+    );
+    assert_eq!(
+        chatr::time::parse_when("1d", now),
+        Some(now - 24 * 60 * 60 * 1000)
+    );
+}
+
+#[test]
+fn searching_just_a_room_is_a_search() {
+    let query = SearchQuery::parse("room:dev").expect("room:dev is a search");
+    assert_eq!(query.room.as_deref(), Some("dev"));
+    assert!(SearchQuery::parse("").is_none());
+}
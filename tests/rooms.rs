@@ -0,0 +1,158 @@
+use chatr::{
+    ChatrMessage,
+    room::{Access, MAX_PASSWORD_TRIES, Role, Rooms},
+    test_support::{TestClient, TestServer},
+};
+
+async fn join(client: &mut TestClient, room: &str, password: Option<&str>) {
+    client
+        .send(ChatrMessage::Join {
+            room: room.to_string(),
+            password: password.map(str::to_string),
+        })
+        .await;
+}
+
+/// Why joining was turned away, panicking if it wasn't
+async fn rejected(client: &mut TestClient) -> String {
+    match client.recv().await {
+        ChatrMessage::JoinRejected { reason, .. } => reason,
+        msg => panic!("expected to be turned away, got {msg:?}"),
+    }
+}
+
+/// Alice makes #vault and bob, in the lobby, has seen her go
+async fn alice_makes_vault(server: &TestServer) -> (TestClient, TestClient) {
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice
+        .expect(&["UserConnected bob", "ReceivedMessage chatr: bob joined"])
+        .await;
+    join(&mut alice, "vault", None).await;
+    alice
+        .expect(&[
+            "Joined vault",
+            "History 0",
+            "ReceivedMessage chatr: alice joined",
+        ])
+        .await;
+    bob.expect(&["ReceivedMessage chatr: alice left for #vault"])
+        .await;
+    (alice, bob)
+}
+
+#[tokio::test]
+async fn passwords_let_people_in() {
+    let server = TestServer::start().await;
+    let (mut alice, mut bob) = alice_makes_vault(&server).await;
+    alice
+        .send(ChatrMessage::SetAccess {
+            access: Access::Password,
+            password: Some("hunter2".to_string()),
+        })
+        .await;
+    alice
+        .expect(&["ReceivedMessage chatr: alice made #vault password protected"])
+        .await;
+    join(&mut bob, "vault", Some("hunter3")).await;
+    assert_eq!(rejected(&mut bob).await, "wrong password");
+    join(&mut bob, "vault", Some("hunter2")).await;
+    bob.expect(&["Joined vault"]).await;
+}
+
+#[tokio::test]
+async fn only_the_owner_revokes_operators() {
+    let server = TestServer::start().await;
+    let (mut alice, mut bob) = alice_makes_vault(&server).await;
+    join(&mut bob, "vault", None).await;
+    alice.expect(&["ReceivedMessage chatr: bob joined"]).await;
+    let _carol = server.join("carol").await;
+    alice.expect(&["UserConnected carol"]).await;
+    for username in ["bob", "carol"] {
+        alice
+            .send(ChatrMessage::SetRole {
+                username: username.to_string(),
+                role: Some(Role::Operator),
+            })
+            .await;
+        alice
+            .expect(&[&format!(
+                "ReceivedMessage chatr: alice made {username} operator"
+            )])
+            .await;
+    }
+    bob.send(ChatrMessage::Revoke {
+        username: "carol".to_string(),
+    })
+    .await;
+    bob.expect(&[
+        "Joined vault",
+        "History 1",
+        "ReceivedMessage chatr: bob joined",
+        "UserConnected carol",
+        "ReceivedMessage chatr: alice made bob operator",
+        "ReceivedMessage chatr: alice made carol operator",
+        "Error you need to be owner of #vault for that",
+    ])
+    .await;
+    alice
+        .send(ChatrMessage::Revoke {
+            username: "carol".to_string(),
+        })
+        .await;
+    alice
+        .expect(&["ReceivedMessage chatr: alice revoked carol"])
+        .await;
+}
+
+#[tokio::test]
+async fn rooms_run_out() {
+    let server =
+        TestServer::start_with(|server| server.with_rooms(Rooms::default().with_max_rooms(2)))
+            .await;
+    let (_alice, mut bob) = alice_makes_vault(&server).await;
+    join(&mut bob, "attic", None).await;
+    assert_eq!(
+        rejected(&mut bob).await,
+        "there are too many rooms to make another"
+    );
+}
+
+#[tokio::test]
+async fn empty_rooms_make_way() {
+    let server =
+        TestServer::start_with(|server| server.with_rooms(Rooms::default().with_max_rooms(2)))
+            .await;
+    let (mut alice, mut bob) = alice_makes_vault(&server).await;
+    join(&mut alice, "lobby", None).await;
+    bob.expect(&["ReceivedMessage chatr: alice joined"]).await;
+    // Nobody set anything up in #vault, so it's gone and there's space again
+    join(&mut bob, "attic", None).await;
+    bob.expect(&["Joined attic"]).await;
+}
+
+#[tokio::test]
+async fn guessing_runs_out_of_tries() {
+    let server = TestServer::start().await;
+    let (mut alice, mut bob) = alice_makes_vault(&server).await;
+    alice
+        .send(ChatrMessage::SetAccess {
+            access: Access::Password,
+            password: Some("hunter2".to_string()),
+        })
+        .await;
+    alice
+        .expect(&["ReceivedMessage chatr: alice made #vault password protected"])
+        .await;
+    for n in 0..MAX_PASSWORD_TRIES {
+        join(&mut bob, "vault", Some(&format!("guess{n}"))).await;
+    }
+    for _ in 0..MAX_PASSWORD_TRIES {
+        assert_eq!(rejected(&mut bob).await, "wrong password");
+    }
+    join(&mut bob, "vault", Some("hunter2")).await;
+    assert_eq!(
+        rejected(&mut bob).await,
+        "too many password tries, wait a minute"
+    );
+}